@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+// Caps how many sessions hold an upstream connection at once. Connections past
+// the cap wait their turn in arrival order, and each one that ends lets the
+// longest-waiting one in.
+pub struct Admission {
+    // 0 is unlimited.
+    max_sessions: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    admitted: usize,
+    next_id: u64,
+    // Each waiting ticket, with where to tell it its place in line.
+    queue: VecDeque<(u64, watch::Sender<usize>)>,
+}
+
+// A connection's place: admitted, or in the queue. Dropping it gives the place up.
+pub struct Ticket {
+    admission: Arc<Admission>,
+    id: u64,
+    position: watch::Receiver<usize>,
+}
+
+impl Admission {
+    pub fn new(max_sessions: usize) -> Arc<Self> {
+        Arc::new(Self { max_sessions, inner: Mutex::new(Inner::default()) })
+    }
+
+    pub fn join(self: &Arc<Self>) -> Ticket {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let position = if self.max_sessions == 0 || (inner.admitted < self.max_sessions && inner.queue.is_empty()) {
+            inner.admitted += 1;
+            watch::channel(0).1
+        } else {
+            let (tx, rx) = watch::channel(inner.queue.len() + 1);
+            inner.queue.push_back((id, tx));
+            rx
+        };
+        Ticket { admission: self.clone(), id, position }
+    }
+
+    fn leave(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.queue.iter().position(|(waiting, _)| *waiting == id) {
+            Some(index) => {
+                inner.queue.remove(index);
+            }
+            None => inner.admitted -= 1,
+        }
+        while inner.admitted < self.max_sessions
+            && let Some((_, tx)) = inner.queue.pop_front()
+        {
+            inner.admitted += 1;
+            tx.send_replace(0);
+        }
+        for (index, (_, tx)) in inner.queue.iter().enumerate() {
+            tx.send_if_modified(|position| std::mem::replace(position, index + 1) != index + 1);
+        }
+    }
+}
+
+impl Ticket {
+    // How many places from the front of the queue; 0 once admitted.
+    pub fn position(&mut self) -> usize {
+        *self.position.borrow_and_update()
+    }
+
+    // Wait until the position changes.
+    pub async fn changed(&mut self) {
+        if self.position.changed().await.is_err() {
+            // Admitted with nothing further to say
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.admission.leave(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lets_waiting_sessions_in_first_come_first_served() {
+        let admission = Admission::new(2);
+        let first = admission.join();
+        let mut second = admission.join();
+        let mut third = admission.join();
+        let mut fourth = admission.join();
+        assert_eq!((second.position(), third.position(), fourth.position()), (0, 1, 2));
+
+        // Leaving the queue moves everyone behind up
+        drop(third);
+        assert_eq!(fourth.position(), 1);
+        let mut fifth = admission.join();
+        assert_eq!(fifth.position(), 2);
+
+        // A session ending admits the front of the queue
+        drop(first);
+        assert_eq!((fourth.position(), fifth.position()), (0, 1));
+        drop(second);
+        assert_eq!(fifth.position(), 0);
+        assert_eq!(admission.inner.lock().unwrap().admitted, 2);
+
+        let unlimited = Admission::new(0);
+        let mut tickets: Vec<Ticket> = (0..100).map(|_| unlimited.join()).collect();
+        assert!(tickets.iter_mut().all(|ticket| ticket.position() == 0));
+    }
+}
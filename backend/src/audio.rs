@@ -0,0 +1,430 @@
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::opus;
+
+// Microphone audio arrives in whatever format the browser captured it in; the
+// Realtime session is configured for `pcm16`, which OpenAI defines as 24 kHz mono
+// little-endian 16-bit PCM. This module converts one into the other.
+pub const UPSTREAM_SAMPLE_RATE: u32 = 24_000;
+
+const MIN_SAMPLE_RATE: u32 = 8_000;
+const MAX_SAMPLE_RATE: u32 = 192_000;
+const MAX_CHANNELS: u16 = 8;
+const LOWPASS_TAPS: usize = 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleEncoding {
+    // Signed 16-bit little-endian.
+    Pcm16,
+    // 32-bit float little-endian in [-1, 1], what Web Audio hands out natively.
+    F32,
+    // One Opus packet per binary message, decoded to PCM16 before anything else.
+    Opus,
+}
+
+impl SampleEncoding {
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleEncoding::Pcm16 | SampleEncoding::Opus => 2,
+            SampleEncoding::F32 => 4,
+        }
+    }
+}
+
+// Interleaved PCM audio description, declared by the client in its hello.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PcmFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub encoding: SampleEncoding,
+}
+
+impl PcmFormat {
+    // What the upstream session expects.
+    pub const UPSTREAM: PcmFormat =
+        PcmFormat { sample_rate: UPSTREAM_SAMPLE_RATE, channels: 1, encoding: SampleEncoding::Pcm16 };
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&self.sample_rate) {
+            return Err(format!(
+                "sample rate {} Hz is outside {MIN_SAMPLE_RATE}..={MAX_SAMPLE_RATE} Hz",
+                self.sample_rate
+            ));
+        }
+        if self.channels == 0 || self.channels > MAX_CHANNELS {
+            return Err(format!("{} channels is outside 1..={MAX_CHANNELS}", self.channels));
+        }
+        if self.encoding == SampleEncoding::Opus {
+            opus::check_format(self.sample_rate, self.channels)?;
+        }
+        Ok(())
+    }
+
+    fn frame_bytes(&self) -> usize {
+        self.encoding.bytes_per_sample() * self.channels as usize
+    }
+}
+
+// Streaming converter from the client's format to 24 kHz mono PCM16. Frames may be
+// split anywhere across calls to `process`; the leftover bytes and the resampler
+// state carry over.
+pub struct AudioPipeline {
+    decoder: Option<opus::Decoder>,
+    input: PcmFormat,
+    output_rate: u32,
+    pending: Vec<u8>,
+    lowpass: Option<LowPass>,
+    resampler: Resampler,
+}
+
+impl AudioPipeline {
+    pub fn new(input: PcmFormat, output_rate: u32) -> Result<Self, String> {
+        input.validate()?;
+        let decoder = match input.encoding {
+            SampleEncoding::Opus => {
+                Some(opus::Decoder::new(input.sample_rate, input.channels).map_err(|e| e.to_string())?)
+            }
+            _ => None,
+        };
+        // Past the decoder everything is PCM16.
+        let input = if decoder.is_some() { PcmFormat { encoding: SampleEncoding::Pcm16, ..input } } else { input };
+        // Only downsampling needs an anti-aliasing filter.
+        let lowpass = (input.sample_rate > output_rate)
+            .then(|| LowPass::new(0.45 * output_rate as f64 / input.sample_rate as f64));
+        Ok(Self {
+            decoder,
+            input,
+            output_rate,
+            pending: Vec::new(),
+            lowpass,
+            resampler: Resampler::new(input.sample_rate, output_rate),
+        })
+    }
+
+    // Whether decoded audio goes upstream as it is.
+    pub fn is_passthrough(&self) -> bool {
+        self.input == PcmFormat { sample_rate: self.output_rate, ..PcmFormat::UPSTREAM }
+    }
+
+    // Convert a chunk of client audio, returning PCM16 LE bytes at the output rate.
+    // For Opus the chunk is one packet; one that does not decode is dropped.
+    pub fn process(&mut self, bytes: &[u8]) -> Vec<u8> {
+        match &mut self.decoder {
+            Some(decoder) => match decoder.decode(bytes) {
+                Ok(pcm) => self.pending.extend_from_slice(&pcm),
+                Err(e) => {
+                    warn!("Dropping an Opus packet that did not decode: {e}");
+                    return Vec::new();
+                }
+            },
+            None => self.pending.extend_from_slice(bytes),
+        }
+        let frame_bytes = self.input.frame_bytes();
+        let whole = self.pending.len() - self.pending.len() % frame_bytes;
+        if self.is_passthrough() {
+            return self.pending.drain(..whole).collect();
+        }
+
+        let mut mono: Vec<f32> =
+            self.pending[..whole].chunks_exact(frame_bytes).map(|frame| self.downmix(frame)).collect();
+        self.pending.drain(..whole);
+
+        if let Some(lowpass) = &mut self.lowpass {
+            lowpass.apply(&mut mono);
+        }
+        let resampled = self.resampler.process(&mono);
+
+        let mut out = Vec::with_capacity(resampled.len() * 2);
+        for sample in resampled {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            out.extend_from_slice(&sample.to_le_bytes());
+        }
+        out
+    }
+
+    fn downmix(&self, frame: &[u8]) -> f32 {
+        let width = self.input.encoding.bytes_per_sample();
+        let sum: f32 = frame
+            .chunks_exact(width)
+            .map(|sample| match self.input.encoding {
+                SampleEncoding::Pcm16 | SampleEncoding::Opus => {
+                    i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0
+                }
+                SampleEncoding::F32 => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+            })
+            .sum();
+        sum / self.input.channels as f32
+    }
+}
+
+// How tutor audio, 24 kHz mono PCM16 from upstream, goes on to the browser.
+pub enum AudioOutput {
+    Pcm16,
+    Opus(opus::Encoder),
+}
+
+impl AudioOutput {
+    // The encoding the client asked for, or PCM16 when this server cannot make it.
+    pub fn new(encoding: SampleEncoding) -> Self {
+        match encoding {
+            SampleEncoding::Opus => match opus::Encoder::new() {
+                Ok(encoder) => AudioOutput::Opus(encoder),
+                Err(e) => {
+                    warn!("Sending PCM16 instead of Opus: {e}");
+                    AudioOutput::Pcm16
+                }
+            },
+            SampleEncoding::Pcm16 | SampleEncoding::F32 => AudioOutput::Pcm16,
+        }
+    }
+
+    pub fn format(&self) -> PcmFormat {
+        match self {
+            AudioOutput::Pcm16 => PcmFormat::UPSTREAM,
+            AudioOutput::Opus(_) => PcmFormat { encoding: SampleEncoding::Opus, ..PcmFormat::UPSTREAM },
+        }
+    }
+
+    // The binary messages for a chunk of tutor audio. Opus holds back what does not
+    // fill a packet until the next chunk or `end_turn`.
+    pub fn frames(&mut self, pcm: Vec<u8>) -> Vec<Vec<u8>> {
+        match self {
+            AudioOutput::Pcm16 => vec![pcm],
+            AudioOutput::Opus(encoder) => encoder.encode(&pcm).unwrap_or_else(|e| {
+                warn!("Failed to encode tutor audio: {e}");
+                Vec::new()
+            }),
+        }
+    }
+
+    // Whatever is held back once the tutor's turn has no more audio.
+    pub fn end_turn(&mut self) -> Vec<Vec<u8>> {
+        match self {
+            AudioOutput::Pcm16 => Vec::new(),
+            AudioOutput::Opus(encoder) => encoder.flush().map(Vec::from_iter).unwrap_or_else(|e| {
+                warn!("Failed to encode tutor audio: {e}");
+                Vec::new()
+            }),
+        }
+    }
+
+    // Forget held-back audio the learner has cut off.
+    pub fn clear(&mut self) {
+        if let AudioOutput::Opus(encoder) = self {
+            encoder.clear();
+        }
+    }
+}
+
+// Windowed-sinc FIR low-pass with its history kept between chunks.
+struct LowPass {
+    taps: Vec<f32>,
+    history: Vec<f32>,
+}
+
+impl LowPass {
+    // `cutoff` is in cycles per input sample (0.5 is Nyquist).
+    fn new(cutoff: f64) -> Self {
+        let mid = (LOWPASS_TAPS / 2) as f64;
+        let mut taps: Vec<f64> = (0..LOWPASS_TAPS)
+            .map(|i| {
+                let x = i as f64 - mid;
+                let sinc = if x == 0.0 {
+                    2.0 * cutoff
+                } else {
+                    (2.0 * std::f64::consts::PI * cutoff * x).sin() / (std::f64::consts::PI * x)
+                };
+                let window = 0.54 - 0.46 * (2.0 * std::f64::consts::PI * i as f64 / (LOWPASS_TAPS - 1) as f64).cos();
+                sinc * window
+            })
+            .collect();
+        let gain: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|t| *t /= gain);
+        Self { taps: taps.into_iter().map(|t| t as f32).collect(), history: vec![0.0; LOWPASS_TAPS - 1] }
+    }
+
+    fn apply(&mut self, samples: &mut [f32]) {
+        let mut buf = std::mem::take(&mut self.history);
+        buf.extend_from_slice(samples);
+        for (i, out) in samples.iter_mut().enumerate() {
+            *out = buf[i..i + LOWPASS_TAPS].iter().zip(self.taps.iter().rev()).map(|(s, t)| s * t).sum();
+        }
+        self.history = buf.split_off(buf.len() - (LOWPASS_TAPS - 1));
+    }
+}
+
+// Linear-interpolation resampler. `pos` is the position of the next output sample
+// in input samples, counted from the last sample of the previous chunk.
+struct Resampler {
+    step: f64,
+    pos: f64,
+    last: f32,
+}
+
+impl Resampler {
+    fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self { step: input_rate as f64 / output_rate as f64, pos: 1.0, last: 0.0 }
+    }
+
+    fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        let n = samples.len();
+        if n == 0 {
+            return Vec::new();
+        }
+        let at = |i: usize| if i == 0 { self.last } else { samples[i - 1] };
+
+        let mut out = Vec::with_capacity((n as f64 / self.step) as usize + 1);
+        while self.pos < n as f64 {
+            let index = self.pos.floor() as usize;
+            let frac = (self.pos - index as f64) as f32;
+            out.push(at(index) + (at(index + 1) - at(index)) * frac);
+            self.pos += self.step;
+        }
+        self.pos -= n as f64;
+        self.last = samples[n - 1];
+        out
+    }
+}
+
+// A mono PCM16 sine tone, the scripted provider's stand-in voice.
+pub fn sine_pcm16(sample_rate: u32, frequency: f32, duration: f32) -> Vec<u8> {
+    let samples = (sample_rate as f32 * duration) as usize;
+    let mut audio_data = Vec::with_capacity(samples * 2); // 2 bytes per sample for PCM16
+
+    for i in 0..samples {
+        let t = i as f32 / sample_rate as f32;
+        let amplitude = 0.3; // Reduce volume
+        let sample = (amplitude * (2.0 * std::f32::consts::PI * frequency * t).sin() * 32767.0) as i16;
+        audio_data.extend_from_slice(&sample.to_le_bytes());
+    }
+    audio_data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcm16_samples(bytes: &[u8]) -> Vec<i16> {
+        bytes.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    fn rms(samples: &[i16]) -> f64 {
+        let sum: f64 = samples.iter().map(|&s| (s as f64 / 32768.0).powi(2)).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    // Upward zero crossings per second, which is the frequency of a clean tone.
+    fn frequency(samples: &[i16], sample_rate: u32) -> f64 {
+        let crossings = samples.windows(2).filter(|w| w[0] < 0 && w[1] >= 0).count();
+        crossings as f64 * sample_rate as f64 / samples.len() as f64
+    }
+
+    // Interleave a mono PCM16 tone into `channels` channels of f32.
+    fn to_f32_channels(pcm16: &[u8], channels: usize) -> Vec<u8> {
+        pcm16_samples(pcm16)
+            .into_iter()
+            .flat_map(|s| std::iter::repeat_n(s as f32 / 32768.0, channels))
+            .flat_map(f32::to_le_bytes)
+            .collect()
+    }
+
+    fn format(sample_rate: u32, channels: u16, encoding: SampleEncoding) -> PcmFormat {
+        PcmFormat { sample_rate, channels, encoding }
+    }
+
+    #[test]
+    fn passes_upstream_format_through_untouched() {
+        let tone = sine_pcm16(UPSTREAM_SAMPLE_RATE, 440.0, 0.1);
+        let mut pipeline = AudioPipeline::new(PcmFormat::UPSTREAM, UPSTREAM_SAMPLE_RATE).unwrap();
+        assert!(pipeline.is_passthrough());
+        assert_eq!(pipeline.process(&tone), tone);
+    }
+
+    #[test]
+    fn downsamples_48k_tone_without_changing_pitch() {
+        let tone = sine_pcm16(48_000, 440.0, 1.0);
+        let mut pipeline = AudioPipeline::new(format(48_000, 1, SampleEncoding::Pcm16), UPSTREAM_SAMPLE_RATE).unwrap();
+        // Feed it in the browser's 4096-sample chunks.
+        let out: Vec<u8> = tone.chunks(4096 * 2).flat_map(|chunk| pipeline.process(chunk)).collect();
+        let samples = pcm16_samples(&out);
+
+        assert!((samples.len() as i64 - UPSTREAM_SAMPLE_RATE as i64).abs() <= 1);
+        assert!((frequency(&samples, UPSTREAM_SAMPLE_RATE) - 440.0).abs() < 3.0);
+        assert!((rms(&samples[100..]) - rms(&pcm16_samples(&tone))).abs() < 0.01);
+    }
+
+    #[test]
+    fn upsamples_16k_tone_without_changing_pitch() {
+        let tone = sine_pcm16(16_000, 440.0, 1.0);
+        let mut pipeline = AudioPipeline::new(format(16_000, 1, SampleEncoding::Pcm16), UPSTREAM_SAMPLE_RATE).unwrap();
+        let samples = pcm16_samples(&pipeline.process(&tone));
+
+        assert!((samples.len() as i64 - UPSTREAM_SAMPLE_RATE as i64).abs() <= 1);
+        assert!((frequency(&samples, UPSTREAM_SAMPLE_RATE) - 440.0).abs() < 3.0);
+    }
+
+    #[test]
+    fn downmixes_stereo_f32_and_handles_split_frames() {
+        let stereo = to_f32_channels(&sine_pcm16(44_100, 440.0, 0.5), 2);
+        let mut pipeline = AudioPipeline::new(format(44_100, 2, SampleEncoding::F32), UPSTREAM_SAMPLE_RATE).unwrap();
+        // Odd chunk sizes split frames and even samples across calls.
+        let out: Vec<u8> = stereo.chunks(1001).flat_map(|chunk| pipeline.process(chunk)).collect();
+        let samples = pcm16_samples(&out);
+
+        assert!((samples.len() as i64 - UPSTREAM_SAMPLE_RATE as i64 / 2).abs() <= 1);
+        assert!((frequency(&samples, UPSTREAM_SAMPLE_RATE) - 440.0).abs() < 5.0);
+        assert!(rms(&samples[100..]) > 0.2);
+    }
+
+    #[test]
+    fn filters_tones_above_the_output_nyquist() {
+        let tone = sine_pcm16(48_000, 18_000.0, 0.5);
+        let mut pipeline = AudioPipeline::new(format(48_000, 1, SampleEncoding::Pcm16), UPSTREAM_SAMPLE_RATE).unwrap();
+        let samples = pcm16_samples(&pipeline.process(&tone));
+        assert!(rms(&samples) < 0.01, "aliased energy: {}", rms(&samples));
+    }
+
+    #[test]
+    fn rejects_unusable_formats() {
+        assert!(AudioPipeline::new(format(1_000, 1, SampleEncoding::Pcm16), UPSTREAM_SAMPLE_RATE).is_err());
+        assert!(AudioPipeline::new(format(48_000, 0, SampleEncoding::F32), UPSTREAM_SAMPLE_RATE).is_err());
+    }
+
+    #[test]
+    fn holds_opus_to_the_rates_it_runs_at() {
+        assert!(format(44_100, 1, SampleEncoding::Opus).validate().is_err());
+        assert!(format(48_000, 3, SampleEncoding::Opus).validate().is_err());
+        assert!(format(48_000, 2, SampleEncoding::Opus).validate().is_ok());
+    }
+
+    #[cfg(not(feature = "opus"))]
+    #[test]
+    fn falls_back_to_pcm16_without_opus() {
+        assert_eq!(AudioOutput::new(SampleEncoding::Opus).format(), PcmFormat::UPSTREAM);
+        assert!(AudioPipeline::new(format(48_000, 1, SampleEncoding::Opus), UPSTREAM_SAMPLE_RATE).is_err());
+    }
+
+    #[cfg(feature = "opus")]
+    #[test]
+    fn round_trips_tutor_audio_through_opus() {
+        let tone = sine_pcm16(UPSTREAM_SAMPLE_RATE, 440.0, 1.01);
+        let mut output = AudioOutput::new(SampleEncoding::Opus);
+        assert_eq!(output.format().encoding, SampleEncoding::Opus);
+        let mut packets: Vec<Vec<u8>> = tone.chunks(1000).flat_map(|chunk| output.frames(chunk.to_vec())).collect();
+        assert_eq!(packets.len(), 50);
+        // The last 10 ms only goes out padded to a whole packet at the end of the turn.
+        packets.extend(output.end_turn());
+        assert_eq!(packets.len(), 51);
+
+        let mut pipeline = AudioPipeline::new(format(24_000, 1, SampleEncoding::Opus), UPSTREAM_SAMPLE_RATE).unwrap();
+        let out: Vec<u8> = packets.iter().flat_map(|packet| pipeline.process(packet)).collect();
+        let samples = pcm16_samples(&out);
+
+        assert_eq!(samples.len(), 51 * 480);
+        let steady = &samples[480..24_000];
+        assert!((frequency(steady, UPSTREAM_SAMPLE_RATE) - 440.0).abs() < 3.0);
+        assert!((rms(steady) - rms(&pcm16_samples(&tone))).abs() < 0.05);
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Utc;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+// Who a connection belongs to, as vouched for by its token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user_id: String,
+}
+
+// Checks the tokens that admit browsers: HS256 JWTs signed locally with one of a
+// set of named secrets, so a key can be rotated by adding the new one before the
+// old one is retired. `backend --issue-token` hands them out. With no keys
+// configured every connection is let in anonymously.
+pub struct Authenticator {
+    keys: BTreeMap<String, hmac::Key>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+    // Names the key that signed the token; without it every key is tried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    // Expiry and issue time, in seconds since the epoch.
+    exp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+}
+
+impl Authenticator {
+    pub fn new(keys: &BTreeMap<String, String>) -> Self {
+        let keys = keys
+            .iter()
+            .map(|(id, secret)| (id.clone(), hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())))
+            .collect();
+        Self { keys }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    // A token for `user_id`, signed with key `key_id`, valid for `ttl`.
+    pub fn issue(&self, key_id: &str, user_id: &str, ttl: chrono::Duration) -> Result<String> {
+        let key = self.keys.get(key_id).with_context(|| format!("no key named {key_id}"))?;
+        if user_id.trim().is_empty() {
+            bail!("the user id is empty");
+        }
+        let now = Utc::now();
+        let header = Header { alg: "HS256".to_string(), typ: Some("JWT".to_string()), kid: Some(key_id.to_string()) };
+        let claims = Claims { sub: user_id.to_string(), exp: (now + ttl).timestamp(), iat: Some(now.timestamp()) };
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = hmac::sign(key, signed.as_bytes());
+        Ok(format!("{signed}.{}", URL_SAFE_NO_PAD.encode(signature.as_ref())))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Identity> {
+        let mut parts = token.trim().split('.');
+        let (Some(header), Some(claims), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            bail!("malformed token");
+        };
+        let signed = &token.trim()[..header.len() + 1 + claims.len()];
+        let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?).context("bad token header")?;
+        if header.alg != "HS256" {
+            bail!("unsupported token algorithm {}", header.alg);
+        }
+        let signature = URL_SAFE_NO_PAD.decode(signature)?;
+        let valid = match &header.kid {
+            Some(kid) => {
+                let key = self.keys.get(kid).with_context(|| format!("unknown key {kid}"))?;
+                hmac::verify(key, signed.as_bytes(), &signature).is_ok()
+            }
+            None => self.keys.values().any(|key| hmac::verify(key, signed.as_bytes(), &signature).is_ok()),
+        };
+        if !valid {
+            bail!("bad token signature");
+        }
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims)?).context("bad token claims")?;
+        if claims.exp <= Utc::now().timestamp() {
+            bail!("token expired");
+        }
+        if claims.sub.trim().is_empty() {
+            bail!("token names no user");
+        }
+        Ok(Identity { user_id: claims.sub })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticator() -> Authenticator {
+        let keys = BTreeMap::from([
+            ("2026-09".to_string(), "an old secret that is still being accepted".to_string()),
+            ("2026-10".to_string(), "the current secret, at least 32 bytes long".to_string()),
+        ]);
+        Authenticator::new(&keys)
+    }
+
+    #[test]
+    fn accepts_tokens_from_any_configured_key() {
+        let auth = authenticator();
+        for key_id in ["2026-09", "2026-10"] {
+            let token = auth.issue(key_id, "alice", chrono::Duration::hours(1)).unwrap();
+            assert_eq!(auth.verify(&token).unwrap(), Identity { user_id: "alice".to_string() });
+        }
+        assert!(!Authenticator::new(&BTreeMap::new()).enabled());
+    }
+
+    #[test]
+    fn rejects_forged_expired_and_foreign_tokens() {
+        let auth = authenticator();
+        let token = auth.issue("2026-10", "alice", chrono::Duration::hours(1)).unwrap();
+
+        // Someone else's claims under alice's signature
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = URL_SAFE_NO_PAD.encode(r#"{"sub":"mallory","exp":9999999999}"#);
+        parts[1] = &forged;
+        assert!(auth.verify(&parts.join(".")).is_err());
+
+        let expired = auth.issue("2026-10", "alice", chrono::Duration::hours(-1)).unwrap();
+        assert_eq!(auth.verify(&expired).unwrap_err().to_string(), "token expired");
+
+        let other = Authenticator::new(&BTreeMap::from([("2026-10".to_string(), "x".repeat(32))]));
+        assert!(other.verify(&token).is_err());
+        assert!(auth.verify("not a token").is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::protocol::{ServerMessage, Speaker};
+use crate::realtime::ServerEvent;
+
+// Live captions for both speakers, assembled from the transcript deltas of the
+// upstream session so the browser can subtitle whoever is talking. Every delta
+// yields a caption with the text so far; the final transcript closes it.
+#[derive(Default)]
+pub struct Captions {
+    open: HashMap<String, OpenCaption>,
+}
+
+struct OpenCaption {
+    speaker: Speaker,
+    text: String,
+    started_at: DateTime<Utc>,
+}
+
+impl Captions {
+    pub fn update(&mut self, event: &ServerEvent) -> Option<ServerMessage> {
+        match event {
+            // Learner captions start when they start speaking, not when text shows up
+            ServerEvent::InputAudioBufferSpeechStarted { item_id, .. } => {
+                self.open.entry(item_id.clone()).or_insert_with(|| OpenCaption::new(Speaker::Learner));
+                None
+            }
+            ServerEvent::InputAudioTranscriptionDelta { item_id, delta, .. } => {
+                Some(self.append(Speaker::Learner, item_id, delta))
+            }
+            ServerEvent::ResponseAudioTranscriptDelta { item_id, delta, .. }
+            | ServerEvent::ResponseTextDelta { item_id, delta, .. } => {
+                Some(self.append(Speaker::Tutor, item_id, delta))
+            }
+            ServerEvent::InputAudioTranscriptionCompleted { item_id, transcript, .. } => {
+                Some(self.close(Speaker::Learner, item_id, transcript))
+            }
+            // Clears whatever partial caption the learner had
+            ServerEvent::InputAudioTranscriptionFailed { item_id, .. } => {
+                Some(self.close(Speaker::Learner, item_id, ""))
+            }
+            ServerEvent::ResponseAudioTranscriptDone { item_id, transcript: text, .. }
+            | ServerEvent::ResponseTextDone { item_id, text, .. } => Some(self.close(Speaker::Tutor, item_id, text)),
+            _ => None,
+        }
+    }
+
+    fn append(&mut self, speaker: Speaker, item_id: &str, delta: &str) -> ServerMessage {
+        let caption = self.open.entry(item_id.to_string()).or_insert_with(|| OpenCaption::new(speaker));
+        caption.text.push_str(delta);
+        caption.message(item_id, false)
+    }
+
+    fn close(&mut self, speaker: Speaker, item_id: &str, text: &str) -> ServerMessage {
+        let mut caption = self.open.remove(item_id).unwrap_or_else(|| OpenCaption::new(speaker));
+        caption.text = text.to_string();
+        caption.message(item_id, true)
+    }
+}
+
+impl OpenCaption {
+    fn new(speaker: Speaker) -> Self {
+        Self { speaker, text: String::new(), started_at: Utc::now() }
+    }
+
+    fn message(&self, item_id: &str, is_final: bool) -> ServerMessage {
+        ServerMessage::Caption {
+            speaker: self.speaker,
+            item_id: item_id.to_string(),
+            text: self.text.clone(),
+            is_final,
+            started_at: self.started_at,
+            updated_at: Utc::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(message: Option<ServerMessage>) -> (Speaker, String, bool) {
+        match message {
+            Some(ServerMessage::Caption { speaker, text, is_final, .. }) => (speaker, text, is_final),
+            other => panic!("expected a caption, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn assembles_deltas_until_the_final_transcript() {
+        let mut captions = Captions::default();
+        let delta = |delta: &str| ServerEvent::ResponseAudioTranscriptDelta {
+            response_id: "resp_1".into(),
+            item_id: "item_1".into(),
+            output_index: 0,
+            content_index: 0,
+            delta: delta.into(),
+        };
+        assert_eq!(text(captions.update(&delta("Teach "))), (Speaker::Tutor, "Teach ".into(), false));
+        assert_eq!(text(captions.update(&delta("me!"))), (Speaker::Tutor, "Teach me!".into(), false));
+
+        // A learner talking meanwhile gets their own caption
+        captions.update(&ServerEvent::InputAudioBufferSpeechStarted { audio_start_ms: 0, item_id: "item_2".into() });
+        let learner = captions.update(&ServerEvent::InputAudioTranscriptionCompleted {
+            item_id: "item_2".into(),
+            content_index: 0,
+            transcript: "Photosynthesis".into(),
+        });
+        assert_eq!(text(learner), (Speaker::Learner, "Photosynthesis".into(), true));
+
+        let done = captions.update(&ServerEvent::ResponseAudioTranscriptDone {
+            response_id: "resp_1".into(),
+            item_id: "item_1".into(),
+            output_index: 0,
+            content_index: 0,
+            transcript: "Teach me!".into(),
+        });
+        assert_eq!(text(done), (Speaker::Tutor, "Teach me!".into(), true));
+        assert!(captions.open.is_empty());
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use axum::http::HeaderValue;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+
+use crate::openai::{DEFAULT_MODEL, DEFAULT_REALTIME_URL};
+use crate::protocol::TurnMode;
+use crate::quota::QuotaLimits;
+
+pub const DEFAULT_PROMPT: &str = include_str!("../feynman_prompt.txt");
+const DEFAULT_CONFIG_FILE: &str = "feynman.toml";
+// Shorter secrets make signed tokens guessable.
+const MIN_AUTH_SECRET_LEN: usize = 32;
+// The voices the Realtime API offers.
+const VOICES: &[&str] = &["alloy", "ash", "ballad", "coral", "echo", "sage", "shimmer", "verse"];
+
+// Command line flags. Each can also be set through the environment variable named
+// in its `env`; both override the config file. Doc comments double as `--help`.
+#[derive(Debug, Default, Parser)]
+#[command(about = "Feynman tutoring relay between the browser and a realtime model")]
+pub struct Cli {
+    /// TOML config file [default: feynman.toml, if present]
+    #[arg(long, short, env = "FEYNMAN_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0:3000]
+    #[arg(long, env = "FEYNMAN_BIND")]
+    pub bind: Option<SocketAddr>,
+    /// Origin the frontend is served from, which browsers let call the API; repeat for several [default: http://localhost:5173]
+    #[arg(long = "allowed-origin", env = "FEYNMAN_ALLOWED_ORIGINS", value_delimiter = ',')]
+    pub allowed_origins: Vec<String>,
+    /// Directory for the session database and transcripts [default: data]
+    #[arg(long, env = "FEYNMAN_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// System prompt file [default: the built-in prompt]
+    #[arg(long, env = "FEYNMAN_PROMPT")]
+    pub prompt: Option<PathBuf>,
+    /// Upstream to use [default: openai]
+    #[arg(long, value_enum, env = "FEYNMAN_MODE")]
+    pub mode: Option<Mode>,
+    /// Script to replay; implies --mode scripted [default: the built-in script]
+    #[arg(long, env = "FEYNMAN_SCRIPT")]
+    pub script: Option<PathBuf>,
+    /// How learner turns end unless the browser asks otherwise [default: server-vad]
+    #[arg(long, value_enum, env = "FEYNMAN_TURN_MODE")]
+    pub turn_mode: Option<TurnMode>,
+    /// Quiet after a learner turn that ends their explanation, in ms; 0 waits for them to say they are done [default: 10000]
+    #[arg(long, env = "FEYNMAN_TEACHING_TIMEOUT_MS")]
+    pub teaching_timeout_ms: Option<u64>,
+    /// Record the audio of voice sessions whose learner agrees to it [default: false]
+    #[arg(long, env = "FEYNMAN_RECORD_AUDIO")]
+    pub record_audio: Option<bool>,
+    /// Sessions connected upstream at once; more wait in a queue. 0 is unlimited [default: 0]
+    #[arg(long, env = "FEYNMAN_MAX_SESSIONS")]
+    pub max_sessions: Option<usize>,
+    /// Key that signs access tokens, as ID=SECRET; repeat to accept several. Without any, no token is needed
+    #[arg(long = "auth-key", env = "FEYNMAN_AUTH_KEYS", value_delimiter = ',', hide_env_values = true)]
+    pub auth_keys: Vec<String>,
+    /// Print an access token for this user, valid for 30 days, and exit
+    #[arg(long, value_name = "USER")]
+    pub issue_token: Option<String>,
+    /// Key to sign --issue-token with [default: the only key]
+    #[arg(long, value_name = "ID")]
+    pub token_key: Option<String>,
+    /// Learner audio each user may send per day, in seconds; 0 is unlimited
+    #[arg(long, env = "FEYNMAN_QUOTA_LEARNER_AUDIO_SECONDS")]
+    pub quota_learner_audio_seconds: Option<u64>,
+    /// Tutor audio each user may receive per day, in seconds; 0 is unlimited
+    #[arg(long, env = "FEYNMAN_QUOTA_TUTOR_AUDIO_SECONDS")]
+    pub quota_tutor_audio_seconds: Option<u64>,
+    /// Model tokens each user may use per day; 0 is unlimited
+    #[arg(long, env = "FEYNMAN_QUOTA_TOKENS")]
+    pub quota_tokens: Option<u64>,
+    /// Log filter, e.g. info or info,backend::routes=debug [default: info]
+    #[arg(long, env = "FEYNMAN_LOG")]
+    pub log_level: Option<String>,
+    /// Log line format [default: text]
+    #[arg(long, value_enum, env = "FEYNMAN_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Realtime API endpoint
+    #[arg(long, env = "OPENAI_REALTIME_URL")]
+    pub openai_url: Option<String>,
+    /// Realtime model
+    #[arg(long, env = "FEYNMAN_MODEL")]
+    pub model: Option<String>,
+    /// Tutor voice
+    #[arg(long, env = "FEYNMAN_VOICE")]
+    pub voice: Option<String>,
+    /// Server VAD activation threshold, 0 to 1
+    #[arg(long, env = "FEYNMAN_VAD_THRESHOLD")]
+    pub vad_threshold: Option<f32>,
+    /// Audio kept from before detected speech, in ms
+    #[arg(long, env = "FEYNMAN_VAD_PREFIX_PADDING_MS")]
+    pub vad_prefix_padding_ms: Option<u32>,
+    /// Silence that ends a learner turn, in ms
+    #[arg(long, env = "FEYNMAN_VAD_SILENCE_DURATION_MS")]
+    pub vad_silence_duration_ms: Option<u32>,
+    /// Silence that ends a learner turn while they are explaining, in ms
+    #[arg(long, env = "FEYNMAN_VAD_TEACHING_SILENCE_DURATION_MS")]
+    pub vad_teaching_silence_duration_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    // Relay to the OpenAI Realtime API.
+    Openai,
+    // Replay a script instead of talking to a model (what TEST_MODE=true selects).
+    Scripted,
+}
+
+// Server settings: defaults, then the config file, then environment and flags.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    // Origins whose pages may call the HTTP API, like "https://tutor.example.org".
+    pub allowed_origins: Vec<String>,
+    pub data_dir: PathBuf,
+    pub prompt: Option<PathBuf>,
+    pub mode: Mode,
+    pub script: Option<PathBuf>,
+    pub turn_mode: TurnMode,
+    pub teaching_timeout_ms: u64,
+    pub record_audio: bool,
+    pub max_sessions: usize,
+    pub auth: AuthSettings,
+    pub quota: QuotaLimits,
+    pub log: LogSettings,
+    pub openai: OpenAiSettings,
+    pub vad: VadSettings,
+}
+
+// Secrets that sign access tokens, by key id.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    pub keys: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    // An EnvFilter directive.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per line, for log collectors.
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAiSettings {
+    pub url: String,
+    pub model: String,
+    pub voice: String,
+}
+
+// Server VAD parameters for the upstream session.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VadSettings {
+    pub threshold: f32,
+    pub prefix_padding_ms: u32,
+    pub silence_duration_ms: u32,
+    // Used while the learner explains, so pausing to think does not end the turn.
+    pub teaching_silence_duration_ms: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            // Where `npm run dev` serves the frontend
+            allowed_origins: vec!["http://localhost:5173".to_string()],
+            data_dir: PathBuf::from("data"),
+            prompt: None,
+            mode: Mode::Openai,
+            script: None,
+            turn_mode: TurnMode::default(),
+            teaching_timeout_ms: 10_000,
+            record_audio: false,
+            max_sessions: 0,
+            auth: AuthSettings::default(),
+            quota: QuotaLimits::default(),
+            log: LogSettings::default(),
+            openai: OpenAiSettings::default(),
+            vad: VadSettings::default(),
+        }
+    }
+}
+
+impl Default for OpenAiSettings {
+    fn default() -> Self {
+        Self { url: DEFAULT_REALTIME_URL.to_string(), model: DEFAULT_MODEL.to_string(), voice: "alloy".to_string() }
+    }
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self { level: "info".to_string(), format: LogFormat::default() }
+    }
+}
+
+impl Default for VadSettings {
+    fn default() -> Self {
+        Self { threshold: 0.5, prefix_padding_ms: 300, silence_duration_ms: 200, teaching_silence_duration_ms: 1000 }
+    }
+}
+
+impl Config {
+    // Assemble the configuration from the command line, or fail with a reason.
+    pub fn from_cli(cli: Cli) -> Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::read(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::read(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+
+        // Older setups turn on the scripted upstream with TEST_MODE=true
+        if std::env::var("TEST_MODE").is_ok_and(|v| v == "true") {
+            config.mode = Mode::Scripted;
+        }
+        if let Some(bind) = cli.bind {
+            config.bind = bind;
+        }
+        if !cli.allowed_origins.is_empty() {
+            config.allowed_origins = cli.allowed_origins;
+        }
+        if let Some(data_dir) = cli.data_dir {
+            config.data_dir = data_dir;
+        }
+        if let Some(prompt) = cli.prompt {
+            config.prompt = Some(prompt);
+        }
+        if let Some(mode) = cli.mode {
+            config.mode = mode;
+        }
+        if let Some(script) = cli.script {
+            config.script = Some(script);
+            if cli.mode.is_none() {
+                config.mode = Mode::Scripted;
+            }
+        }
+        if let Some(turn_mode) = cli.turn_mode {
+            config.turn_mode = turn_mode;
+        }
+        if let Some(ms) = cli.teaching_timeout_ms {
+            config.teaching_timeout_ms = ms;
+        }
+        if let Some(record_audio) = cli.record_audio {
+            config.record_audio = record_audio;
+        }
+        if let Some(max_sessions) = cli.max_sessions {
+            config.max_sessions = max_sessions;
+        }
+        for key in cli.auth_keys {
+            let Some((id, secret)) = key.split_once('=') else {
+                anyhow::bail!("--auth-key must be ID=SECRET");
+            };
+            config.auth.keys.insert(id.trim().to_string(), secret.to_string());
+        }
+        if let Some(seconds) = cli.quota_learner_audio_seconds {
+            config.quota.learner_audio_seconds = seconds;
+        }
+        if let Some(seconds) = cli.quota_tutor_audio_seconds {
+            config.quota.tutor_audio_seconds = seconds;
+        }
+        if let Some(tokens) = cli.quota_tokens {
+            config.quota.tokens = tokens;
+        }
+        if let Some(level) = cli.log_level {
+            config.log.level = level;
+        }
+        if let Some(format) = cli.log_format {
+            config.log.format = format;
+        }
+        if let Some(url) = cli.openai_url {
+            config.openai.url = url;
+        }
+        if let Some(model) = cli.model {
+            config.openai.model = model;
+        }
+        if let Some(voice) = cli.voice {
+            config.openai.voice = voice;
+        }
+        if let Some(threshold) = cli.vad_threshold {
+            config.vad.threshold = threshold;
+        }
+        if let Some(ms) = cli.vad_prefix_padding_ms {
+            config.vad.prefix_padding_ms = ms;
+        }
+        if let Some(ms) = cli.vad_silence_duration_ms {
+            config.vad.silence_duration_ms = ms;
+        }
+        if let Some(ms) = cli.vad_teaching_silence_duration_ms {
+            config.vad.teaching_silence_duration_ms = ms;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.vad.threshold) {
+            anyhow::bail!("vad.threshold must be between 0 and 1, got {}", self.vad.threshold);
+        }
+        for origin in &self.allowed_origins {
+            let bare = origin.split_once("://").is_some_and(|(scheme, host)| {
+                matches!(scheme, "http" | "https") && !host.is_empty() && !host.contains('/')
+            });
+            if !bare || HeaderValue::from_str(origin).is_err() {
+                anyhow::bail!("allowed origin {origin} must be a scheme and host, like https://tutor.example.org");
+            }
+        }
+        if self.vad.silence_duration_ms == 0 {
+            anyhow::bail!("vad.silence_duration_ms must be positive");
+        }
+        if self.vad.teaching_silence_duration_ms == 0 {
+            anyhow::bail!("vad.teaching_silence_duration_ms must be positive");
+        }
+        for (id, secret) in &self.auth.keys {
+            if id.is_empty() {
+                anyhow::bail!("auth key ids must not be empty");
+            }
+            if secret.len() < MIN_AUTH_SECRET_LEN {
+                anyhow::bail!("auth key {id} must be at least {MIN_AUTH_SECRET_LEN} bytes long");
+            }
+        }
+        if self.mode == Mode::Openai {
+            if !(self.openai.url.starts_with("wss://") || self.openai.url.starts_with("ws://")) {
+                anyhow::bail!("openai.url must be a ws:// or wss:// URL, got {}", self.openai.url);
+            }
+            if self.openai.model.trim().is_empty() {
+                anyhow::bail!("openai.model must not be empty");
+            }
+            if !VOICES.contains(&self.openai.voice.as_str()) {
+                anyhow::bail!("openai.voice must be one of {}, got {}", VOICES.join(", "), self.openai.voice);
+            }
+        }
+        Ok(())
+    }
+
+    // The tutor's system prompt: the configured file, or the built-in one.
+    pub fn load_prompt(&self) -> Result<String> {
+        let Some(path) = &self.prompt else {
+            return Ok(DEFAULT_PROMPT.to_string());
+        };
+        let prompt = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        if prompt.trim().is_empty() {
+            anyhow::bail!("{} is empty", path.display());
+        }
+        Ok(prompt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_settings_are_overridden_by_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("feynman.toml");
+        std::fs::write(
+            &path,
+            r#"
+                bind = "127.0.0.1:8080"
+                data_dir = "/var/lib/feynman"
+
+                [quota]
+                tokens = 50000
+
+                [openai]
+                voice = "verse"
+
+                [vad]
+                threshold = 0.7
+                silence_duration_ms = 500
+            "#,
+        )
+        .unwrap();
+
+        let cli = Cli {
+            config: Some(path),
+            bind: Some("127.0.0.1:9000".parse().unwrap()),
+            vad_threshold: Some(0.6),
+            quota_learner_audio_seconds: Some(1800),
+            ..Cli::default()
+        };
+        let config = Config::from_cli(cli).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/feynman"));
+        assert_eq!(config.openai.voice, "verse");
+        assert_eq!(config.openai.model, DEFAULT_MODEL);
+        assert_eq!(config.quota, QuotaLimits { learner_audio_seconds: 1800, tutor_audio_seconds: 0, tokens: 50_000 });
+        assert_eq!(
+            config.vad,
+            VadSettings {
+                threshold: 0.6,
+                prefix_padding_ms: 300,
+                silence_duration_ms: 500,
+                teaching_silence_duration_ms: 1000
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(toml::from_str::<Config>("colour = \"blue\"").is_err());
+
+        let mut config = Config::default();
+        config.vad.threshold = 1.5;
+        assert!(config.validate().is_err());
+
+        let config = Config { allowed_origins: vec!["https://tutor.example.org/".to_string()], ..Config::default() };
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.openai.voice = "robot".to_string();
+        assert!(config.validate().is_err());
+        // Scripted mode never talks to OpenAI, so its settings do not matter
+        config.mode = Mode::Scripted;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn example_file_matches_the_defaults() {
+        let example: Config = toml::from_str(include_str!("../feynman.example.toml")).unwrap();
+        assert_eq!(example, Config::default());
+    }
+
+    #[test]
+    fn script_implies_scripted_mode() {
+        let cli = Cli { script: Some(PathBuf::from("conversation.json")), ..Cli::default() };
+        assert_eq!(Config::from_cli(cli).unwrap().mode, Mode::Scripted);
+    }
+}
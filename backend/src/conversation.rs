@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{probing, report};
+
+// Answers a question may get before it is flagged for review and the tutor moves on.
+pub const MAX_ANSWER_ATTEMPTS: u32 = 3;
+
+// The Feynman protocol as a state machine. The prompt in feynman_prompt.txt still
+// describes the whole protocol, but the backend decides which step we are on and
+// tells the model what to do next.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConversationState {
+    Initial,
+    WaitingForTopic,
+    ReadyToTeach,
+    Teaching,
+    Analyzing,
+    Questioning,
+    Complete,
+}
+
+// Something the analysis found missing, vague or wrong in the learner's explanation,
+// with the probing question that goes with it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Gap {
+    pub category: GapCategory,
+    // The part of the explanation the gap is about, in the learner's words.
+    pub excerpt: String,
+    pub severity: Severity,
+    pub question: String,
+    #[serde(default)]
+    pub probe: Probe,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapCategory {
+    MissingPart,
+    Vague,
+    Misconception,
+    // A question the tutor asked without a structured analysis behind it.
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+// How well the learner answered a probing question.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    // Right, in their own words and with the reasoning behind it: a pass.
+    Deep,
+    // Partly right, vague or parroted: worth another try.
+    NeedsReexplain,
+    // They do not know it yet; rephrasing will not help within this session.
+    ReviewMaterial,
+}
+
+// How probing one gap has gone so far.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Probe {
+    // Answers heard and answers graded; they differ while a grade is outstanding.
+    #[serde(default)]
+    pub answers: u32,
+    pub attempts: u32,
+    pub verdict: Option<Verdict>,
+    // What the grader said about the latest answer.
+    pub feedback: String,
+}
+
+// A function call the relay requested out of band and is waiting on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PendingCall {
+    GapAnalysis,
+    AnswerGrade,
+}
+
+impl PendingCall {
+    // The function the call asks for.
+    pub fn function(self) -> &'static str {
+        match self {
+            PendingCall::GapAnalysis => report::RECORD_GAPS,
+            PendingCall::AnswerGrade => probing::GRADE_ANSWER,
+        }
+    }
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+        }
+    }
+}
+
+impl Gap {
+    pub fn from_question(question: String) -> Self {
+        Self {
+            category: GapCategory::Other,
+            excerpt: String::new(),
+            severity: Severity::Medium,
+            question,
+            probe: Probe::default(),
+        }
+    }
+}
+
+// Things that happen on the upstream connection that can move the protocol forward.
+pub enum TurnEvent<'a> {
+    // The learner's audio buffer was committed (input_audio_buffer.committed).
+    LearnerTurn { item_id: &'a str },
+    // The transcription of a committed learner turn finished.
+    LearnerTranscript { item_id: &'a str, transcript: &'a str },
+    // A typed learner turn: committed and transcribed in one go.
+    LearnerText { item_id: &'a str, text: &'a str },
+    // The learner has finished explaining: they said so, or went quiet for long enough.
+    DoneTeaching,
+    // The tutor finished a response (response.done), with its transcript if any.
+    TutorFinished { transcript: Option<&'a str> },
+}
+
+pub struct ConversationContext {
+    pub state: ConversationState,
+    pub topic: Option<String>,
+    pub explanation: String,
+    // The gaps to probe, one question each, asked in order. The index moves on once
+    // the current question is settled: passed, or flagged for review.
+    pub questions: Vec<Gap>,
+    pub current_question_index: usize,
+    pub audio_buffer_has_data: bool,
+    // The out-of-band function call whose response.done has not arrived yet.
+    pub pending_call: Option<PendingCall>,
+    // The learner finished explaining while their last turn was still being
+    // committed; the explanation ends with that turn.
+    pub done_teaching: bool,
+    // State the conversation was in when each learner turn was committed, so a
+    // transcript that arrives late is still attributed to the right step.
+    turn_states: HashMap<String, ConversationState>,
+}
+
+impl ConversationContext {
+    pub fn new() -> Self {
+        Self {
+            state: ConversationState::Initial,
+            topic: None,
+            explanation: String::new(),
+            questions: Vec::new(),
+            current_question_index: 0,
+            audio_buffer_has_data: false,
+            pending_call: None,
+            done_teaching: false,
+            turn_states: HashMap::new(),
+        }
+    }
+
+    // Apply an event and return the new state if it caused a transition.
+    pub fn advance(&mut self, event: TurnEvent) -> Option<ConversationState> {
+        use ConversationState::*;
+
+        let next = match event {
+            TurnEvent::LearnerTurn { item_id } => {
+                self.turn_states.insert(item_id.to_string(), self.state.clone());
+                self.after_learner_turn()
+            }
+            TurnEvent::LearnerTranscript { item_id, transcript } => {
+                self.take_transcript(item_id, transcript);
+                None
+            }
+            // Typed text is already final, so it counts before the turn moves us on
+            TurnEvent::LearnerText { item_id, text } => {
+                self.turn_states.insert(item_id.to_string(), self.state.clone());
+                self.take_transcript(item_id, text);
+                self.after_learner_turn()
+            }
+            TurnEvent::DoneTeaching => match self.state {
+                Teaching => Some(Analyzing),
+                _ => None,
+            },
+            TurnEvent::TutorFinished { transcript } => match self.state {
+                Initial => Some(WaitingForTopic),
+                ReadyToTeach => Some(Teaching),
+                Analyzing => {
+                    // Without a structured analysis, go by the questions the tutor asked
+                    if self.questions.is_empty() {
+                        self.questions = transcript
+                            .map(extract_questions)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(Gap::from_question)
+                            .collect();
+                    }
+                    self.current_question_index = 0;
+                    if self.questions.is_empty() { Some(Complete) } else { Some(Questioning) }
+                }
+                _ => None,
+            },
+        };
+
+        next.and_then(|state| self.enter(state))
+    }
+
+    fn after_learner_turn(&self) -> Option<ConversationState> {
+        match self.state {
+            ConversationState::WaitingForTopic => Some(ConversationState::ReadyToTeach),
+            // An explanation can take many turns; it ends on DoneTeaching
+            ConversationState::Teaching if self.done_teaching => Some(ConversationState::Analyzing),
+            // Answers move on only once graded, see `record_verdict`
+            _ => None,
+        }
+    }
+
+    // File what the learner said under the step their turn was committed in.
+    fn take_transcript(&mut self, item_id: &str, transcript: &str) {
+        let transcript = transcript.trim();
+        match self.turn_states.remove(item_id) {
+            Some(ConversationState::WaitingForTopic) if self.topic.is_none() && !transcript.is_empty() => {
+                self.topic = Some(transcript.to_string());
+            }
+            Some(ConversationState::Teaching) => {
+                if !self.explanation.is_empty() {
+                    self.explanation.push(' ');
+                }
+                self.explanation.push_str(transcript);
+            }
+            Some(ConversationState::Questioning) if !transcript.is_empty() => {
+                if let Some(gap) = self.questions.get_mut(self.current_question_index) {
+                    gap.probe.answers += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, state: ConversationState) -> Option<ConversationState> {
+        info!("Conversation state: {:?} -> {:?}", self.state, state);
+        self.state = state.clone();
+        self.done_teaching = false;
+        Some(state)
+    }
+
+    // The gap currently being probed.
+    pub fn current_gap(&self) -> Option<&Gap> {
+        self.questions.get(self.current_question_index)
+    }
+
+    // Grade the answer to the current question. A pass or a flag for review settles
+    // it and moves on to the next one; otherwise the learner gets another try, until
+    // MAX_ANSWER_ATTEMPTS runs out. Returns the new state if that ended the session.
+    pub fn record_verdict(&mut self, verdict: Verdict, feedback: String) -> Option<ConversationState> {
+        if self.state != ConversationState::Questioning {
+            return None;
+        }
+        let gap = self.questions.get_mut(self.current_question_index)?;
+        gap.probe.attempts += 1;
+        gap.probe.feedback = feedback;
+        let verdict = match verdict {
+            Verdict::NeedsReexplain if gap.probe.attempts >= MAX_ANSWER_ATTEMPTS => Verdict::ReviewMaterial,
+            verdict => verdict,
+        };
+        gap.probe.verdict = Some(verdict);
+        if verdict == Verdict::NeedsReexplain {
+            return None;
+        }
+
+        self.current_question_index += 1;
+        if self.current_question_index >= self.questions.len() { self.enter(ConversationState::Complete) } else { None }
+    }
+
+    // The question settled just before the current one, if any.
+    fn last_settled(&self) -> Option<&Gap> {
+        self.current_question_index.checked_sub(1).and_then(|i| self.questions.get(i))
+    }
+
+    // Take the gaps from the structured analysis, most severe first.
+    pub fn record_gaps(&mut self, mut gaps: Vec<Gap>) {
+        gaps.sort_by_key(|gap| std::cmp::Reverse(gap.severity));
+        self.questions = gaps;
+        self.current_question_index = 0;
+    }
+
+    // Add one gap found outside the structured analysis. Before questioning starts it
+    // goes in by severity, afterwards it is asked last. Returns false for a gap that
+    // is already recorded.
+    pub fn add_gap(&mut self, gap: Gap) -> bool {
+        let same = |other: &Gap| {
+            other.question.eq_ignore_ascii_case(&gap.question)
+                || (!gap.excerpt.is_empty() && other.excerpt.eq_ignore_ascii_case(&gap.excerpt))
+        };
+        if self.questions.iter().any(same) {
+            return false;
+        }
+        let position = if self.state == ConversationState::Questioning {
+            self.questions.len()
+        } else {
+            self.questions.partition_point(|other| other.severity >= gap.severity)
+        };
+        self.questions.insert(position, gap);
+        true
+    }
+
+    // Finish the session early, wherever it is.
+    pub fn end(&mut self) -> Option<ConversationState> {
+        if self.state == ConversationState::Complete {
+            return None;
+        }
+        self.enter(ConversationState::Complete)
+    }
+
+    // The step a learner turn was committed in, until its transcript arrives.
+    pub fn turn_state(&self, item_id: &str) -> Option<&ConversationState> {
+        self.turn_states.get(item_id)
+    }
+
+    // What has been established so far, for an upstream session that did not hear it.
+    pub fn summary(&self) -> String {
+        let mut summary = String::from("Session so far:");
+        if let Some(topic) = &self.topic {
+            summary.push_str(&format!("\n- Topic: {topic}"));
+        }
+        if !self.explanation.is_empty() {
+            summary.push_str(&format!("\n- The learner's explanation: {}", self.explanation));
+        }
+        for (i, gap) in self.questions.iter().enumerate() {
+            let status = gap.probe.verdict.map_or("open", verdict_label);
+            summary.push_str(&format!("\n- Question {} ({status}): {}", i + 1, gap.question));
+        }
+        summary
+    }
+
+    // What the tutor should do on its next response, given the current step.
+    pub fn directive(&self) -> String {
+        match self.state {
+            ConversationState::Initial => "Greet the learner and ask what topic they will be teaching you.".to_string(),
+            ConversationState::WaitingForTopic => "Ask the learner what topic they will be teaching you.".to_string(),
+            ConversationState::ReadyToTeach => format!(
+                "The learner will teach you {}. Acknowledge the topic and say you are ready to listen.",
+                self.topic.as_deref().unwrap_or("their topic")
+            ),
+            ConversationState::Teaching => {
+                "The learner is teaching. Do not interrupt; wait for the full explanation.".to_string()
+            }
+            ConversationState::Analyzing => match self.questions.first() {
+                Some(first) => {
+                    let gaps: Vec<String> = self
+                        .questions
+                        .iter()
+                        .map(|gap| {
+                            format!("{} ({}): \"{}\"", gap_label(gap.category), gap.severity.as_str(), gap.excerpt)
+                        })
+                        .collect();
+                    format!(
+                        "Briefly tell the learner what their explanation was missing or got wrong: {}. \
+                        Then ask this question only: {}",
+                        gaps.join("; "),
+                        first.question
+                    )
+                }
+                None => "Analyze the learner's explanation for missing parts, vague or superficial \
+                    descriptions and misconceptions. Briefly name the gaps, list one simple probing \
+                    question per gap, then ask the first question only."
+                    .to_string(),
+            },
+            ConversationState::Questioning => match self.current_gap() {
+                Some(gap) if gap.probe.verdict == Some(Verdict::NeedsReexplain) => format!(
+                    "The learner's answer to \"{}\" fell short: {} Without giving the answer away, \
+                    ask them to explain it again, more simply or from another angle. Ask nothing else.",
+                    gap.question, gap.probe.feedback
+                ),
+                Some(gap) => match self.last_settled().and_then(settled_note) {
+                    Some(note) => format!("{note} Then ask this question only: {}", gap.question),
+                    None => format!("Ask this question only: {}", gap.question),
+                },
+                None => "Ask the next probing question.".to_string(),
+            },
+            ConversationState::Complete => {
+                let mut directive = self.last_settled().and_then(settled_note).unwrap_or_default();
+                let review: Vec<&str> = self
+                    .questions
+                    .iter()
+                    .filter(|gap| gap.probe.verdict == Some(Verdict::ReviewMaterial))
+                    .map(|gap| gap.question.as_str())
+                    .collect();
+                if !review.is_empty() {
+                    directive.push_str(&format!(" Suggest they review these before next time: {}.", review.join(" ")));
+                }
+                directive.push_str(" All questions have been answered. Congratulate the learner and end the session.");
+                directive.trim_start().to_string()
+            }
+        }
+    }
+}
+
+// What the tutor should say about a question that was just settled.
+fn settled_note(gap: &Gap) -> Option<String> {
+    match gap.probe.verdict? {
+        Verdict::Deep => Some(format!(
+            "The learner answered \"{}\" well ({}). Confirm it in one sentence.",
+            gap.question, gap.probe.feedback
+        )),
+        Verdict::ReviewMaterial => Some(format!(
+            "The learner could not answer \"{}\" ({}). Give the answer in two or three plain \
+            sentences and suggest they review it.",
+            gap.question, gap.probe.feedback
+        )),
+        Verdict::NeedsReexplain => None,
+    }
+}
+
+pub fn verdict_label(verdict: Verdict) -> &'static str {
+    match verdict {
+        Verdict::Deep => "answered in depth",
+        Verdict::NeedsReexplain => "needs re-explaining",
+        Verdict::ReviewMaterial => "to review",
+    }
+}
+
+pub fn gap_label(category: GapCategory) -> &'static str {
+    match category {
+        GapCategory::MissingPart => "Missing part",
+        GapCategory::Vague => "Vague",
+        GapCategory::Misconception => "Misconception",
+        GapCategory::Other => "Open question",
+    }
+}
+
+// Pull the probing questions out of the tutor's spoken analysis.
+fn extract_questions(transcript: &str) -> Vec<String> {
+    let mut questions: Vec<String> = Vec::new();
+    let mut start = 0;
+    for (i, c) in transcript.char_indices() {
+        match c {
+            '.' | '!' | '\n' | ':' => start = i + c.len_utf8(),
+            '?' => {
+                let question = transcript[start..=i]
+                    .trim()
+                    .trim_start_matches(|c: char| c.is_ascii_digit() || c == ')' || c == '-')
+                    .trim();
+                if !question.is_empty() && !questions.iter().any(|q| q == question) {
+                    questions.push(question.to_string());
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    questions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyzing() -> ConversationContext {
+        let mut ctx = ConversationContext::new();
+        ctx.state = ConversationState::Analyzing;
+        ctx
+    }
+
+    #[test]
+    fn an_explanation_spans_turns_until_the_learner_is_done() {
+        let mut ctx = ConversationContext::new();
+        ctx.state = ConversationState::Teaching;
+        for (item_id, transcript) in [("item_1", "Plants use sunlight."), ("item_2", "They give off oxygen.")] {
+            assert_eq!(ctx.advance(TurnEvent::LearnerTurn { item_id }), None);
+            ctx.advance(TurnEvent::LearnerTranscript { item_id, transcript });
+        }
+        assert_eq!(ctx.explanation, "Plants use sunlight. They give off oxygen.");
+        assert_eq!(ctx.advance(TurnEvent::DoneTeaching), Some(ConversationState::Analyzing));
+        assert_eq!(ctx.advance(TurnEvent::DoneTeaching), None);
+
+        // Done while the last turn is still on its way: that turn ends it
+        let mut ctx = ConversationContext::new();
+        ctx.state = ConversationState::Teaching;
+        ctx.done_teaching = true;
+        assert_eq!(ctx.advance(TurnEvent::LearnerTurn { item_id: "item_1" }), Some(ConversationState::Analyzing));
+        assert!(!ctx.done_teaching);
+    }
+
+    #[test]
+    fn questions_come_from_the_gap_analysis_when_there_is_one() {
+        let mut ctx = analyzing();
+        let gap = |severity, question: &str| Gap {
+            category: GapCategory::Vague,
+            excerpt: "sunlight".to_string(),
+            severity,
+            question: question.to_string(),
+            probe: Probe::default(),
+        };
+        ctx.record_gaps(vec![gap(Severity::Low, "Minor?"), gap(Severity::High, "Major?")]);
+        assert!(ctx.directive().ends_with("Then ask this question only: Major?"));
+
+        let transition = ctx.advance(TurnEvent::TutorFinished { transcript: Some("Something else entirely?") });
+        assert_eq!(transition, Some(ConversationState::Questioning));
+        let questions: Vec<&str> = ctx.questions.iter().map(|gap| gap.question.as_str()).collect();
+        assert_eq!(questions, ["Major?", "Minor?"]);
+    }
+
+    #[test]
+    fn questions_fall_back_to_what_the_tutor_asked() {
+        let mut ctx = analyzing();
+        ctx.advance(TurnEvent::TutorFinished {
+            transcript: Some("Good start. 1) Where does the oxygen come from? 2) What is chlorophyll for?"),
+        });
+        assert_eq!(
+            ctx.questions,
+            vec![
+                Gap::from_question("Where does the oxygen come from?".to_string()),
+                Gap::from_question("What is chlorophyll for?".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn questions_advance_only_once_settled() {
+        let mut ctx = ConversationContext::new();
+        ctx.state = ConversationState::Questioning;
+        ctx.questions = vec![
+            Gap::from_question("Where does the oxygen come from?".to_string()),
+            Gap::from_question("What does chlorophyll do?".to_string()),
+        ];
+
+        // A learner turn alone does not move on; the grade does
+        assert_eq!(ctx.advance(TurnEvent::LearnerTurn { item_id: "item_1" }), None);
+        assert_eq!(ctx.record_verdict(Verdict::Deep, "splits water".to_string()), None);
+        assert_eq!(ctx.current_question_index, 1);
+        assert!(ctx.directive().starts_with("The learner answered \"Where does the oxygen come from?\" well"));
+
+        for attempt in 1..MAX_ANSWER_ATTEMPTS {
+            assert_eq!(ctx.record_verdict(Verdict::NeedsReexplain, "too vague.".to_string()), None);
+            assert_eq!(ctx.current_question_index, 1);
+            assert_eq!(ctx.questions[1].probe.attempts, attempt);
+            assert!(ctx.directive().contains("explain it again"));
+        }
+        // Out of attempts: flagged for review, and that was the last question
+        let transition = ctx.record_verdict(Verdict::NeedsReexplain, "still vague.".to_string());
+        assert_eq!(transition, Some(ConversationState::Complete));
+        assert_eq!(ctx.questions[1].probe.verdict, Some(Verdict::ReviewMaterial));
+        assert!(ctx.directive().contains("review these before next time: What does chlorophyll do?"));
+    }
+}
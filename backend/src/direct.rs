@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::body::Bytes;
+use tokio::time::Instant;
+
+use crate::admission::Ticket;
+use crate::metrics::{self, ActiveSession};
+use crate::openai::{ClientSecret, OpenAiConfig, SessionEvents};
+use crate::provider::{RealtimeProvider, SessionOptions};
+use crate::realtime::{ClientEvent, Item, ServerEvent, Tool};
+use crate::session::Session;
+
+// The upstream of a session whose browser is connected to OpenAI itself. Nothing
+// goes over the network from here: the client events the state machine asks for
+// wait in an outbox until the browser picks them up and sends them down its own
+// data channel, and the server events come back in the browser's posts.
+pub struct DirectUpstream {
+    events: SessionEvents,
+    outbox: Vec<ClientEvent>,
+    pub secret: ClientSecret,
+    // Where the browser connects with the secret.
+    pub url: String,
+}
+
+impl DirectUpstream {
+    pub fn new(events: SessionEvents, secret: ClientSecret, url: String) -> Self {
+        Self { events, outbox: Vec::new(), secret, url }
+    }
+
+    // The client events queued since the last call, for the browser to send.
+    pub fn take_events(&mut self) -> Vec<ClientEvent> {
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+impl RealtimeProvider for DirectUpstream {
+    type Config = OpenAiConfig;
+
+    async fn connect(_config: &OpenAiConfig, _instructions: &str, _options: SessionOptions) -> Result<Self> {
+        Err(anyhow::anyhow!("direct sessions are opened by the browser"))
+    }
+
+    async fn set_teaching(&mut self, teaching: bool) -> Result<()> {
+        self.outbox.extend(self.events.set_teaching(teaching));
+        Ok(())
+    }
+
+    async fn send_audio(&mut self, _pcm: Bytes) -> Result<()> {
+        Err(anyhow::anyhow!("the browser sends its audio straight to OpenAI"))
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.outbox.push(ClientEvent::InputAudioBufferCommit);
+        Ok(())
+    }
+
+    async fn create_response(&mut self, instructions: &str) -> Result<()> {
+        self.outbox.push(self.events.create_response(instructions));
+        Ok(())
+    }
+
+    async fn request_function_call(&mut self, instructions: &str, function: Tool) -> Result<()> {
+        self.outbox.push(self.events.request_function_call(instructions, function));
+        Ok(())
+    }
+
+    async fn cancel_response(&mut self, response_id: &str) -> Result<()> {
+        self.outbox.push(self.events.cancel_response(response_id));
+        Ok(())
+    }
+
+    async fn truncate(&mut self, item_id: &str, audio_end_ms: u64) -> Result<()> {
+        self.outbox.push(self.events.truncate(item_id, audio_end_ms));
+        Ok(())
+    }
+
+    async fn create_item(&mut self, item: Item) -> Result<()> {
+        self.outbox.push(self.events.create_item(item));
+        Ok(())
+    }
+
+    async fn next_event(&mut self) -> Result<ServerEvent> {
+        Err(anyhow::anyhow!("the browser posts the events of a direct session"))
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// A direct session between the browser's posts. It keeps its place under the
+// session cap until the browser ends it or stops posting.
+pub struct DirectSession {
+    pub session: Session,
+    pub upstream: DirectUpstream,
+    pub last_seen: Instant,
+    _ticket: Ticket,
+    _active: ActiveSession,
+}
+
+impl DirectSession {
+    pub fn new(session: Session, upstream: DirectUpstream, ticket: Ticket) -> Self {
+        Self { session, upstream, last_seen: Instant::now(), _ticket: ticket, _active: metrics::session_started() }
+    }
+}
+
+// The direct sessions in progress, by session id.
+#[derive(Default)]
+pub struct DirectSessions {
+    sessions: Mutex<HashMap<String, Arc<tokio::sync::Mutex<DirectSession>>>>,
+}
+
+impl DirectSessions {
+    pub fn insert(&self, direct: DirectSession) -> Arc<tokio::sync::Mutex<DirectSession>> {
+        let id = direct.session.id.clone();
+        let direct = Arc::new(tokio::sync::Mutex::new(direct));
+        self.sessions.lock().unwrap().insert(id, direct.clone());
+        direct
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<tokio::sync::Mutex<DirectSession>>> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Arc<tokio::sync::Mutex<DirectSession>>> {
+        self.sessions.lock().unwrap().remove(id)
+    }
+
+    // Remove session `id` if it is still `direct` and not a later one under the
+    // same id. False if something else already took it out.
+    pub fn remove_if(&self, id: &str, direct: &Arc<tokio::sync::Mutex<DirectSession>>) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        if !sessions.get(id).is_some_and(|current| Arc::ptr_eq(current, direct)) {
+            return false;
+        }
+        sessions.remove(id);
+        true
+    }
+
+    // Take out the sessions nothing has been posted to for `idle`, to be finished.
+    // One in the middle of a post is not idle.
+    pub fn take_idle(&self, idle: Duration) -> Vec<Arc<tokio::sync::Mutex<DirectSession>>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let stale: Vec<String> = sessions
+            .iter()
+            .filter(|(_, direct)| direct.try_lock().is_ok_and(|direct| direct.last_seen.elapsed() >= idle))
+            .map(|(id, _)| id.clone())
+            .collect();
+        stale.into_iter().filter_map(|id| sessions.remove(&id)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::test_openai_config;
+    use crate::protocol::{SessionMode, TurnMode};
+    use crate::realtime::TurnDetection;
+
+    #[tokio::test]
+    async fn queues_client_events_for_the_browser() {
+        let config = OpenAiConfig { model: "gpt-realtime".to_string(), ..test_openai_config() };
+        assert_eq!(config.webrtc_url(), "https://api.openai.com/v1/realtime?model=gpt-realtime");
+        let options = SessionOptions { mode: SessionMode::Voice, turn_mode: TurnMode::SemanticVad };
+        let secret = ClientSecret { value: "ek_test".to_string(), expires_at: 0 };
+        let mut upstream = DirectUpstream::new(SessionEvents::new(&config, options), secret, config.webrtc_url());
+
+        upstream.set_teaching(false).await.unwrap();
+        upstream.create_response("Greet the learner").await.unwrap();
+        upstream.set_teaching(true).await.unwrap();
+        assert!(upstream.send_audio(Bytes::from_static(&[0; 4])).await.is_err());
+        let events = upstream.take_events();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], ClientEvent::ResponseCreate { response: Some(response) }
+            if response.instructions.as_deref() == Some("Greet the learner")));
+        let ClientEvent::SessionUpdate { session } = &events[1] else {
+            panic!("expected a session.update, got {:?}", events[1]);
+        };
+        assert!(
+            matches!(&session.turn_detection, Some(Some(TurnDetection::SemanticVad { eagerness, .. })) if eagerness == "low")
+        );
+        assert!(upstream.take_events().is_empty());
+    }
+}
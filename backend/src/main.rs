@@ -1,27 +1,175 @@
-mod routes;
+mod admission;
+mod audio;
+mod auth;
+mod captions;
+mod config;
+mod conversation;
+mod direct;
+mod logging;
+mod metrics;
+#[cfg(test)]
+mod mock_realtime;
 mod openai;
+mod opus;
+mod playback;
+mod probing;
+mod protocol;
+mod provider;
+mod quota;
+mod realtime;
+mod recording;
+mod report;
+mod routes;
+mod scripted;
+mod session;
+mod session_store;
+mod tools;
+mod transcript;
 
-use axum::{routing::any,
-        Router,
-        };
-use crate::routes::handle_ws;
+use crate::admission::Admission;
+use crate::auth::Authenticator;
+use crate::config::{Cli, Config, Mode};
+use crate::direct::DirectSessions;
+use crate::openai::{OASocket, OpenAiConfig};
+use crate::provider::RealtimeProvider;
+use crate::quota::Quotas;
+use crate::routes::{AppState, finish_idle_direct_sessions, router};
+use crate::scripted::{Script, ScriptedProvider};
+use crate::session::Sessions;
+use crate::session_store::{MemorySessionStore, SessionStore, SqliteSessionStore};
 
+use axum::http::HeaderValue;
+use clap::Parser;
 use rustls::crypto::ring;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// How long tokens from --issue-token last.
+const ISSUED_TOKEN_DAYS: i64 = 30;
+const DIRECT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() {
     // Install the ring-based crypto provider so rustls can operate.
-    ring::default_provider()
-        .install_default()
-        .expect("Failed to install CryptoProvider");
+    ring::default_provider().install_default().expect("Failed to install CryptoProvider");
 
     // You can still use `provider_arc` later if you like.
     // …
 
-    let app = Router::new().route("/ws", any(handle_ws));
+    dotenvy::dotenv().ok();
+    // Everything is checked here, so a bad setting stops the server instead of
+    // failing each connection.
+    let cli = Cli::parse();
+    let (issue_token, token_key) = (cli.issue_token.clone(), cli.token_key.clone());
+    let config = Config::from_cli(cli).unwrap_or_else(|e| exit_with(e));
+    logging::init(&config.log).unwrap_or_else(|e| exit_with(e));
+    let auth = Arc::new(Authenticator::new(&config.auth.keys));
+    if let Some(user_id) = issue_token {
+        let token = issue(&auth, &config, token_key.as_deref(), &user_id).unwrap_or_else(|e| exit_with(e));
+        println!("{token}");
+        return;
+    }
+    if !auth.enabled() {
+        warn!("No auth keys configured - anyone who can reach the server can use it");
+    }
+    let prompt: Arc<str> = config.load_prompt().unwrap_or_else(|e| exit_with(e)).into();
+    let data_dir = config.data_dir.clone();
+    let turn_mode = config.turn_mode;
+    let record_audio = config.record_audio;
+    let quotas = Arc::new(Quotas::new(config.quota));
+    let admission = Admission::new(config.max_sessions);
+    // All of them parse; the config was validated
+    let allowed_origins: Vec<HeaderValue> =
+        config.allowed_origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()).collect();
+    let teaching_timeout = (config.teaching_timeout_ms > 0).then(|| Duration::from_millis(config.teaching_timeout_ms));
+    let store: Arc<dyn SessionStore> = match SqliteSessionStore::open(&data_dir.join("sessions.db")) {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            warn!("Failed to open session database, sessions will not survive a restart: {:#}", e);
+            Arc::new(MemorySessionStore::default())
+        }
+    };
+    let sessions = Sessions::new(store);
+
+    match config.mode {
+        Mode::Scripted => {
+            let script = match &config.script {
+                Some(path) => Script::load(path).unwrap_or_else(|e| exit_with(e)),
+                None => Script::test_mode(),
+            };
+            info!("Running in scripted mode - no model behind the relay");
+            let app = AppState::<ScriptedProvider> {
+                sessions,
+                data_dir,
+                prompt,
+                provider: Arc::new(script),
+                turn_mode,
+                teaching_timeout,
+                record_audio,
+                auth,
+                quotas,
+                admission,
+                direct: Arc::new(DirectSessions::default()),
+                allowed_origins,
+            };
+            serve(config.bind, app).await
+        }
+        Mode::Openai => {
+            let openai = OpenAiConfig::new(&config.openai, config.vad).unwrap_or_else(|e| exit_with(e));
+            info!("Relaying to {} with model {}", openai.base_url, openai.model);
+            let app = AppState::<OASocket> {
+                sessions,
+                data_dir,
+                prompt,
+                provider: Arc::new(openai),
+                turn_mode,
+                teaching_timeout,
+                record_audio,
+                auth,
+                quotas,
+                admission,
+                direct: Arc::new(DirectSessions::default()),
+                allowed_origins,
+            };
+            serve(config.bind, app).await
+        }
+    }
+}
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
+async fn serve<P: RealtimeProvider>(bind: SocketAddr, app: AppState<P>) {
+    let listener = tokio::net::TcpListener::bind(bind)
         .await
-        .expect("Failed to bind TCP listener");
-        axum::serve(listener,app).await.unwrap();
+        .unwrap_or_else(|e| exit_with(anyhow::anyhow!("Failed to bind {}: {}", bind, e)));
+    info!("Listening on {}", bind);
+    // Browsers in direct sessions can vanish without a word, still holding a place
+    let sweeper = app.clone();
+    tokio::spawn(async move {
+        let mut sweep = tokio::time::interval(DIRECT_SWEEP_INTERVAL);
+        loop {
+            sweep.tick().await;
+            finish_idle_direct_sessions(&sweeper).await;
+        }
+    });
+    axum::serve(listener, router(app)).await.unwrap();
+}
+
+// An access token for `user_id`, signed with `key_id` or the only configured key.
+fn issue(auth: &Authenticator, config: &Config, key_id: Option<&str>, user_id: &str) -> anyhow::Result<String> {
+    let key_id = match key_id {
+        Some(key_id) => key_id,
+        None => match config.auth.keys.keys().collect::<Vec<_>>()[..] {
+            [only] => only.as_str(),
+            [] => anyhow::bail!("no auth keys are configured to sign tokens with"),
+            _ => anyhow::bail!("several auth keys are configured; pick one with --token-key"),
+        },
+    };
+    auth.issue(key_id, user_id, chrono::Duration::days(ISSUED_TOKEN_DAYS))
+}
+
+fn exit_with(error: anyhow::Error) -> ! {
+    // Straight to stderr: logging may not be set up yet
+    eprintln!("{:#}", error);
+    std::process::exit(1);
 }
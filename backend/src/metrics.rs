@@ -0,0 +1,159 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{Histogram, exponential_buckets};
+use prometheus_client::registry::Registry;
+
+use crate::conversation::ConversationState;
+
+// What the relay exports at /metrics. There is one set for the whole process, so
+// anything that sees an event can count it without being handed somewhere to.
+struct Metrics {
+    registry: Registry,
+    // Sessions holding a place under the session cap, relayed or direct.
+    active_sessions: Gauge,
+    upstream_connect_seconds: Histogram,
+    upstream_connect_failures: Counter,
+    // "in" is learner audio from browsers, "out" tutor audio to them.
+    audio_bytes: Family<DirectionLabel, Counter>,
+    // From the end of a learner turn to the first audio of the tutor's answer.
+    response_latency_seconds: Histogram,
+    state_transitions: Family<StateLabel, Counter>,
+    upstream_errors: Family<CodeLabel, Counter>,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DirectionLabel {
+    direction: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StateLabel {
+    state: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CodeLabel {
+    code: String,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Self {
+        let metrics = Self {
+            registry: Registry::with_prefix("feynman"),
+            active_sessions: Gauge::default(),
+            // 50 ms to about 25 s
+            upstream_connect_seconds: Histogram::new(exponential_buckets(0.05, 2.0, 10)),
+            upstream_connect_failures: Counter::default(),
+            audio_bytes: Family::default(),
+            // 100 ms to about 8.6 s
+            response_latency_seconds: Histogram::new(exponential_buckets(0.1, 1.5, 12)),
+            state_transitions: Family::default(),
+            upstream_errors: Family::default(),
+        };
+        let mut registry = metrics.registry;
+        registry.register("active_sessions", "Sessions in progress", metrics.active_sessions.clone());
+        registry.register(
+            "upstream_connect_seconds",
+            "Time taken to open an upstream session",
+            metrics.upstream_connect_seconds.clone(),
+        );
+        registry.register(
+            "upstream_connect_failures",
+            "Upstream sessions that failed to open",
+            metrics.upstream_connect_failures.clone(),
+        );
+        registry.register("audio_bytes", "PCM16 audio relayed, by direction", metrics.audio_bytes.clone());
+        registry.register(
+            "response_latency_seconds",
+            "Time from the end of a learner turn to the first audio of the reply",
+            metrics.response_latency_seconds.clone(),
+        );
+        registry.register(
+            "state_transitions",
+            "Conversation state transitions, by the state entered",
+            metrics.state_transitions.clone(),
+        );
+        registry.register("upstream_errors", "Error events from OpenAI, by code", metrics.upstream_errors.clone());
+        Self { registry, ..metrics }
+    }
+}
+
+// Counts a session as active until dropped.
+pub struct ActiveSession(());
+
+pub fn session_started() -> ActiveSession {
+    METRICS.active_sessions.inc();
+    ActiveSession(())
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        METRICS.active_sessions.dec();
+    }
+}
+
+pub fn upstream_connected(took: Duration) {
+    METRICS.upstream_connect_seconds.observe(took.as_secs_f64());
+}
+
+pub fn upstream_connect_failed() {
+    METRICS.upstream_connect_failures.inc();
+}
+
+pub fn learner_audio(bytes: usize) {
+    METRICS.audio_bytes.get_or_create(&DirectionLabel { direction: "in" }).inc_by(bytes as u64);
+}
+
+pub fn tutor_audio(bytes: usize) {
+    METRICS.audio_bytes.get_or_create(&DirectionLabel { direction: "out" }).inc_by(bytes as u64);
+}
+
+pub fn response_latency(took: Duration) {
+    METRICS.response_latency_seconds.observe(took.as_secs_f64());
+}
+
+pub fn state_entered(state: &ConversationState) {
+    // Labelled as the state is named in the protocol
+    let state = serde_json::to_value(state).ok().and_then(|name| name.as_str().map(str::to_string));
+    let state = state.unwrap_or_else(|| "unknown".to_string());
+    METRICS.state_transitions.get_or_create(&StateLabel { state }).inc();
+}
+
+pub fn upstream_error(code: Option<&str>) {
+    let code = code.unwrap_or("unknown").to_string();
+    METRICS.upstream_errors.get_or_create(&CodeLabel { code }).inc();
+}
+
+// Everything so far, in the OpenMetrics text format.
+pub fn render() -> String {
+    let mut text = String::new();
+    prometheus_client::encoding::text::encode(&mut text, &METRICS.registry).expect("writing to a String cannot fail");
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_with_their_labels() {
+        upstream_error(Some("metrics_test_code"));
+        upstream_error(Some("metrics_test_code"));
+        state_entered(&ConversationState::WaitingForTopic);
+        let active = session_started();
+
+        let text = render();
+        assert!(text.contains("feynman_upstream_errors_total{code=\"metrics_test_code\"} 2\n"), "{text}");
+        assert!(text.contains("feynman_state_transitions_total{state=\"waiting_for_topic\"}"));
+        assert!(text.contains("# TYPE feynman_response_latency_seconds histogram"));
+        assert!(text.ends_with("# EOF\n"));
+        drop(active);
+    }
+}
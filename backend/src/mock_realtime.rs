@@ -0,0 +1,127 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tracing::warn;
+
+use crate::protocol::SessionMode;
+use crate::provider::{RealtimeProvider, SessionOptions};
+use crate::realtime::{ClientEvent, ServerEvent};
+use crate::scripted::{Script, ScriptedProvider};
+
+// A stand-in for the OpenAI Realtime WebSocket API on localhost, so tests can drive
+// the real `OASocket` path without network access. Each connection announces
+// session.created, acknowledges session.update and replays the next of `scripts`
+// (the last one repeats); scripts inject errors as raw `error` events and drop the
+// connection with `{"disconnect": true}`.
+pub struct MockRealtime {
+    pub url: String,
+    // Client events received, one list per connection.
+    received: Arc<Mutex<Vec<Vec<ClientEvent>>>>,
+}
+
+impl MockRealtime {
+    pub async fn start(scripts: Vec<Script>) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}/v1/realtime", listener.local_addr()?);
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let log = received.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let connection = {
+                    let mut log = log.lock().unwrap();
+                    log.push(Vec::new());
+                    log.len() - 1
+                };
+                let script = scripts[connection.min(scripts.len() - 1)].clone();
+                let log = log.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_connection(stream, script, log, connection).await {
+                        warn!("Mock realtime connection {} failed: {:#}", connection, e);
+                    }
+                });
+            }
+        });
+        Ok(Self { url, received })
+    }
+
+    pub fn received(&self) -> Vec<Vec<ClientEvent>> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    script: Script,
+    log: Arc<Mutex<Vec<Vec<ClientEvent>>>>,
+    connection: usize,
+) -> Result<()> {
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, require_bearer).await?;
+
+    send(&mut ws, &ServerEvent::SessionCreated { session: serde_json::json!({}) }).await?;
+    let options = SessionOptions { mode: SessionMode::Voice, turn_mode: Default::default() };
+    let mut upstream = ScriptedProvider::connect(&script, "", options).await?;
+
+    loop {
+        tokio::select! {
+            msg = ws.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let event: ClientEvent = serde_json::from_str(&text)?;
+                    log.lock().unwrap()[connection].push(event.clone());
+                    match event {
+                        ClientEvent::SessionUpdate { session } => {
+                            send(&mut ws, &ServerEvent::SessionUpdated { session: serde_json::to_value(session)? }).await?;
+                        }
+                        ClientEvent::InputAudioBufferAppend { .. } => upstream.send_audio(Default::default()).await?,
+                        ClientEvent::InputAudioBufferCommit => upstream.commit().await?,
+                        ClientEvent::ResponseCreate { .. } => upstream.create_response("").await?,
+                        ClientEvent::ConversationItemCreate { item, .. } => upstream.create_item(item).await?,
+                        ClientEvent::ResponseCancel { response_id } => {
+                            upstream.cancel_response(response_id.as_deref().unwrap_or_default()).await?
+                        }
+                        ClientEvent::ConversationItemTruncate { item_id, audio_end_ms, .. } => {
+                            upstream.truncate(&item_id, audio_end_ms).await?
+                        }
+                        _ => {}
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+            event = upstream.next_event() => match event {
+                Ok(event) => send(&mut ws, &event).await?,
+                // Scripted disconnect: drop the socket without a close handshake
+                Err(_) => return Ok(()),
+            },
+        }
+    }
+}
+
+// Turn away clients that would not get past the real API either.
+// The callback's signature is tungstenite's.
+#[allow(clippy::result_large_err)]
+fn require_bearer(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    let bearer = request
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("Bearer "));
+    if bearer {
+        Ok(response)
+    } else {
+        let mut error = ErrorResponse::new(Some("missing bearer token".to_string()));
+        *error.status_mut() = StatusCode::UNAUTHORIZED;
+        Err(error)
+    }
+}
+
+async fn send(ws: &mut tokio_tungstenite::WebSocketStream<TcpStream>, event: &ServerEvent) -> Result<()> {
+    ws.send(Message::Text(serde_json::to_string(event)?.into())).await?;
+    Ok(())
+}
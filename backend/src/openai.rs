@@ -1,11 +1,121 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::{connect_async, tungstenite::Message};
-use futures_util::{SinkExt, StreamExt};
-use anyhow::Result;
-use serde_json::json;
-use base64;
+use tracing::{debug, info, warn};
+
+use crate::config::{OpenAiSettings, VadSettings};
+use crate::direct::DirectUpstream;
+use crate::protocol::{SessionMode, TurnMode};
+use crate::provider::{RealtimeProvider, SessionOptions};
+use crate::realtime::{
+    AudioFormat, CALL_TAG, ClientEvent, InputAudioTranscription, Item, Modality, ResponseConfig, ServerEvent,
+    SessionConfig, Tool, ToolChoice, TurnDetection,
+};
+use crate::tools;
+
+pub const DEFAULT_REALTIME_URL: &str = "wss://api.openai.com/v1/realtime";
+pub const DEFAULT_MODEL: &str = "gpt-4o-realtime-preview-2024-12-17";
+
+// How to reach OpenAI and set up its sessions, built once at startup. `base_url`
+// can point at a proxy or, in tests, at a local mock of the Realtime API.
+pub struct OpenAiConfig {
+    pub api_key: String,
+    pub base_url: String,
+    pub model: String,
+    pub voice: String,
+    pub vad: VadSettings,
+}
+
+impl OpenAiConfig {
+    // The API key only ever comes from the environment.
+    pub fn new(settings: &OpenAiSettings, vad: VadSettings) -> Result<Self> {
+        let api_key = std::env::var("OPENAI_API_KEY")
+            .map_err(|_| anyhow::anyhow!("OPENAI_API_KEY environment variable not set"))?;
+        if !api_key.starts_with("sk-") {
+            return Err(anyhow::anyhow!("Invalid OpenAI API key format"));
+        }
+        Ok(Self {
+            api_key,
+            base_url: settings.url.clone(),
+            model: settings.model.clone(),
+            voice: settings.voice.clone(),
+            vad,
+        })
+    }
 
-pub struct OASocket{
+    fn url(&self) -> String {
+        format!("{}?model={}", self.base_url.trim_end_matches('/'), self.model)
+    }
+
+    // The REST side of `base_url`: the same path over https (or http for a local mock).
+    fn rest_url(&self) -> String {
+        let base = self.base_url.trim_end_matches('/');
+        match base.split_once("://") {
+            Some(("wss", rest)) => format!("https://{rest}"),
+            Some(("ws", rest)) => format!("http://{rest}"),
+            _ => base.to_string(),
+        }
+    }
+
+    // Where a browser holding a client secret opens its WebRTC connection.
+    pub fn webrtc_url(&self) -> String {
+        format!("{}?model={}", self.rest_url(), self.model)
+    }
+
+    // Have OpenAI create a session set up as `session` and hand back a short-lived
+    // secret a browser can connect to it with, in place of the API key.
+    pub async fn mint_client_secret(&self, session: SessionConfig) -> Result<ClientSecret> {
+        #[derive(Serialize)]
+        struct CreateSession<'a> {
+            model: &'a str,
+            #[serde(flatten)]
+            session: SessionConfig,
+        }
+        #[derive(Deserialize)]
+        struct Created {
+            client_secret: ClientSecret,
+        }
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/sessions", self.rest_url()))
+            .bearer_auth(&self.api_key)
+            .json(&CreateSession { model: &self.model, session })
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("OpenAI refused to create a session ({}): {}", status, body));
+        }
+        Ok(response.json::<Created>().await?.client_secret)
+    }
+}
+
+// A config pointing at the real endpoint with a fake key; tests swap in what they need.
+#[cfg(test)]
+pub fn test_openai_config() -> OpenAiConfig {
+    OpenAiConfig {
+        api_key: "sk-test".to_string(),
+        base_url: DEFAULT_REALTIME_URL.to_string(),
+        model: DEFAULT_MODEL.to_string(),
+        voice: "alloy".to_string(),
+        vad: VadSettings::default(),
+    }
+}
+
+// A short-lived key for one Realtime session, safe to give to a browser.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientSecret {
+    pub value: String,
+    // Seconds since the epoch.
+    pub expires_at: i64,
+}
+
+pub struct OASocket {
     // create a websocket object to send messages to OpenAI
     write: futures_util::stream::SplitSink<
         tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
@@ -15,100 +125,246 @@ pub struct OASocket{
     read: futures_util::stream::SplitStream<
         tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
     >,
+    events: SessionEvents,
+}
+
+impl OASocket {
+    pub async fn send(&mut self, event: &ClientEvent) -> Result<()> {
+        let text = serde_json::to_string(event)?;
+        self.write.send(Message::Text(text.into())).await?;
+        Ok(())
+    }
 }
 
-impl OASocket{
-    pub async fn connect(api_key: &str, system_prompt: &str) -> Result<Self>{
-        let url = "wss://api.openai.com/v1/realtime?model=gpt-4o-realtime-preview-2024-12-17";
-        println!("Attempting to connect to OpenAI at: {}", url);
-        
-        let mut req = url.into_client_request()?;   
+// Builds the client events of one upstream session, whichever way they reach
+// OpenAI: over the relay's WebSocket, or through the browser's own connection in a
+// direct session.
+pub struct SessionEvents {
+    // What the tutor answers in: text, plus audio in a voice session.
+    modalities: Vec<Modality>,
+    options: SessionOptions,
+    voice: String,
+    vad: VadSettings,
+    // Whether turn detection is set up for the learner's explanation.
+    teaching: bool,
+}
+
+impl SessionEvents {
+    pub fn new(config: &OpenAiConfig, options: SessionOptions) -> Self {
+        let modalities = match options.mode {
+            SessionMode::Voice => vec![Modality::Text, Modality::Audio],
+            SessionMode::Text => vec![Modality::Text],
+        };
+        Self { modalities, options, voice: config.voice.clone(), vad: config.vad, teaching: false }
+    }
+
+    // The whole session setup, with `system_prompt` as its instructions.
+    pub fn session_config(&self, system_prompt: &str) -> SessionConfig {
+        let voice = self.options.mode == SessionMode::Voice;
+        SessionConfig {
+            modalities: Some(self.modalities.clone()),
+            instructions: Some(system_prompt.to_string()),
+            voice: Some(self.voice.clone()),
+            input_audio_format: Some(AudioFormat::Pcm16),
+            output_audio_format: Some(AudioFormat::Pcm16),
+            input_audio_transcription: voice.then(|| InputAudioTranscription { model: "whisper-1".to_string() }),
+            turn_detection: Some(self.turn_detection()),
+            tools: Some(tools::session_tools()),
+            tool_choice: Some(ToolChoice::Mode("auto".to_string())),
+        }
+    }
+
+    // The backend asks for every response itself, so detected turns never trigger
+    // one upstream.
+    fn turn_detection(&self) -> Option<TurnDetection> {
+        match (self.options.mode, self.options.turn_mode) {
+            (SessionMode::Text, _) | (_, TurnMode::PushToTalk) => None,
+            (SessionMode::Voice, TurnMode::ServerVad) => Some(TurnDetection::ServerVad {
+                threshold: self.vad.threshold,
+                prefix_padding_ms: self.vad.prefix_padding_ms,
+                silence_duration_ms: if self.teaching {
+                    self.vad.teaching_silence_duration_ms
+                } else {
+                    self.vad.silence_duration_ms
+                },
+                create_response: false,
+                interrupt_response: true,
+            }),
+            (SessionMode::Voice, TurnMode::SemanticVad) => Some(TurnDetection::SemanticVad {
+                // A learner explaining needs room to think mid-sentence
+                eagerness: if self.teaching { "low" } else { "auto" }.to_string(),
+                create_response: false,
+                interrupt_response: true,
+            }),
+        }
+    }
+
+    // The session.update that switches turn detection, if anything changes.
+    pub fn set_teaching(&mut self, teaching: bool) -> Option<ClientEvent> {
+        if self.teaching == teaching {
+            return None;
+        }
+        self.teaching = teaching;
+        let turn_detection = self.turn_detection()?;
+        Some(ClientEvent::SessionUpdate {
+            session: SessionConfig { turn_detection: Some(Some(turn_detection)), ..Default::default() },
+        })
+    }
+
+    pub fn create_response(&self, instructions: &str) -> ClientEvent {
+        ClientEvent::ResponseCreate {
+            response: Some(ResponseConfig {
+                modalities: Some(self.modalities.clone()),
+                instructions: Some(instructions.to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn request_function_call(&self, instructions: &str, function: Tool) -> ClientEvent {
+        let choice = ToolChoice::Function { kind: function.kind, name: function.name.clone() };
+        let metadata = HashMap::from([(CALL_TAG.to_string(), function.name.clone())]);
+        ClientEvent::ResponseCreate {
+            response: Some(ResponseConfig {
+                modalities: Some(vec![Modality::Text]),
+                instructions: Some(instructions.to_string()),
+                tools: Some(vec![function]),
+                tool_choice: Some(choice),
+                conversation: Some("none".to_string()),
+                metadata: Some(metadata),
+            }),
+        }
+    }
+
+    pub fn cancel_response(&self, response_id: &str) -> ClientEvent {
+        ClientEvent::ResponseCancel { response_id: Some(response_id.to_string()) }
+    }
+
+    pub fn truncate(&self, item_id: &str, audio_end_ms: u64) -> ClientEvent {
+        ClientEvent::ConversationItemTruncate { item_id: item_id.to_string(), content_index: 0, audio_end_ms }
+    }
+
+    pub fn create_item(&self, item: Item) -> ClientEvent {
+        ClientEvent::ConversationItemCreate { previous_item_id: None, item }
+    }
+}
+
+impl RealtimeProvider for OASocket {
+    type Config = OpenAiConfig;
+
+    async fn connect(config: &OpenAiConfig, system_prompt: &str, options: SessionOptions) -> Result<Self> {
+        let api_key = &config.api_key;
+        let url = config.url();
+        debug!("Attempting to connect to OpenAI at: {}", url);
+
+        let mut req = url.into_client_request()?;
         req.headers_mut().insert("Authorization", format!("Bearer {api_key}").parse()?);
         req.headers_mut().insert("OpenAI-Beta", "realtime=v1".parse()?);
 
-        println!("Connecting to OpenAI WebSocket...");
+        debug!("Connecting to OpenAI WebSocket...");
         let (ws, response) = connect_async(req).await?;
-        println!("OpenAI WebSocket connected successfully. Response status: {:?}", response.status());
-        
-        let (mut write, mut read) = ws.split();
-        
+        debug!("OpenAI WebSocket connected successfully. Response status: {:?}", response.status());
+
+        let (write, mut read) = ws.split();
+
         // Wait for OpenAI session response
-        if let Some(msg) = read.next().await {
-            println!("OpenAI session response: {:?}", msg);
-        } else {
-            return Err(anyhow::anyhow!("No initial response from OpenAI"));
+        match read.next().await {
+            Some(Ok(Message::Text(text))) => match serde_json::from_str::<ServerEvent>(&text)? {
+                ServerEvent::SessionCreated { .. } => debug!("OpenAI session created"),
+                ServerEvent::Error { error } => {
+                    return Err(anyhow::anyhow!("OpenAI rejected the session: {}", error.message));
+                }
+                other => warn!("Unexpected initial event from OpenAI: {}", other.event_type()),
+            },
+            Some(msg) => debug!("OpenAI session response: {:?}", msg),
+            None => return Err(anyhow::anyhow!("No initial response from OpenAI")),
         }
-        
+
+        let mut socket = Self { write, read, events: SessionEvents::new(config, options) };
+
         // Send proper session.update configuration message
-        println!("Sending session.update configuration...");
-        let config = json!({
-            "type": "session.update",
-            "session": {
-                "modalities": ["text", "audio"],
-                "instructions": system_prompt,
-                "voice": "alloy",
-                "input_audio_format": "pcm16",
-                "output_audio_format": "pcm16",
-                "turn_detection": {
-                    "type": "server_vad",
-                    "threshold": 0.5,
-                    "prefix_padding_ms": 300,
-                    "silence_duration_ms": 200,
-                    "create_response": true,
-                    "interrupt_response": true
-                }
-            }
-        });
-        write.send(Message::Text(config.to_string().into())).await?;
+        debug!("Sending session.update configuration...");
+        let session = socket.events.session_config(system_prompt);
+        socket.send(&ClientEvent::SessionUpdate { session }).await?;
 
-        println!("OpenAI connection setup complete");
-        Ok(Self { write, read })
-     }
+        info!("OpenAI connection setup complete");
+        Ok(socket)
+    }
+
+    async fn open_direct(
+        config: &OpenAiConfig,
+        system_prompt: &str,
+        options: SessionOptions,
+    ) -> Result<DirectUpstream> {
+        let events = SessionEvents::new(config, options);
+        let secret = config.mint_client_secret(events.session_config(system_prompt)).await?;
+        Ok(DirectUpstream::new(events, secret, config.webrtc_url()))
+    }
+
+    async fn set_teaching(&mut self, teaching: bool) -> Result<()> {
+        match self.events.set_teaching(teaching) {
+            Some(update) => self.send(&update).await,
+            None => Ok(()),
+        }
+    }
 
-    pub async fn send_audio(&mut self, data: axum::body::Bytes) -> Result<()>{
+    async fn send_audio(&mut self, data: axum::body::Bytes) -> Result<()> {
         // Convert audio data to base64 for OpenAI realtime API
-        let audio_base64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &data);
-        
-        // Create proper input_audio_buffer.append event
-        let audio_event = json!({
-            "type": "input_audio_buffer.append",
-            "audio": audio_base64
-        });
-        
-        self.write.send(Message::Text(audio_event.to_string().into())).await?;
-        Ok(())
+        let audio = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &data);
+        self.send(&ClientEvent::InputAudioBufferAppend { audio }).await
     }
 
-    pub async fn commit_audio_buffer(&mut self) -> Result<()> {
-        let commit_event = json!({
-            "type": "input_audio_buffer.commit"
-        });
-        eprintln!("Sending input_audio_buffer.commit event");
-        self.write.send(Message::Text(commit_event.to_string().into())).await?;
-        eprintln!("input_audio_buffer.commit event sent successfully");
+    async fn commit(&mut self) -> Result<()> {
+        debug!("Sending input_audio_buffer.commit event");
+        self.send(&ClientEvent::InputAudioBufferCommit).await?;
+        debug!("input_audio_buffer.commit event sent successfully");
         Ok(())
     }
 
-    pub async fn create_response(&mut self) -> Result<()> {
-        let response_event = json!({
-            "type": "response.create",
-            "response": {
-                "modalities": ["text", "audio"],
-                "instructions": "Follow the Feynman tutor protocol."
-            }
-        });
-        eprintln!("Sending response.create event");
-        self.write.send(Message::Text(response_event.to_string().into())).await?;
-        eprintln!("response.create event sent successfully");
+    async fn create_response(&mut self, instructions: &str) -> Result<()> {
+        debug!("Sending response.create event");
+        self.send(&self.events.create_response(instructions)).await?;
+        debug!("response.create event sent successfully");
         Ok(())
     }
-    pub async fn next(&mut self) -> Result<Message> {
-        let msg = self.read.next().await.ok_or_else(|| anyhow::anyhow!("Failed to receive message"))??;
-        Ok(msg)
+
+    async fn request_function_call(&mut self, instructions: &str, function: Tool) -> Result<()> {
+        self.send(&self.events.request_function_call(instructions, function)).await
+    }
+
+    async fn cancel_response(&mut self, response_id: &str) -> Result<()> {
+        self.send(&self.events.cancel_response(response_id)).await
     }
-    pub async fn close(&mut self) -> anyhow::Result<()> {
-        use futures_util::SinkExt;
+
+    async fn truncate(&mut self, item_id: &str, audio_end_ms: u64) -> Result<()> {
+        self.send(&self.events.truncate(item_id, audio_end_ms)).await
+    }
+
+    async fn create_item(&mut self, item: Item) -> Result<()> {
+        self.send(&self.events.create_item(item)).await
+    }
+
+    async fn next_event(&mut self) -> Result<ServerEvent> {
+        loop {
+            let msg = self.read.next().await.ok_or_else(|| anyhow::anyhow!("Failed to receive message"))??;
+            match msg {
+                Message::Text(text) => {
+                    let event = serde_json::from_str::<ServerEvent>(&text)?;
+                    if let ServerEvent::Unknown(raw) = &event {
+                        warn!("Unrecognised OpenAI event: {}", raw);
+                    }
+                    return Ok(event);
+                }
+                Message::Close(frame) => return Err(anyhow::anyhow!("OpenAI closed the connection: {:?}", frame)),
+                _ => {
+                    // Ping/pong and binary frames carry no Realtime events
+                }
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<()> {
         self.write.send(tokio_tungstenite::tungstenite::Message::Close(None)).await?;
         Ok(())
     }
-}
\ No newline at end of file
+}
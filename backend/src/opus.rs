@@ -0,0 +1,166 @@
+use anyhow::Result;
+
+// Opus on the browser link. At 24 kHz mono it takes about 24 kbit/s where PCM16
+// takes 384, which matters on a phone's data plan. Upstream only speaks PCM16, so
+// learner audio is decoded on the way in and tutor audio encoded on the way out.
+// It needs libopus, so it is only built with the `opus` feature; without it a
+// client that asks for Opus is answered with PCM16.
+pub const AVAILABLE: bool = cfg!(feature = "opus");
+
+// The sample rates Opus runs at, and it does mono or stereo.
+pub const SAMPLE_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+pub const MAX_CHANNELS: u16 = 2;
+
+pub use codec::{Decoder, Encoder};
+
+#[cfg(feature = "opus")]
+mod codec {
+    use std::sync::Mutex;
+
+    use anyhow::Result;
+    use audiopus::coder::{Decoder as OpusDecoder, Encoder as OpusEncoder};
+    use audiopus::{Application, Bitrate, Channels, SampleRate, Signal};
+
+    use crate::audio::UPSTREAM_SAMPLE_RATE;
+
+    // The longest packet Opus decodes to.
+    const MAX_PACKET_MS: usize = 120;
+    // Tutor audio goes out in 20 ms packets.
+    const FRAME_SAMPLES: usize = UPSTREAM_SAMPLE_RATE as usize / 50;
+    const OUTPUT_BITRATE: i32 = 24_000;
+    // What libopus recommends sizing its output for.
+    const MAX_PACKET_BYTES: usize = 4_000;
+
+    fn sample_rate(hz: u32) -> Result<SampleRate> {
+        Ok(SampleRate::try_from(hz as i32)?)
+    }
+
+    // Opus packets, one per call, to interleaved PCM16.
+    pub struct Decoder {
+        // libopus state may not be shared between threads, but the session it
+        // belongs to is; only one place ever decodes with it.
+        inner: Mutex<OpusDecoder>,
+        channels: usize,
+        max_samples: usize,
+    }
+
+    impl Decoder {
+        pub fn new(hz: u32, channels: u16) -> Result<Self> {
+            let layout = if channels == 1 { Channels::Mono } else { Channels::Stereo };
+            let inner = OpusDecoder::new(sample_rate(hz)?, layout)?;
+            let channels = channels as usize;
+            let max_samples = hz as usize * MAX_PACKET_MS / 1000 * channels;
+            Ok(Self { inner: Mutex::new(inner), channels, max_samples })
+        }
+
+        // PCM16 LE bytes for one packet.
+        pub fn decode(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+            let mut samples = vec![0i16; self.max_samples];
+            let decoder = self.inner.get_mut().unwrap();
+            let frames = decoder.decode(Some(packet.try_into()?), samples.as_mut_slice().try_into()?, false)?;
+            Ok(samples[..frames * self.channels].iter().flat_map(|sample| sample.to_le_bytes()).collect())
+        }
+    }
+
+    // Tutor audio, 24 kHz mono PCM16, to 20 ms Opus packets. Audio that does not
+    // fill a packet waits for the next chunk, or for the end of the turn.
+    pub struct Encoder {
+        // As for the decoder.
+        inner: Mutex<OpusEncoder>,
+        pending: Vec<i16>,
+    }
+
+    impl Encoder {
+        pub fn new() -> Result<Self> {
+            let mut inner = OpusEncoder::new(sample_rate(UPSTREAM_SAMPLE_RATE)?, Channels::Mono, Application::Voip)?;
+            inner.set_bitrate(Bitrate::BitsPerSecond(OUTPUT_BITRATE))?;
+            inner.set_signal(Signal::Voice)?;
+            Ok(Self { inner: Mutex::new(inner), pending: Vec::new() })
+        }
+
+        // The packets `pcm` completes.
+        pub fn encode(&mut self, pcm: &[u8]) -> Result<Vec<Vec<u8>>> {
+            self.pending.extend(pcm.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])));
+            let whole = self.pending.len() - self.pending.len() % FRAME_SAMPLES;
+            let frames: Vec<i16> = self.pending.drain(..whole).collect();
+            frames.chunks_exact(FRAME_SAMPLES).map(|frame| self.packet(frame)).collect()
+        }
+
+        // Whatever is left, padded out with silence to a last packet.
+        pub fn flush(&mut self) -> Result<Option<Vec<u8>>> {
+            if self.pending.is_empty() {
+                return Ok(None);
+            }
+            let mut frame = std::mem::take(&mut self.pending);
+            frame.resize(FRAME_SAMPLES, 0);
+            self.packet(&frame).map(Some)
+        }
+
+        pub fn clear(&mut self) {
+            self.pending.clear();
+        }
+
+        fn packet(&mut self, frame: &[i16]) -> Result<Vec<u8>> {
+            let mut packet = vec![0u8; MAX_PACKET_BYTES];
+            let len = self.inner.get_mut().unwrap().encode(frame, &mut packet)?;
+            packet.truncate(len);
+            Ok(packet)
+        }
+    }
+}
+
+// Stand-ins that can never be made, so nothing reaches their methods.
+#[cfg(not(feature = "opus"))]
+mod codec {
+    use std::convert::Infallible;
+
+    use anyhow::Result;
+
+    pub struct Decoder(Infallible);
+
+    impl Decoder {
+        pub fn new(_hz: u32, _channels: u16) -> Result<Self> {
+            Err(super::unavailable())
+        }
+
+        pub fn decode(&mut self, _packet: &[u8]) -> Result<Vec<u8>> {
+            match self.0 {}
+        }
+    }
+
+    pub struct Encoder(Infallible);
+
+    impl Encoder {
+        pub fn new() -> Result<Self> {
+            Err(super::unavailable())
+        }
+
+        pub fn encode(&mut self, _pcm: &[u8]) -> Result<Vec<Vec<u8>>> {
+            match self.0 {}
+        }
+
+        pub fn flush(&mut self) -> Result<Option<Vec<u8>>> {
+            match self.0 {}
+        }
+
+        pub fn clear(&mut self) {
+            match self.0 {}
+        }
+    }
+}
+
+#[cfg(not(feature = "opus"))]
+fn unavailable() -> anyhow::Error {
+    anyhow::anyhow!("this server was built without Opus support")
+}
+
+// Whether Opus can run at this rate and channel count.
+pub fn check_format(sample_rate: u32, channels: u16) -> Result<(), String> {
+    if !SAMPLE_RATES.contains(&sample_rate) {
+        return Err(format!("Opus does not run at {sample_rate} Hz, only at one of {SAMPLE_RATES:?}"));
+    }
+    if channels == 0 || channels > MAX_CHANNELS {
+        return Err(format!("Opus carries 1 or {MAX_CHANNELS} channels, not {channels}"));
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use crate::audio::UPSTREAM_SAMPLE_RATE;
+
+// Keeps track of the tutor audio the browser is playing, so that when the learner
+// barges in the relay knows which response to cancel and where to cut the tutor's
+// turn off: at what the browser reports having played, not at what was sent.
+#[derive(Default)]
+pub struct Playback {
+    current: Option<TutorAudio>,
+}
+
+struct TutorAudio {
+    response_id: String,
+    item_id: String,
+    sent_bytes: usize,
+    played_ms: u64,
+    // Upstream is still producing this response.
+    responding: bool,
+    interrupted: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AudioDelta {
+    Forward { starts_item: bool },
+    // Audio of a turn the learner interrupted.
+    Drop,
+}
+
+// What to do about a barge-in.
+#[derive(Debug, PartialEq)]
+pub struct Interruption {
+    // The response to cancel, if upstream is still generating it.
+    pub response_id: Option<String>,
+    pub item_id: String,
+    pub audio_end_ms: u64,
+}
+
+impl Playback {
+    // Account for a tutor audio delta of `bytes` PCM16 bytes before it is forwarded.
+    pub fn on_audio(&mut self, response_id: &str, item_id: &str, bytes: usize) -> AudioDelta {
+        match &mut self.current {
+            Some(audio) if audio.item_id == item_id => {
+                if audio.interrupted {
+                    return AudioDelta::Drop;
+                }
+                audio.sent_bytes += bytes;
+                AudioDelta::Forward { starts_item: false }
+            }
+            _ => {
+                self.current = Some(TutorAudio {
+                    response_id: response_id.to_string(),
+                    item_id: item_id.to_string(),
+                    sent_bytes: bytes,
+                    played_ms: 0,
+                    responding: true,
+                    interrupted: false,
+                });
+                AudioDelta::Forward { starts_item: true }
+            }
+        }
+    }
+
+    pub fn on_response_done(&mut self, response_id: &str) {
+        if let Some(audio) = self.current.as_mut().filter(|audio| audio.response_id == response_id) {
+            audio.responding = false;
+        }
+    }
+
+    // The browser's report of how much of `item_id` it has played.
+    pub fn on_progress(&mut self, item_id: &str, played_ms: u64) {
+        if let Some(audio) = self.current.as_mut().filter(|audio| audio.item_id == item_id) {
+            audio.played_ms = played_ms.min(audio.sent_ms());
+        }
+    }
+
+    // The learner started speaking. Returns what to stop, unless the tutor's audio
+    // has already been played out.
+    pub fn interrupt(&mut self) -> Option<Interruption> {
+        let audio = self.current.as_mut().filter(|audio| !audio.interrupted)?;
+        if !audio.responding && audio.played_ms >= audio.sent_ms() {
+            return None;
+        }
+        audio.interrupted = true;
+        Some(Interruption {
+            response_id: audio.responding.then(|| audio.response_id.clone()),
+            item_id: audio.item_id.clone(),
+            audio_end_ms: audio.played_ms,
+        })
+    }
+}
+
+impl TutorAudio {
+    // Upstream audio is 24 kHz mono PCM16.
+    fn sent_ms(&self) -> u64 {
+        (self.sent_bytes as u64 / 2) * 1000 / u64::from(UPSTREAM_SAMPLE_RATE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 100 ms of upstream audio.
+    const CHUNK: usize = 4800;
+
+    #[test]
+    fn cuts_the_tutor_off_where_the_browser_got_to() {
+        let mut playback = Playback::default();
+        assert_eq!(playback.on_audio("resp_1", "item_1", CHUNK), AudioDelta::Forward { starts_item: true });
+        assert_eq!(playback.on_audio("resp_1", "item_1", CHUNK), AudioDelta::Forward { starts_item: false });
+        playback.on_progress("item_1", 120);
+        // Reports past what was sent are clamped
+        playback.on_progress("item_0", 5000);
+
+        assert_eq!(
+            playback.interrupt(),
+            Some(Interruption { response_id: Some("resp_1".into()), item_id: "item_1".into(), audio_end_ms: 120 })
+        );
+        assert_eq!(playback.interrupt(), None);
+        assert_eq!(playback.on_audio("resp_1", "item_1", CHUNK), AudioDelta::Drop);
+        assert_eq!(playback.on_audio("resp_2", "item_2", CHUNK), AudioDelta::Forward { starts_item: true });
+    }
+
+    #[test]
+    fn nothing_to_interrupt_once_played_out() {
+        let mut playback = Playback::default();
+        assert_eq!(playback.interrupt(), None);
+        playback.on_audio("resp_1", "item_1", CHUNK);
+        playback.on_response_done("resp_1");
+        playback.on_progress("item_1", 100);
+        assert_eq!(playback.interrupt(), None);
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::conversation::{Gap, Verdict, gap_label};
+use crate::realtime::Tool;
+
+// The function the model must call to grade the learner's answer to a probing
+// question, so the backend decides whether to move on.
+pub const GRADE_ANSWER: &str = "grade_answer";
+
+pub fn grade_answer_tool() -> Tool {
+    Tool::function(
+        GRADE_ANSWER,
+        "Grade the learner's answer to the current probing question.",
+        json!({
+            "type": "object",
+            "properties": {
+                "verdict": {
+                    "type": "string",
+                    "enum": ["deep", "needs_reexplain", "review_material"],
+                    "description": "deep: correct, in their own words, with the reasoning behind it. \
+                        needs_reexplain: partly right, vague or parroted. \
+                        review_material: they do not know it or hold a misconception."
+                },
+                "feedback": {
+                    "type": "string",
+                    "description": "One sentence on what the answer got right or is missing."
+                }
+            },
+            "required": ["verdict", "feedback"],
+            "additionalProperties": false
+        }),
+    )
+}
+
+pub fn grading_instructions(gap: &Gap, answer: &str) -> String {
+    let mut instructions = format!(
+        "The learner was asked: \"{}\". It probes this gap in their explanation: {}",
+        gap.question,
+        gap_label(gap.category)
+    );
+    if !gap.excerpt.is_empty() {
+        instructions.push_str(&format!(" (\"{}\")", gap.excerpt));
+    }
+    instructions.push_str(&format!(
+        ". Their answer: \"{answer}\". Call {GRADE_ANSWER} with your verdict on this answer alone."
+    ));
+    if gap.probe.attempts > 0 {
+        instructions.push_str(&format!(
+            " This is attempt {}; last time: {}",
+            gap.probe.attempts + 1,
+            gap.probe.feedback
+        ));
+    }
+    instructions
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Grade {
+    pub verdict: Verdict,
+    pub feedback: String,
+}
+
+pub fn parse_grade(arguments: &str) -> Result<Grade> {
+    Ok(serde_json::from_str(arguments)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_function_arguments() {
+        assert_eq!(
+            parse_grade(r#"{"verdict":"needs_reexplain","feedback":"Says light matters but not why."}"#).unwrap(),
+            Grade { verdict: Verdict::NeedsReexplain, feedback: "Says light matters but not why.".to_string() }
+        );
+        assert!(parse_grade(r#"{"verdict":"great","feedback":""}"#).is_err());
+    }
+}
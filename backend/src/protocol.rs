@@ -0,0 +1,345 @@
+use axum::extract::ws::Message;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{PcmFormat, SampleEncoding};
+use crate::conversation::ConversationState;
+use crate::quota::{DailyUsage, QuotaLimits};
+
+// The browser <-> backend protocol on /ws. Binary frames carry audio; every text
+// frame is one JSON message tagged by "type". The browser opens with `hello`,
+// the backend answers with `welcome` carrying the negotiated version.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Messages the browser sends.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // Must be the first message.
+    Hello(Hello),
+    // The learner finished speaking; commit the buffered audio.
+    CommitAudio,
+    // A typed learner turn, e.g. in a text session.
+    Text { text: String },
+    // The learner has finished explaining the topic; whatever they are still
+    // saying is committed as the last part of it.
+    DoneTeaching,
+    // How much of tutor turn `item_id` the browser has played so far, sent while
+    // it plays. Tells the backend where to cut the turn off on a barge-in.
+    PlaybackProgress { item_id: String, played_ms: u64 },
+    // Try the upstream connection again after it failed.
+    RetryUpstream,
+}
+
+impl ClientMessage {
+    pub fn parse(text: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    // The newest protocol version the client speaks.
+    pub version: u32,
+    // Format of the binary audio frames the client will send. Defaults to what the
+    // upstream session expects (24 kHz mono PCM16).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_audio: Option<PcmFormat>,
+    // Encoding the client would like tutor audio in. PCM16 if absent, or if the
+    // backend cannot produce it; the welcome says which it got.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_encoding: Option<SampleEncoding>,
+    // Id from an earlier welcome, to resume that session after a reconnect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub mode: SessionMode,
+    // How learner turns end; the server's default if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub turn_mode: Option<TurnMode>,
+    // The learner agrees to the session's audio being recorded.
+    #[serde(default)]
+    pub record: bool,
+    // Signed token naming the user, for browsers that cannot set an Authorization
+    // header on a WebSocket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+// How the learner and tutor talk. In a text session the learner sends `text`
+// messages, no audio flows either way and the tutor's replies arrive as captions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionMode {
+    #[default]
+    Voice,
+    Text,
+}
+
+// Who decides that the learner has finished a turn.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TurnMode {
+    // Upstream, after a stretch of silence.
+    #[default]
+    ServerVad,
+    // Upstream, when what was said sounds finished; more patient with pauses.
+    SemanticVad,
+    // The learner, with `commit_audio`. Text sessions always work this way.
+    PushToTalk,
+}
+
+// Messages the backend sends.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome {
+        version: u32,
+        // Present this in a later hello to resume the session.
+        session_id: String,
+        // Whether the hello's session id was found and the session resumed.
+        resumed: bool,
+        // Format of the binary audio frames the backend expects, which is what the
+        // hello asked for unless that was Opus and the backend has none.
+        input_audio: PcmFormat,
+        // Format of the binary audio frames the backend sends.
+        output_audio: PcmFormat,
+        mode: SessionMode,
+        turn_mode: TurnMode,
+        // Whether this connection's audio is being recorded.
+        recording: bool,
+    },
+    Status {
+        status: SessionStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+        // Place in the queue, while `queued`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        position: Option<usize>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    // A finished turn.
+    Transcript {
+        speaker: Speaker,
+        item_id: String,
+        text: String,
+    },
+    // A live caption: the text of turn `item_id` so far, sent as it grows. The
+    // `final` one carries the finished transcript and replaces the partial ones.
+    Caption {
+        speaker: Speaker,
+        item_id: String,
+        text: String,
+        #[serde(rename = "final")]
+        is_final: bool,
+        // When the speaker started, and when this text was current.
+        started_at: DateTime<Utc>,
+        updated_at: DateTime<Utc>,
+    },
+    State {
+        state: ConversationState,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+    },
+    // The session's gap report is ready to download over HTTP from `url`.
+    Report {
+        url: String,
+    },
+    // The binary audio frames that follow belong to tutor turn `item_id`.
+    TutorAudio {
+        item_id: String,
+    },
+    // The learner interrupted tutor turn `item_id`: stop playing it and drop
+    // whatever of it is still queued.
+    FlushAudio {
+        item_id: String,
+    },
+    // How much of their daily allowance the user has used; a limit of 0 is
+    // unlimited.
+    Usage {
+        used: DailyUsage,
+        limits: QuotaLimits,
+    },
+}
+
+impl ServerMessage {
+    pub fn status(status: SessionStatus) -> Self {
+        ServerMessage::Status { status, detail: None, position: None }
+    }
+
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error { code, message: message.into() }
+    }
+
+    pub fn into_ws(self) -> Message {
+        let text = serde_json::to_string(&self).expect("server messages always serialize");
+        Message::Text(text.into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStatus {
+    // Upstream session is up; audio can flow.
+    Ready,
+    // Upstream connection failed; the client may send `retry_upstream`.
+    UpstreamUnavailable,
+    // Upstream connection dropped mid-session; the backend is reconnecting.
+    Reconnecting,
+    // Scripted upstream (TEST_MODE or FEYNMAN_SCRIPT), no model behind it.
+    TestMode,
+    // The server is at its session limit; sent before the welcome while the
+    // connection waits its turn, each time its place changes.
+    Queued,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    UnsupportedAudioFormat,
+    InvalidMessage,
+    ServerMisconfigured,
+    UpstreamConnectFailed,
+    Upstream,
+    Unauthorized,
+    QuotaExceeded,
+    // The session was resumed on another connection, which ends this one.
+    TakenOver,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Speaker {
+    Learner,
+    Tutor,
+}
+
+// Pick the version to speak with a client that supports up to `client_version`.
+pub fn negotiate_version(client_version: u32) -> Result<u32, ServerMessage> {
+    if client_version < MIN_PROTOCOL_VERSION {
+        return Err(ServerMessage::error(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "protocol version {client_version} is not supported, need {MIN_PROTOCOL_VERSION}..={PROTOCOL_VERSION}"
+            ),
+        ));
+    }
+    Ok(client_version.min(PROTOCOL_VERSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn client_messages_parse_from_wire_format() {
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"hello","version":1}"#).unwrap(),
+            ClientMessage::Hello(Hello {
+                version: 1,
+                input_audio: None,
+                output_encoding: None,
+                session_id: None,
+                mode: SessionMode::Voice,
+                turn_mode: None,
+                record: false,
+                token: None
+            })
+        );
+        assert_eq!(
+            ClientMessage::parse(
+                r#"{"type":"hello","version":1,"input_audio":{"sample_rate":48000,"channels":1,"encoding":"opus"},"output_encoding":"opus"}"#
+            )
+            .unwrap(),
+            ClientMessage::Hello(Hello {
+                version: 1,
+                input_audio: Some(PcmFormat { sample_rate: 48_000, channels: 1, encoding: SampleEncoding::Opus }),
+                output_encoding: Some(SampleEncoding::Opus),
+                session_id: None,
+                mode: SessionMode::Voice,
+                turn_mode: None,
+                record: false,
+                token: None,
+            })
+        );
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"hello","version":1,"mode":"text"}"#).unwrap(),
+            ClientMessage::Hello(Hello {
+                version: 1,
+                input_audio: None,
+                output_encoding: None,
+                session_id: None,
+                mode: SessionMode::Text,
+                turn_mode: None,
+                record: false,
+                token: None
+            })
+        );
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"playback_progress","item_id":"item_1","played_ms":480}"#).unwrap(),
+            ClientMessage::PlaybackProgress { item_id: "item_1".to_string(), played_ms: 480 }
+        );
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"text","text":"Plants eat light."}"#).unwrap(),
+            ClientMessage::Text { text: "Plants eat light.".to_string() }
+        );
+        assert_eq!(ClientMessage::parse(r#"{"type":"commit_audio"}"#).unwrap(), ClientMessage::CommitAudio);
+        assert_eq!(ClientMessage::parse(r#"{"type":"retry_upstream"}"#).unwrap(), ClientMessage::RetryUpstream);
+        assert!(ClientMessage::parse("commit_audio").is_err());
+        assert!(ClientMessage::parse(r#"{"type":"comit_audio"}"#).is_err());
+    }
+
+    #[test]
+    fn server_messages_serialize_to_wire_format() {
+        let to_json = |msg: ServerMessage| serde_json::to_value(msg).unwrap();
+
+        assert_eq!(
+            to_json(ServerMessage::status(SessionStatus::Ready)),
+            json!({ "type": "status", "status": "ready" })
+        );
+        assert_eq!(
+            to_json(ServerMessage::error(ErrorCode::Upstream, "boom")),
+            json!({ "type": "error", "code": "upstream", "message": "boom" })
+        );
+        assert_eq!(
+            to_json(ServerMessage::State { state: ConversationState::WaitingForTopic, topic: None }),
+            json!({ "type": "state", "state": "waiting_for_topic" })
+        );
+        assert_eq!(
+            to_json(ServerMessage::Transcript {
+                speaker: Speaker::Learner,
+                item_id: "item_1".into(),
+                text: "Photosynthesis".into(),
+            }),
+            json!({ "type": "transcript", "speaker": "learner", "item_id": "item_1", "text": "Photosynthesis" })
+        );
+        let at = DateTime::parse_from_rfc3339("2025-01-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(
+            to_json(ServerMessage::Caption {
+                speaker: Speaker::Tutor,
+                item_id: "item_2".into(),
+                text: "Teach".into(),
+                is_final: false,
+                started_at: at,
+                updated_at: at,
+            }),
+            json!({
+                "type": "caption", "speaker": "tutor", "item_id": "item_2", "text": "Teach", "final": false,
+                "started_at": "2025-01-01T12:00:00Z", "updated_at": "2025-01-01T12:00:00Z"
+            })
+        );
+    }
+
+    #[test]
+    fn version_negotiation() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), Ok(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 5), Ok(PROTOCOL_VERSION));
+        assert!(matches!(negotiate_version(0), Err(ServerMessage::Error { code: ErrorCode::UnsupportedVersion, .. })));
+    }
+}
@@ -0,0 +1,79 @@
+use std::future::Future;
+
+use anyhow::Result;
+use axum::body::Bytes;
+
+use crate::direct::DirectUpstream;
+use crate::protocol::{SessionMode, SessionStatus, TurnMode};
+use crate::realtime::{Item, ServerEvent, Tool};
+
+// What one browser session asks of its upstream session.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionOptions {
+    pub mode: SessionMode,
+    pub turn_mode: TurnMode,
+}
+
+// An upstream realtime speech session the relay can drive: OpenAI in production,
+// a scripted replay in tests and TEST_MODE. The methods mirror the Realtime client
+// events the relay needs; `next_event` yields the server events in order.
+pub trait RealtimeProvider: Sized + Send + 'static {
+    // Whatever `connect` needs, built once at startup and shared by every connection.
+    type Config: Send + Sync + 'static;
+
+    // What the browser is told once the upstream session is up.
+    const READY_STATUS: SessionStatus = SessionStatus::Ready;
+
+    // Open a session whose system prompt is `instructions`. A text session neither
+    // takes nor produces audio; turns end as `options.turn_mode` says.
+    fn connect(
+        config: &Self::Config,
+        instructions: &str,
+        options: SessionOptions,
+    ) -> impl Future<Output = Result<Self>> + Send;
+
+    // Set up a session the browser connects to itself, over WebRTC, instead of
+    // through the relay. Its client events are handed to the browser to send, and
+    // the browser posts the server events back. Only OpenAI offers this.
+    fn open_direct(
+        config: &Self::Config,
+        instructions: &str,
+        options: SessionOptions,
+    ) -> impl Future<Output = Result<DirectUpstream>> + Send {
+        let _ = (config, instructions, options);
+        async { Err(anyhow::anyhow!("this upstream does not support direct sessions")) }
+    }
+
+    // Switch turn detection to the patient settings for while the learner explains,
+    // or back. Does nothing without turn detection or if it is already so set.
+    fn set_teaching(&mut self, teaching: bool) -> impl Future<Output = Result<()>> + Send;
+
+    // Append 24 kHz mono PCM16 to the input audio buffer.
+    fn send_audio(&mut self, pcm: Bytes) -> impl Future<Output = Result<()>> + Send;
+
+    // Commit the input audio buffer as a learner turn.
+    fn commit(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    // Ask for a tutor response with per-response instructions, spoken or written
+    // according to the session mode.
+    fn create_response(&mut self, instructions: &str) -> impl Future<Output = Result<()>> + Send;
+
+    // Ask for an out-of-band text response that must call `function`. It sees the
+    // conversation but is not added to it, and produces no audio.
+    fn request_function_call(&mut self, instructions: &str, function: Tool) -> impl Future<Output = Result<()>> + Send;
+
+    // Stop generating response `response_id`.
+    fn cancel_response(&mut self, response_id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    // Cut the audio of assistant item `item_id` off at `audio_end_ms`, dropping
+    // the rest of it and its transcript from the conversation.
+    fn truncate(&mut self, item_id: &str, audio_end_ms: u64) -> impl Future<Output = Result<()>> + Send;
+
+    // Append an item to the conversation, after whatever is there already.
+    fn create_item(&mut self, item: Item) -> impl Future<Output = Result<()>> + Send;
+
+    // Wait for the next server event. A closed connection is an error.
+    fn next_event(&mut self) -> impl Future<Output = Result<ServerEvent>> + Send;
+
+    fn close(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::audio::UPSTREAM_SAMPLE_RATE;
+
+// Whose allowance connections without a user draw on: with authentication off,
+// everyone's.
+pub const ANONYMOUS_USER: &str = "anonymous";
+
+// How much of the Realtime API one user may use per day (UTC). 0 is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaLimits {
+    // Learner audio sent upstream.
+    pub learner_audio_seconds: u64,
+    // Tutor audio received from upstream, whether or not it was played.
+    pub tutor_audio_seconds: u64,
+    // Tokens billed for responses, as reported in response.done.
+    pub tokens: u64,
+}
+
+impl QuotaLimits {
+    // Whether either kind of audio is limited. Only the relay can meter audio.
+    pub fn limits_audio(&self) -> bool {
+        self.learner_audio_seconds > 0 || self.tutor_audio_seconds > 0
+    }
+}
+
+// What a user has used so far today.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DailyUsage {
+    pub learner_audio_seconds: f64,
+    pub tutor_audio_seconds: f64,
+    pub tokens: u64,
+}
+
+impl DailyUsage {
+    // `bytes` of upstream audio (24 kHz mono PCM16) from the learner.
+    pub fn learner_audio(bytes: usize) -> Self {
+        Self { learner_audio_seconds: audio_seconds(bytes), ..Self::default() }
+    }
+
+    pub fn tutor_audio(bytes: usize) -> Self {
+        Self { tutor_audio_seconds: audio_seconds(bytes), ..Self::default() }
+    }
+
+    pub fn tokens(tokens: u64) -> Self {
+        Self { tokens, ..Self::default() }
+    }
+}
+
+fn audio_seconds(bytes: usize) -> f64 {
+    (bytes / 2) as f64 / f64::from(UPSTREAM_SAMPLE_RATE)
+}
+
+// Tallies every user's usage for the current day and checks it against the
+// limits. Usage is kept in memory only, so a restart forgives the day so far.
+pub struct Quotas {
+    limits: QuotaLimits,
+    // The day being tallied, and each user's usage on it.
+    day: Mutex<(NaiveDate, HashMap<String, DailyUsage>)>,
+}
+
+impl Quotas {
+    pub fn new(limits: QuotaLimits) -> Self {
+        Self { limits, day: Mutex::new((Utc::now().date_naive(), HashMap::new())) }
+    }
+
+    pub fn limits(&self) -> QuotaLimits {
+        self.limits
+    }
+
+    pub fn today(&self, user_id: &str) -> DailyUsage {
+        self.add(user_id, DailyUsage::default())
+    }
+
+    // Count `used` against `user_id`'s day and return their usage so far.
+    pub fn add(&self, user_id: &str, used: DailyUsage) -> DailyUsage {
+        self.add_on(Utc::now().date_naive(), user_id, used)
+    }
+
+    fn add_on(&self, today: NaiveDate, user_id: &str, used: DailyUsage) -> DailyUsage {
+        let mut guard = self.day.lock().unwrap();
+        let (day, users) = &mut *guard;
+        // A new day starts everyone afresh, so the old day's tallies can go
+        if *day != today {
+            *day = today;
+            users.clear();
+        }
+        let usage = users.entry(user_id.to_string()).or_default();
+        usage.learner_audio_seconds += used.learner_audio_seconds;
+        usage.tutor_audio_seconds += used.tutor_audio_seconds;
+        usage.tokens += used.tokens;
+        *usage
+    }
+
+    // The allowance `usage` has used up, if any.
+    pub fn exceeded(&self, usage: &DailyUsage) -> Option<&'static str> {
+        let over = |used: f64, limit: u64| limit > 0 && used >= limit as f64;
+        if over(usage.learner_audio_seconds, self.limits.learner_audio_seconds) {
+            Some("learner audio")
+        } else if over(usage.tutor_audio_seconds, self.limits.tutor_audio_seconds) {
+            Some("tutor audio")
+        } else if over(usage.tokens as f64, self.limits.tokens) {
+            Some("tokens")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_user_has_their_own_allowance() {
+        let quotas = Quotas::new(QuotaLimits { learner_audio_seconds: 2, tutor_audio_seconds: 0, tokens: 100 });
+        // One second of upstream audio
+        let usage = quotas.add("alice", DailyUsage::learner_audio(48_000));
+        assert_eq!(usage.learner_audio_seconds, 1.0);
+        assert_eq!(quotas.exceeded(&usage), None);
+
+        let usage = quotas.add("alice", DailyUsage::learner_audio(48_000));
+        assert_eq!(quotas.exceeded(&usage), Some("learner audio"));
+        assert_eq!(quotas.exceeded(&quotas.add("bob", DailyUsage::tokens(99))), None);
+        assert_eq!(quotas.exceeded(&quotas.add("bob", DailyUsage::tokens(1))), Some("tokens"));
+
+        // Unlimited tutor audio
+        let usage = quotas.add("carol", DailyUsage::tutor_audio(48_000_000));
+        assert_eq!(quotas.exceeded(&usage), None);
+        assert_eq!(quotas.today("alice").learner_audio_seconds, 2.0);
+    }
+
+    #[test]
+    fn a_new_day_drops_the_old_ones_tallies() {
+        let quotas = Quotas::new(QuotaLimits { tokens: 100, ..QuotaLimits::default() });
+        let monday = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        let tuesday = monday.succ_opt().unwrap();
+        quotas.add_on(monday, "alice", DailyUsage::tokens(100));
+        quotas.add_on(monday, "bob", DailyUsage::tokens(40));
+
+        let usage = quotas.add_on(tuesday, "alice", DailyUsage::tokens(1));
+        assert_eq!(usage.tokens, 1);
+        assert_eq!(quotas.exceeded(&usage), None);
+        // Bob has not been back, and is not remembered either
+        assert_eq!(quotas.day.lock().unwrap().1.len(), 1);
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+// Typed model of the OpenAI Realtime WebSocket protocol (beta, `realtime=v1`).
+// Only the fields we use or may want soon are modelled; serde ignores the rest.
+
+// Events we send to OpenAI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientEvent {
+    #[serde(rename = "session.update")]
+    SessionUpdate { session: SessionConfig },
+    #[serde(rename = "input_audio_buffer.append")]
+    InputAudioBufferAppend { audio: String },
+    #[serde(rename = "input_audio_buffer.commit")]
+    InputAudioBufferCommit,
+    #[serde(rename = "input_audio_buffer.clear")]
+    InputAudioBufferClear,
+    #[serde(rename = "conversation.item.create")]
+    ConversationItemCreate {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        previous_item_id: Option<String>,
+        item: Item,
+    },
+    #[serde(rename = "conversation.item.truncate")]
+    ConversationItemTruncate { item_id: String, content_index: u32, audio_end_ms: u64 },
+    #[serde(rename = "conversation.item.delete")]
+    ConversationItemDelete { item_id: String },
+    #[serde(rename = "response.create")]
+    ResponseCreate {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        response: Option<ResponseConfig>,
+    },
+    #[serde(rename = "response.cancel")]
+    ResponseCancel {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        response_id: Option<String>,
+    },
+}
+
+// Events OpenAI sends to us. Anything we don't recognise (or can't parse) lands in
+// `Unknown` with the raw JSON so it can still be logged and forwarded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerEvent {
+    #[serde(rename = "error")]
+    Error { error: ApiError },
+    #[serde(rename = "session.created")]
+    SessionCreated { session: serde_json::Value },
+    #[serde(rename = "session.updated")]
+    SessionUpdated { session: serde_json::Value },
+    #[serde(rename = "conversation.created")]
+    ConversationCreated { conversation: serde_json::Value },
+    #[serde(rename = "conversation.item.created")]
+    ConversationItemCreated {
+        #[serde(default)]
+        previous_item_id: Option<String>,
+        item: Item,
+    },
+    #[serde(rename = "conversation.item.input_audio_transcription.delta")]
+    InputAudioTranscriptionDelta {
+        item_id: String,
+        #[serde(default)]
+        content_index: u32,
+        delta: String,
+    },
+    #[serde(rename = "conversation.item.input_audio_transcription.completed")]
+    InputAudioTranscriptionCompleted {
+        item_id: String,
+        #[serde(default)]
+        content_index: u32,
+        transcript: String,
+    },
+    #[serde(rename = "conversation.item.input_audio_transcription.failed")]
+    InputAudioTranscriptionFailed {
+        item_id: String,
+        #[serde(default)]
+        content_index: u32,
+        error: ApiError,
+    },
+    #[serde(rename = "conversation.item.truncated")]
+    ConversationItemTruncated { item_id: String, content_index: u32, audio_end_ms: u64 },
+    #[serde(rename = "conversation.item.deleted")]
+    ConversationItemDeleted { item_id: String },
+    #[serde(rename = "input_audio_buffer.committed")]
+    InputAudioBufferCommitted {
+        #[serde(default)]
+        previous_item_id: Option<String>,
+        item_id: String,
+    },
+    #[serde(rename = "input_audio_buffer.cleared")]
+    InputAudioBufferCleared,
+    #[serde(rename = "input_audio_buffer.speech_started")]
+    InputAudioBufferSpeechStarted { audio_start_ms: u64, item_id: String },
+    #[serde(rename = "input_audio_buffer.speech_stopped")]
+    InputAudioBufferSpeechStopped { audio_end_ms: u64, item_id: String },
+    #[serde(rename = "response.created")]
+    ResponseCreated { response: Response },
+    #[serde(rename = "response.done")]
+    ResponseDone { response: Response },
+    #[serde(rename = "response.output_item.added")]
+    ResponseOutputItemAdded { response_id: String, output_index: u32, item: Item },
+    #[serde(rename = "response.output_item.done")]
+    ResponseOutputItemDone { response_id: String, output_index: u32, item: Item },
+    #[serde(rename = "response.content_part.added")]
+    ResponseContentPartAdded {
+        response_id: String,
+        item_id: String,
+        output_index: u32,
+        content_index: u32,
+        part: ContentPart,
+    },
+    #[serde(rename = "response.content_part.done")]
+    ResponseContentPartDone {
+        response_id: String,
+        item_id: String,
+        output_index: u32,
+        content_index: u32,
+        part: ContentPart,
+    },
+    #[serde(rename = "response.text.delta")]
+    ResponseTextDelta { response_id: String, item_id: String, output_index: u32, content_index: u32, delta: String },
+    #[serde(rename = "response.text.done")]
+    ResponseTextDone { response_id: String, item_id: String, output_index: u32, content_index: u32, text: String },
+    #[serde(rename = "response.audio_transcript.delta")]
+    ResponseAudioTranscriptDelta {
+        response_id: String,
+        item_id: String,
+        output_index: u32,
+        content_index: u32,
+        delta: String,
+    },
+    #[serde(rename = "response.audio_transcript.done")]
+    ResponseAudioTranscriptDone {
+        response_id: String,
+        item_id: String,
+        output_index: u32,
+        content_index: u32,
+        transcript: String,
+    },
+    #[serde(rename = "response.audio.delta")]
+    ResponseAudioDelta { response_id: String, item_id: String, output_index: u32, content_index: u32, delta: String },
+    #[serde(rename = "response.audio.done")]
+    ResponseAudioDone { response_id: String, item_id: String, output_index: u32, content_index: u32 },
+    #[serde(rename = "response.function_call_arguments.delta")]
+    ResponseFunctionCallArgumentsDelta {
+        response_id: String,
+        item_id: String,
+        output_index: u32,
+        call_id: String,
+        delta: String,
+    },
+    #[serde(rename = "response.function_call_arguments.done")]
+    ResponseFunctionCallArgumentsDone {
+        response_id: String,
+        item_id: String,
+        output_index: u32,
+        call_id: String,
+        #[serde(default)]
+        name: Option<String>,
+        arguments: String,
+    },
+    #[serde(rename = "rate_limits.updated")]
+    RateLimitsUpdated { rate_limits: Vec<RateLimit> },
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
+}
+
+impl ServerEvent {
+    // The wire name of the event, for logging.
+    pub fn event_type(&self) -> String {
+        match self {
+            ServerEvent::Unknown(raw) => raw.get("type").and_then(|t| t.as_str()).unwrap_or("<untyped>").to_string(),
+            known => serde_json::to_value(known)
+                .ok()
+                .and_then(|v| v.get("type")?.as_str().map(str::to_string))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modalities: Option<Vec<Modality>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instructions: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_audio_format: Option<AudioFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_audio_format: Option<AudioFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_audio_transcription: Option<InputAudioTranscription>,
+    // Some(None) is sent as null, which turns turn detection off.
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "explicit_null")]
+    pub turn_detection: Option<Option<TurnDetection>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Modality {
+    Text,
+    Audio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioFormat {
+    Pcm16,
+    G711Ulaw,
+    G711Alaw,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputAudioTranscription {
+    pub model: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TurnDetection {
+    ServerVad {
+        threshold: f32,
+        prefix_padding_ms: u32,
+        silence_duration_ms: u32,
+        create_response: bool,
+        interrupt_response: bool,
+    },
+    SemanticVad {
+        // "low", "medium", "high" or "auto": how soon a turn counts as finished.
+        eagerness: String,
+        create_response: bool,
+        interrupt_response: bool,
+    },
+}
+
+// Tell a field that is null apart from one that is missing.
+fn explicit_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResponseConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modalities: Option<Vec<Modality>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instructions: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    // "none" makes an out-of-band response that is not added to the conversation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<String>,
+    // Echoed back on the response, so its events can be told apart from others.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+// The metadata key that tags an out-of-band response with the function it was asked to call.
+pub const CALL_TAG: &str = "relay_call";
+
+// A function the model may call. `parameters` is a JSON schema.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: ToolKind,
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+impl Tool {
+    pub fn function(name: &str, description: &str, parameters: serde_json::Value) -> Self {
+        Self { kind: ToolKind::Function, name: name.to_string(), description: description.to_string(), parameters }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolKind {
+    Function,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    // "auto", "none" or "required".
+    Mode(String),
+    // Force a call to the named function.
+    Function {
+        #[serde(rename = "type")]
+        kind: ToolKind,
+        name: String,
+    },
+}
+
+// A conversation item: a message, a function call or a function call's output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Item {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: ItemKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content: Vec<ContentPart>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub call_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+impl Item {
+    // A plain text message. User text is `input_text`, assistant text is `text`.
+    pub fn message(role: Role, text: &str) -> Self {
+        let part = match role {
+            Role::Assistant => ContentPart::Text { text: text.to_string() },
+            Role::User | Role::System => ContentPart::InputText { text: text.to_string() },
+        };
+        Self {
+            id: None,
+            kind: ItemKind::Message,
+            role: Some(role),
+            content: vec![part],
+            call_id: None,
+            name: None,
+            arguments: None,
+            output: None,
+        }
+    }
+
+    // The result of the function call `call_id`, for the model to read.
+    pub fn function_call_output(call_id: &str, output: String) -> Self {
+        Self {
+            id: None,
+            kind: ItemKind::FunctionCallOutput,
+            role: None,
+            content: Vec::new(),
+            call_id: Some(call_id.to_string()),
+            name: None,
+            arguments: None,
+            output: Some(output),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemKind {
+    Message,
+    FunctionCall,
+    FunctionCallOutput,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    User,
+    Assistant,
+    System,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    InputText {
+        text: String,
+    },
+    InputAudio {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        audio: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transcript: Option<String>,
+    },
+    Text {
+        text: String,
+    },
+    Audio {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        audio: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transcript: Option<String>,
+    },
+}
+
+impl ContentPart {
+    // The readable text of the part, whether it was typed or transcribed.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            ContentPart::InputText { text } | ContentPart::Text { text } => Some(text),
+            ContentPart::InputAudio { transcript, .. } | ContentPart::Audio { transcript, .. } => transcript.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub id: String,
+    pub status: ResponseStatus,
+    #[serde(default)]
+    pub output: Vec<Item>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    #[serde(default)]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+impl Response {
+    // Join the text or audio transcripts of every content part in the response.
+    pub fn transcript(&self) -> Option<String> {
+        let parts: Vec<&str> =
+            self.output.iter().flat_map(|item| &item.content).filter_map(ContentPart::text).collect();
+        if parts.is_empty() { None } else { Some(parts.join(" ")) }
+    }
+
+    // The call the response made to function `name`, if any.
+    pub fn function_call(&self, name: &str) -> Option<&Item> {
+        self.output.iter().find(|item| item.kind == ItemKind::FunctionCall && item.name.as_deref() == Some(name))
+    }
+
+    // The function an out-of-band request asked this response to call, if it was one.
+    pub fn requested_call(&self) -> Option<&str> {
+        self.metadata.as_ref()?.get(CALL_TAG).map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseStatus {
+    InProgress,
+    Completed,
+    Cancelled,
+    Failed,
+    Incomplete,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub total_tokens: u64,
+    #[serde(default)]
+    pub input_tokens: u64,
+    #[serde(default)]
+    pub output_tokens: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiError {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    pub message: String,
+    #[serde(default)]
+    pub param: Option<String>,
+    #[serde(default)]
+    pub event_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub name: String,
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset_seconds: f64,
+}
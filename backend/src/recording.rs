@@ -0,0 +1,184 @@
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+use crate::audio::UPSTREAM_SAMPLE_RATE;
+use crate::protocol::Speaker;
+
+// A gap shorter than this between where a track ends and where the clock says the
+// next audio belongs is jitter, not silence, and is not padded.
+const TIMELINE_SLACK_MS: u64 = 100;
+
+// Records both sides of a voice session for later review, each as its own WAV
+// track under `<data_dir>/recordings/<session_id>/`. Every connection of a session
+// gets its own pair of files, named after when it started. Both tracks share that
+// starting point: silence is written wherever a speaker was quiet, so they line up
+// when played side by side.
+pub struct SessionRecording {
+    started: Instant,
+    learner: WavTrack,
+    tutor: WavTrack,
+}
+
+impl SessionRecording {
+    pub async fn create(data_dir: &Path, session_id: &str) -> std::io::Result<Self> {
+        let dir = data_dir.join("recordings").join(session_id);
+        fs::create_dir_all(&dir).await?;
+        let created = Utc::now();
+        let stamp = created.format("%Y%m%dT%H%M%SZ");
+        Ok(Self {
+            started: Instant::now(),
+            learner: WavTrack::create(dir.join(format!("{stamp}-learner.wav")), created).await?,
+            tutor: WavTrack::create(dir.join(format!("{stamp}-tutor.wav")), created).await?,
+        })
+    }
+
+    // Add 24 kHz mono PCM16 audio from `speaker`, as of now.
+    pub async fn append(&mut self, speaker: Speaker, pcm: &[u8]) -> std::io::Result<()> {
+        let elapsed_ms = self.started.elapsed().as_millis() as u64;
+        let track = match speaker {
+            Speaker::Learner => &mut self.learner,
+            Speaker::Tutor => &mut self.tutor,
+        };
+        track.append_at(elapsed_ms, pcm).await
+    }
+
+    // Fill in the WAV headers and return the paths of the tracks.
+    pub async fn finish(&mut self) -> std::io::Result<[PathBuf; 2]> {
+        self.learner.finish().await?;
+        self.tutor.finish().await?;
+        Ok([self.learner.path.clone(), self.tutor.path.clone()])
+    }
+}
+
+// One mono PCM16 WAV file, written as audio arrives. The header is written with
+// the sizes so far and rewritten by `finish`.
+struct WavTrack {
+    path: PathBuf,
+    created: DateTime<Utc>,
+    file: BufWriter<File>,
+    data_bytes: u32,
+}
+
+impl WavTrack {
+    async fn create(path: PathBuf, created: DateTime<Utc>) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(&path).await?);
+        file.write_all(&wav_header(created, 0)).await?;
+        Ok(Self { path, created, file, data_bytes: 0 })
+    }
+
+    // Append `pcm` where `at_ms` falls on the track's timeline, after silence if
+    // the track has fallen behind.
+    async fn append_at(&mut self, at_ms: u64, pcm: &[u8]) -> std::io::Result<()> {
+        let end_ms = u64::from(self.data_bytes / 2) * 1000 / u64::from(UPSTREAM_SAMPLE_RATE);
+        if at_ms > end_ms + TIMELINE_SLACK_MS {
+            let silence = (at_ms - end_ms) * u64::from(UPSTREAM_SAMPLE_RATE) / 1000 * 2;
+            self.write(&vec![0; silence as usize]).await?;
+        }
+        // PCM16 frames are two bytes; a stray odd byte would shift everything after it
+        self.write(&pcm[..pcm.len() & !1]).await
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let Ok(len) = u32::try_from(bytes.len()) else {
+            return Ok(());
+        };
+        // A WAV file cannot describe more than 4 GiB; stop there
+        let Some(total) = self.data_bytes.checked_add(len).filter(|total| *total <= u32::MAX - 1024) else {
+            return Ok(());
+        };
+        self.file.write_all(bytes).await?;
+        self.data_bytes = total;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+        let file = self.file.get_mut();
+        file.seek(std::io::SeekFrom::Start(0)).await?;
+        file.write_all(&wav_header(self.created, self.data_bytes)).await?;
+        file.seek(std::io::SeekFrom::End(0)).await?;
+        file.flush().await
+    }
+}
+
+// RIFF header for `data_bytes` of 24 kHz mono PCM16, with the creation time in an
+// INFO chunk. Its length does not depend on `data_bytes`, so it can be rewritten
+// in place.
+fn wav_header(created: DateTime<Utc>, data_bytes: u32) -> Vec<u8> {
+    let byte_rate = UPSTREAM_SAMPLE_RATE * 2;
+
+    let mut date = created.format("%Y-%m-%dT%H:%M:%SZ").to_string().into_bytes();
+    date.push(0);
+    let mut info = Vec::new();
+    info.extend_from_slice(b"INFO");
+    info.extend_from_slice(b"ICRD");
+    info.extend_from_slice(&(date.len() as u32).to_le_bytes());
+    info.extend_from_slice(&date);
+    if date.len() % 2 == 1 {
+        info.push(0);
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(b"RIFF");
+    // Filled in below, once the length is known
+    header.extend_from_slice(&[0; 4]);
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, mono
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&UPSTREAM_SAMPLE_RATE.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    // Block align and bits per sample
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"LIST");
+    header.extend_from_slice(&(info.len() as u32).to_le_bytes());
+    header.extend_from_slice(&info);
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_bytes.to_le_bytes());
+
+    let riff_len = (header.len() as u32 - 8) + data_bytes;
+    header[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn tracks_are_wav_files_on_a_shared_timeline() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut recording = SessionRecording::create(dir.path(), "session").await.unwrap();
+        recording.append(Speaker::Learner, &[1; 4800]).await.unwrap();
+        // The tutor's first audio comes half a second in
+        recording.tutor.append_at(500, &[2; 4801]).await.unwrap();
+        let [learner, tutor] = recording.finish().await.unwrap();
+
+        let learner = std::fs::read(learner).unwrap();
+        assert_eq!(&learner[..4], b"RIFF");
+        assert_eq!(&learner[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&learner, 24), UPSTREAM_SAMPLE_RATE);
+        assert_eq!(u32_at(&learner, 4) as usize, learner.len() - 8);
+        let data = learner.windows(4).position(|w| w == b"data").unwrap();
+        assert_eq!(u32_at(&learner, data + 4), 4800);
+
+        // 500 ms of silence, then the audio, less its odd byte
+        let tutor = std::fs::read(tutor).unwrap();
+        let data = tutor.windows(4).position(|w| w == b"data").unwrap();
+        assert_eq!(u32_at(&tutor, data + 4), 24_000 + 4800);
+        assert!(tutor[data + 8..data + 8 + 24_000].iter().all(|b| *b == 0));
+        assert_eq!(tutor[data + 8 + 24_000], 2);
+        assert!(String::from_utf8_lossy(&tutor[..data]).contains("ICRD"));
+    }
+}
@@ -0,0 +1,194 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::fs;
+
+use crate::conversation::{ConversationContext, Gap, gap_label, verdict_label};
+use crate::realtime::Tool;
+
+// The function the model must call with its analysis of the explanation, so the
+// gaps come back as data instead of only as speech.
+pub const RECORD_GAPS: &str = "record_gaps";
+
+pub fn gap_analysis_tool() -> Tool {
+    Tool::function(
+        RECORD_GAPS,
+        "Record every gap found in the learner's explanation.",
+        json!({
+            "type": "object",
+            "properties": {
+                "gaps": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "category": { "type": "string", "enum": ["missing_part", "vague", "misconception"] },
+                            "excerpt": {
+                                "type": "string",
+                                "description": "The learner's own words the gap is about, quoted briefly."
+                            },
+                            "severity": { "type": "string", "enum": ["low", "medium", "high"] },
+                            "question": {
+                                "type": "string",
+                                "description": "One simple probing question that makes the learner fill the gap."
+                            }
+                        },
+                        "required": ["category", "excerpt", "severity", "question"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["gaps"],
+            "additionalProperties": false
+        }),
+    )
+}
+
+pub fn gap_analysis_instructions(ctx: &ConversationContext) -> String {
+    format!(
+        "Analyze the learner's explanation of {} in this conversation. Find missing parts, vague or \
+        superficial descriptions and misconceptions, and call {RECORD_GAPS} with one entry per gap, \
+        most important first. Record at most five gaps.",
+        ctx.topic.as_deref().unwrap_or("their topic")
+    )
+}
+
+#[derive(Deserialize)]
+struct RecordGapsArguments {
+    gaps: Vec<Gap>,
+}
+
+pub fn parse_gaps(arguments: &str) -> Result<Vec<Gap>> {
+    Ok(serde_json::from_str::<RecordGapsArguments>(arguments)?.gaps)
+}
+
+// What the learner takes away from a finished session: their explanation and the
+// gaps found in it. Written to `<data_dir>/reports/<session_id>.{json,md}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GapReport {
+    pub session_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    pub generated_at: DateTime<Utc>,
+    pub topic: Option<String>,
+    pub explanation: String,
+    pub gaps: Vec<Gap>,
+}
+
+impl GapReport {
+    pub fn capture(session_id: &str, user_id: Option<&str>, ctx: &ConversationContext) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            user_id: user_id.map(str::to_string),
+            generated_at: Utc::now(),
+            topic: ctx.topic.clone(),
+            explanation: ctx.explanation.clone(),
+            gaps: ctx.questions.clone(),
+        }
+    }
+
+    // Write both renderings and return the Markdown path.
+    pub async fn write(&self, data_dir: &Path) -> std::io::Result<PathBuf> {
+        let dir = data_dir.join("reports");
+        fs::create_dir_all(&dir).await?;
+        fs::write(dir.join(format!("{}.json", self.session_id)), serde_json::to_vec_pretty(self)?).await?;
+        let path = markdown_path(data_dir, &self.session_id);
+        fs::write(&path, self.render_markdown()).await?;
+        Ok(path)
+    }
+
+    pub fn render_markdown(&self) -> String {
+        let mut out = String::from("# Feynman gap report\n\n");
+        out.push_str(&format!("- **Topic:** {}\n", self.topic.as_deref().unwrap_or("(not set)")));
+        out.push_str(&format!("- **Session:** {}\n", self.session_id));
+        if let Some(user_id) = &self.user_id {
+            out.push_str(&format!("- **Learner:** {user_id}\n"));
+        }
+        out.push_str(&format!("- **Generated:** {}\n\n", self.generated_at.format("%Y-%m-%d %H:%M:%S UTC")));
+
+        out.push_str("## Your explanation\n\n");
+        if self.explanation.is_empty() {
+            out.push_str("(no explanation was recorded)\n\n");
+        } else {
+            out.push_str(&format!("> {}\n\n", self.explanation.trim()));
+        }
+
+        out.push_str("## Gaps\n\n");
+        if self.gaps.is_empty() {
+            out.push_str("No gaps were found.\n");
+        }
+        for (i, gap) in self.gaps.iter().enumerate() {
+            out.push_str(&format!(
+                "{}. **{}** ({}): {}",
+                i + 1,
+                gap_label(gap.category),
+                gap.severity.as_str(),
+                gap.question
+            ));
+            match gap.probe.verdict {
+                Some(verdict) => out.push_str(&format!(" *({})*\n", verdict_label(verdict))),
+                None => out.push('\n'),
+            }
+            if !gap.excerpt.is_empty() {
+                out.push_str(&format!("   > {}\n", gap.excerpt.trim()));
+            }
+        }
+        out
+    }
+}
+
+pub fn markdown_path(data_dir: &Path, session_id: &str) -> PathBuf {
+    data_dir.join("reports").join(format!("{session_id}.md"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::{GapCategory, Probe, Severity};
+
+    #[test]
+    fn parses_the_function_arguments() {
+        let gaps = parse_gaps(
+            r#"{"gaps":[{"category":"misconception","excerpt":"plants eat soil","severity":"high",
+                "question":"Where does the mass of a tree come from?"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            gaps,
+            vec![Gap {
+                category: GapCategory::Misconception,
+                excerpt: "plants eat soil".to_string(),
+                severity: Severity::High,
+                question: "Where does the mass of a tree come from?".to_string(),
+                probe: Probe::default(),
+            }]
+        );
+        assert!(parse_gaps(r#"{"gaps":[{"category":"typo"}]}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_markdown_and_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ctx = ConversationContext::new();
+        ctx.topic = Some("Photosynthesis".to_string());
+        ctx.explanation = "Plants eat soil.".to_string();
+        ctx.record_gaps(parse_gaps(
+            r#"{"gaps":[{"category":"missing_part","excerpt":"","severity":"low","question":"What about light?"},
+                {"category":"misconception","excerpt":"eat soil","severity":"high","question":"Where does the mass come from?"}]}"#,
+        ).unwrap());
+
+        let path = GapReport::capture("abc", Some("alice"), &ctx).write(dir.path()).await.unwrap();
+        let md = std::fs::read_to_string(path).unwrap();
+        assert!(md.contains("**Learner:** alice"));
+        assert!(md.contains("**Topic:** Photosynthesis"));
+        assert!(md.contains("1. **Misconception** (high): Where does the mass come from?\n   > eat soil\n"));
+        assert!(md.contains("2. **Missing part** (low): What about light?\n"));
+
+        let json: GapReport =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("reports/abc.json")).unwrap()).unwrap();
+        assert_eq!(json.gaps, ctx.questions);
+    }
+}
@@ -1,282 +1,857 @@
 use axum::{
-    extract::ws::{WebSocketUpgrade, WebSocket, Message},
-    response::{Response},
+    Json, Router,
+    extract::{
+        Path as UrlPath, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{any, delete, get, post},
 };
-use std::env;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::field::{self, Empty};
+use tracing::{Span, debug, error, info, trace, warn};
+use uuid::Uuid;
 
-use crate::openai::OASocket; 
-use tokio_tungstenite::tungstenite;
-use base64;
+use crate::admission::{Admission, Ticket};
+use crate::audio::{AudioOutput, AudioPipeline, PcmFormat, SampleEncoding, UPSTREAM_SAMPLE_RATE};
+use crate::auth::{Authenticator, Identity};
+use crate::captions::Captions;
+use crate::conversation::{ConversationContext, ConversationState, PendingCall, TurnEvent};
+use crate::direct::{DirectSession, DirectSessions};
+use crate::metrics;
+use crate::openai::ClientSecret;
+use crate::opus;
+use crate::playback::{AudioDelta, Playback};
+use crate::probing;
+use crate::protocol::{
+    self, ClientMessage, ErrorCode, Hello, ServerMessage, SessionMode, SessionStatus, Speaker, TurnMode,
+};
+use crate::provider::{RealtimeProvider, SessionOptions};
+use crate::quota::{ANONYMOUS_USER, DailyUsage, Quotas};
+use crate::realtime::{
+    ClientEvent, Item, ItemKind, Response as RealtimeResponse, ResponseStatus, Role, ServerEvent, Tool,
+};
+use crate::report;
+use crate::session::{Session, Sessions};
+use crate::tools;
 
-const FEYNMAN_PROMPT: &str = include_str!("../feynman_prompt.txt"); 
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RECONNECT_ATTEMPTS: u32 = 6;
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(15);
+// How long the tutor gets to tell a learner who ran out of quota that the session
+// is over, before it is closed anyway.
+const QUOTA_NOTICE_TIMEOUT: Duration = Duration::from_secs(20);
+const QUOTA_NOTICE: &str = "The learner has used up today's allowance for tutoring sessions. \
+    In one or two sentences, tell them that this session has to end here, that they can pick it up \
+    again tomorrow, and say goodbye. Do not ask them anything.";
+// A direct session nothing has been posted to for this long is over.
+const DIRECT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const RESUME_DIRECTIVE: &str = "The learner just reconnected after a dropped connection. \
+    Briefly welcome them back and continue from the current step; do not start over.";
 
-#[derive(Debug, Clone, PartialEq)]
-enum ConversationState {
-    Initial,
-    WaitingForTopic,
-    ReadyToTeach,
-    Teaching,
-    Analyzing,
-    Questioning,
-    Complete,
+// Shared by every connection.
+pub struct AppState<P: RealtimeProvider> {
+    pub sessions: Arc<Sessions>,
+    pub data_dir: PathBuf,
+    // The tutor's system prompt.
+    pub prompt: Arc<str>,
+    pub provider: Arc<P::Config>,
+    // For sessions whose hello does not pick a turn mode.
+    pub turn_mode: TurnMode,
+    // Quiet after a learner turn that ends their explanation; None waits for them
+    // to say they are done.
+    pub teaching_timeout: Option<Duration>,
+    // Whether sessions may be recorded, for learners who agree to it.
+    pub record_audio: bool,
+    // Without keys, connections and reports are open to anyone.
+    pub auth: Arc<Authenticator>,
+    // Every user's usage today, against the daily limits.
+    pub quotas: Arc<Quotas>,
+    // Who may hold an upstream connection right now, and who is waiting.
+    pub admission: Arc<Admission>,
+    // Sessions whose browser is connected to OpenAI itself.
+    pub direct: Arc<DirectSessions>,
+    // Where the frontend is served from.
+    pub allowed_origins: Vec<HeaderValue>,
 }
 
-struct ConversationContext {
-    state: ConversationState,
-    topic: Option<String>,
-    questions: Vec<String>,
-    current_question_index: usize,
-    audio_buffer_has_data: bool,
-} 
+impl<P: RealtimeProvider> Clone for AppState<P> {
+    fn clone(&self) -> Self {
+        Self {
+            sessions: self.sessions.clone(),
+            data_dir: self.data_dir.clone(),
+            prompt: self.prompt.clone(),
+            provider: self.provider.clone(),
+            turn_mode: self.turn_mode,
+            teaching_timeout: self.teaching_timeout,
+            record_audio: self.record_audio,
+            auth: self.auth.clone(),
+            quotas: self.quotas.clone(),
+            admission: self.admission.clone(),
+            direct: self.direct.clone(),
+            allowed_origins: self.allowed_origins.clone(),
+        }
+    }
+}
 
+pub fn router<P: RealtimeProvider>(app: AppState<P>) -> Router {
+    Router::new()
+        .route("/ws", any(handle_ws::<P>))
+        .route("/sessions/{id}/report", get(download_report::<P>))
+        .route("/metrics", get(export_metrics))
+        .route("/realtime/sessions", post(create_direct_session::<P>))
+        .route("/realtime/sessions/{id}/events", post(post_direct_events::<P>))
+        .route("/realtime/sessions/{id}", delete(end_direct_session::<P>))
+        // The frontend is served from elsewhere, and only its pages may call in.
+        // Tokens travel in headers, not cookies.
+        .layer(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::list(app.allowed_origins.clone()))
+                .allow_methods([Method::GET, Method::POST, Method::DELETE])
+                .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE]),
+        )
+        .with_state(app)
+}
 
-pub async fn handle_ws(ws: WebSocketUpgrade) -> Response {
-    dotenvy::dotenv().ok();
-    ws.on_upgrade(socket_task)
+// A token in the Authorization header is checked before upgrading. Browsers cannot
+// set one on a WebSocket, so they present theirs in the hello instead.
+async fn handle_ws<P: RealtimeProvider>(
+    State(app): State<AppState<P>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let identity = match bearer_token(&headers) {
+        Some(token) if app.auth.enabled() => match app.auth.verify(token) {
+            Ok(identity) => Some(identity),
+            Err(e) => {
+                warn!("Refused WebSocket: {}", e);
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+        },
+        _ => None,
+    };
+    // Whoever is known before the hello can be turned away before upgrading
+    if identity.is_some() || !app.auth.enabled() {
+        let user_id = identity.as_ref().map_or(ANONYMOUS_USER, |identity| identity.user_id.as_str());
+        if let Some(limit) = app.quotas.exceeded(&app.quotas.today(user_id)) {
+            warn!("Refused WebSocket: {} has used up today's {}", user_id, limit);
+            return StatusCode::TOO_MANY_REQUESTS.into_response();
+        }
+    }
+    ws.on_upgrade(move |socket| socket_task(socket, app, identity))
 }
-async fn socket_task(mut browser_ws: WebSocket){
-    let key = match env::var("OPENAI_API_KEY") {
-        Ok(k) => k,
-        Err(_) => {
-            eprintln!("OPENAI_API_KEY environment variable not set");
-            let _ = browser_ws.send(Message::Text("Error: OPENAI_API_KEY not configured".into())).await;
-            let _ = browser_ws.send(Message::Close(None)).await;
-            return;
+
+// For Prometheus to scrape. Nothing in it identifies a learner.
+async fn export_metrics() -> Response {
+    let content_type = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+    ([(header::CONTENT_TYPE, content_type)], metrics::render()).into_response()
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ").map(str::trim)
+}
+
+// The Markdown gap report of a finished session, as a download. With
+// authentication on, only the session's own user may fetch it, and the token has
+// to come in the Authorization header: one in the URL would end up in browser
+// history and access logs.
+async fn download_report<P: RealtimeProvider>(
+    State(app): State<AppState<P>>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    if Uuid::parse_str(&id).is_err() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let identity = match request_identity(&app, &headers) {
+        Ok(identity) => identity,
+        Err(status) => return status.into_response(),
+    };
+    if let Some(identity) = identity {
+        // Someone else's session looks the same as no session at all
+        let owner = app.sessions.load(&id).await.ok().flatten().and_then(|stored| stored.user_id);
+        if owner.as_deref() != Some(identity.user_id.as_str()) {
+            return StatusCode::NOT_FOUND.into_response();
         }
+    }
+    match tokio::fs::read_to_string(report::markdown_path(&app.data_dir, &id)).await {
+        Ok(markdown) => (
+            [
+                (header::CONTENT_TYPE, "text/markdown; charset=utf-8".to_string()),
+                (header::CONTENT_DISPOSITION, format!("attachment; filename=\"feynman-report-{id}.md\"")),
+            ],
+            markdown,
+        )
+            .into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// Who an HTTP request comes from, going by its bearer token. With authentication
+// off everyone is anonymous.
+fn request_identity<P: RealtimeProvider>(
+    app: &AppState<P>,
+    headers: &HeaderMap,
+) -> Result<Option<Identity>, StatusCode> {
+    if !app.auth.enabled() {
+        return Ok(None);
+    }
+    let token = bearer_token(headers).ok_or(StatusCode::UNAUTHORIZED)?;
+    match app.auth.verify(token) {
+        Ok(identity) => Ok(Some(identity)),
+        Err(e) => {
+            warn!("Refused request: {}", e);
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct DirectSessionRequest {
+    session_id: Option<String>,
+    turn_mode: Option<TurnMode>,
+}
+
+#[derive(serde::Serialize)]
+struct DirectSessionStarted {
+    session_id: String,
+    resumed: bool,
+    turn_mode: TurnMode,
+    client_secret: ClientSecret,
+    // Where to open the WebRTC connection with the secret.
+    url: String,
+    // Client events for the browser to send once its data channel is open.
+    events: Vec<ClientEvent>,
+    messages: Vec<ServerMessage>,
+}
+
+#[derive(serde::Deserialize)]
+struct DirectEvents {
+    // Server events from the data channel, in the order they arrived.
+    #[serde(default)]
+    events: Vec<ServerEvent>,
+    #[serde(default)]
+    done_teaching: bool,
+}
+
+#[derive(serde::Serialize)]
+struct DirectReply {
+    events: Vec<ClientEvent>,
+    messages: Vec<ServerMessage>,
+    // The session is over and the browser should hang up.
+    ended: bool,
+}
+
+// Start (or resume) a voice session whose browser connects to OpenAI over WebRTC,
+// so its audio does not pass through here. OpenAI is asked for a short-lived
+// client secret with the tutor's prompt, voice and tools already set up, and the
+// browser sends the returned events down its data channel. It then posts what
+// OpenAI says to `/realtime/sessions/{id}/events` so the state machine can run,
+// and sends whatever comes back. With every place under the session cap taken the
+// request is refused rather than queued; the relay is there to fall back on. The
+// same goes for a server that limits audio, which it could not meter here.
+#[tracing::instrument(name = "session", skip_all, fields(session_id = Empty, user = Empty, state = Empty))]
+async fn create_direct_session<P: RealtimeProvider>(
+    State(app): State<AppState<P>>,
+    headers: HeaderMap,
+    Json(request): Json<DirectSessionRequest>,
+) -> Response {
+    let identity = match request_identity(&app, &headers) {
+        Ok(identity) => identity,
+        Err(status) => return status.into_response(),
     };
-    
-    // Check for test mode
-    let test_mode = env::var("TEST_MODE").unwrap_or_default() == "true";
-    
-    if test_mode {
-        eprintln!("Running in TEST_MODE - simulating OpenAI connection");
-        let _ = browser_ws.send(Message::Text("TEST_MODE: Simulated OpenAI connection".into())).await;
-        socket_task_test_mode(browser_ws).await;
-        return;
+    if app.quotas.limits().limits_audio() {
+        info!("Refused direct session: audio allowances are only metered on the relay");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let user_id = identity.as_ref().map(|identity| identity.user_id.as_str());
+    let usage = app.quotas.today(user_id.unwrap_or(ANONYMOUS_USER));
+    if let Some(limit) = app.quotas.exceeded(&usage) {
+        warn!("Refused direct session: {} has used up today's {}", user_id.unwrap_or(ANONYMOUS_USER), limit);
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    }
+    let mut ticket = app.admission.join();
+    if ticket.position() != 0 {
+        warn!("Refused direct session: the session limit is reached");
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    let options = SessionOptions { mode: SessionMode::Voice, turn_mode: request.turn_mode.unwrap_or(app.turn_mode) };
+    let mut upstream = match P::open_direct(&app.provider, &app.prompt, options).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            warn!("Failed to set up a direct session: {}", e);
+            return (StatusCode::BAD_GATEWAY, e.to_string()).into_response();
+        }
+    };
+    // Never used: the audio goes straight to OpenAI
+    let pipeline = match AudioPipeline::new(PcmFormat::UPSTREAM, UPSTREAM_SAMPLE_RATE) {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            error!("Unusable audio format: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    // A browser starting over takes the session over from its old connection, but
+    // only once nothing is left to refuse the request
+    let claim = app.sessions.claim(request.session_id.as_deref(), user_id).await;
+    let session = Session::open(claim, user_id, &app.data_dir, pipeline, AudioOutput::Pcm16, options, false).await;
+    record_session(&session).await;
+    info!(
+        "Direct session {} ({}) for {}",
+        session.id,
+        if session.resumed { "resumed" } else { "new" },
+        user_id.unwrap_or("an anonymous user")
+    );
+
+    let mut messages = vec![ServerMessage::Usage { used: usage, limits: app.quotas.limits() }];
+    if session.resumed {
+        let ctx = session.context.lock().await;
+        messages.push(ServerMessage::State { state: ctx.state.clone(), topic: ctx.topic.clone() });
+    }
+    if let Err(e) = restore_upstream(&mut upstream, &app.prompt, &session, session.resumed).await {
+        warn!("Failed to start the conversation: {}", e);
+    }
+    let started = DirectSessionStarted {
+        session_id: session.id.clone(),
+        resumed: session.resumed,
+        turn_mode: options.turn_mode,
+        client_secret: upstream.secret.clone(),
+        url: upstream.url.clone(),
+        events: upstream.take_events(),
+        messages,
+    };
+    // Nothing else notices another connection taking the session over
+    let (taken_over, id) = (session.watch_taken_over(), session.id.clone());
+    let direct = app.direct.insert(DirectSession::new(session, upstream, ticket));
+    tokio::spawn(async move {
+        if taken_over.await && app.direct.remove_if(&id, &direct) {
+            info!("Direct session {} was taken over by another connection", id);
+            direct.lock().await.session.finish().await;
+        }
+    });
+    Json(started).into_response()
+}
+
+// Run the state machine on what OpenAI said in a direct session, and hand back
+// what to send it and what to tell the learner. Audio is not metered here, since
+// it never passes through; tokens are.
+#[tracing::instrument(name = "session", skip_all, fields(session_id = %id, user = Empty, state = Empty))]
+async fn post_direct_events<P: RealtimeProvider>(
+    State(app): State<AppState<P>>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+    Json(posted): Json<DirectEvents>,
+) -> Response {
+    let direct = match direct_session(&app, &id, &headers).await {
+        Ok(direct) => direct,
+        Err(status) => return status.into_response(),
+    };
+    let mut direct = direct.lock().await;
+    direct.last_seen = Instant::now();
+    let DirectSession { session, upstream, .. } = &mut *direct;
+    record_session(session).await;
+    let user_id = session.user_id.clone().unwrap_or_else(|| ANONYMOUS_USER.to_string());
+
+    let mut messages = Vec::new();
+    for event in &posted.events {
+        match event {
+            ServerEvent::ResponseDone { response } => {
+                let tokens = response.usage.as_ref().map_or(0, |usage| usage.total_tokens);
+                app.quotas.add(&user_id, DailyUsage::tokens(tokens));
+            }
+            ServerEvent::Error { error } => metrics::upstream_error(error.code.as_deref()),
+            _ => {}
+        }
+        let message = browser_message(event);
+        if let Some(ServerMessage::Transcript { speaker, item_id, text }) = &message {
+            session.record_transcript(*speaker, item_id, text).await;
+        }
+        messages.extend(message);
+        if let Err(e) = drive_conversation(event, &app.prompt, session, upstream, &mut messages).await {
+            warn!("Failed to advance conversation: {}", e);
+        }
+    }
+    if posted.done_teaching
+        && let Err(e) = done_teaching(false, &app.prompt, session, upstream, &mut messages).await
+    {
+        warn!("Failed to end the explanation: {}", e);
+    }
+
+    let usage = app.quotas.today(&user_id);
+    messages.push(ServerMessage::Usage { used: usage, limits: app.quotas.limits() });
+    let exceeded = app.quotas.exceeded(&usage);
+    if let Some(limit) = exceeded {
+        warn!("{} has used up today's {}, ending direct session {}", user_id, limit, id);
+        messages.push(ServerMessage::error(ErrorCode::QuotaExceeded, format!("today's {limit} allowance is used up")));
+        app.direct.remove(&id);
+        session.finish().await;
+    }
+    let events = if exceeded.is_some() { Vec::new() } else { upstream.take_events() };
+    Json(DirectReply { events, messages, ended: exceeded.is_some() }).into_response()
+}
+
+// The browser hung up on a direct session.
+#[tracing::instrument(name = "session", skip_all, fields(session_id = %id))]
+async fn end_direct_session<P: RealtimeProvider>(
+    State(app): State<AppState<P>>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    let direct = match direct_session(&app, &id, &headers).await {
+        Ok(direct) => direct,
+        Err(status) => return status.into_response(),
+    };
+    app.direct.remove(&id);
+    direct.lock().await.session.finish().await;
+    info!("Direct session {} ended", id);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// Direct session `id`, if the request may use it. Someone else's session looks
+// the same as no session at all.
+async fn direct_session<P: RealtimeProvider>(
+    app: &AppState<P>,
+    id: &str,
+    headers: &HeaderMap,
+) -> Result<Arc<tokio::sync::Mutex<DirectSession>>, StatusCode> {
+    let identity = request_identity(app, headers)?;
+    let direct = app.direct.get(id).ok_or(StatusCode::NOT_FOUND)?;
+    let owner = direct.lock().await.session.user_id.clone();
+    if owner.as_deref() != identity.as_ref().map(|identity| identity.user_id.as_str()) {
+        return Err(StatusCode::NOT_FOUND);
     }
-    
-    // Validate API key format
-    if !key.starts_with("sk-") {
-        eprintln!("Invalid OpenAI API key format");
-        let _ = browser_ws.send(Message::Text("Error: Invalid API key format".into())).await;
+    Ok(direct)
+}
+
+// End the direct sessions whose browsers went away without saying so, letting
+// queued sessions in.
+pub async fn finish_idle_direct_sessions<P: RealtimeProvider>(app: &AppState<P>) {
+    for direct in app.direct.take_idle(DIRECT_IDLE_TIMEOUT) {
+        let mut direct = direct.lock().await;
+        info!("Direct session {} went quiet, ending it", direct.session.id);
+        direct.session.finish().await;
+    }
+}
+
+// Everything logged for the connection goes in its span, filled in as the session
+// is resolved and moves along.
+#[tracing::instrument(name = "session", skip_all, fields(session_id = Empty, user = Empty, state = Empty))]
+async fn socket_task<P: RealtimeProvider>(mut browser_ws: WebSocket, app: AppState<P>, identity: Option<Identity>) {
+    let Some(hello) = handshake(&mut browser_ws).await else {
+        let _ = browser_ws.send(Message::Close(None)).await;
+        return;
+    };
+    let identity = match identity {
+        Some(identity) => Some(identity),
+        None if app.auth.enabled() => {
+            let verified = match hello.token.as_deref() {
+                Some(token) => app.auth.verify(token),
+                None => Err(anyhow::anyhow!("a token is required")),
+            };
+            match verified {
+                Ok(identity) => Some(identity),
+                Err(e) => {
+                    warn!("Refused session: {}", e);
+                    let _ =
+                        browser_ws.send(ServerMessage::error(ErrorCode::Unauthorized, e.to_string()).into_ws()).await;
+                    let _ = browser_ws.send(Message::Close(None)).await;
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    let user_id = identity.as_ref().map(|identity| identity.user_id.as_str());
+    let usage = app.quotas.today(user_id.unwrap_or(ANONYMOUS_USER));
+    if let Some(limit) = app.quotas.exceeded(&usage) {
+        warn!("Refused session: {} has used up today's {}", user_id.unwrap_or(ANONYMOUS_USER), limit);
+        let _ = browser_ws.send(ServerMessage::Usage { used: usage, limits: app.quotas.limits() }.into_ws()).await;
+        let error = ServerMessage::error(ErrorCode::QuotaExceeded, format!("today's {limit} allowance is used up"));
+        let _ = browser_ws.send(error.into_ws()).await;
         let _ = browser_ws.send(Message::Close(None)).await;
         return;
     }
-    
-    let oa = match OASocket::connect(&key, FEYNMAN_PROMPT).await{
+    // Taken before queueing, so an old connection to the session gives up its place
+    let claim = app.sessions.claim(hello.session_id.as_deref(), user_id).await;
+    // Held until the connection ends
+    let Some(_ticket) = admit(&app.admission, &mut browser_ws).await else {
+        return;
+    };
+    let _active = metrics::session_started();
+
+    let input_audio = hello.input_audio.unwrap_or(PcmFormat::UPSTREAM);
+    let pipeline = match AudioPipeline::new(input_audio, UPSTREAM_SAMPLE_RATE) {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            // The handshake already validated the format
+            error!("Unusable input audio format: {}", e);
+            return;
+        }
+    };
+
+    let options = SessionOptions {
+        mode: hello.mode,
+        // Typed turns always end when the learner sends them
+        turn_mode: match hello.mode {
+            SessionMode::Voice => hello.turn_mode.unwrap_or(app.turn_mode),
+            SessionMode::Text => TurnMode::PushToTalk,
+        },
+    };
+    // Recording needs both the server to allow it and the learner to agree to it
+    let record = app.record_audio && hello.record;
+    let output = AudioOutput::new(hello.output_encoding.unwrap_or(SampleEncoding::Pcm16));
+    let output_audio = output.format();
+    let mut session = Session::open(claim, user_id, &app.data_dir, pipeline, output, options, record).await;
+    record_session(&session).await;
+    info!(
+        "Session {} ({}) for {}",
+        session.id,
+        if session.resumed { "resumed" } else { "new" },
+        user_id.unwrap_or("an anonymous user")
+    );
+    let welcome = ServerMessage::Welcome {
+        version: hello.version,
+        session_id: session.id.clone(),
+        resumed: session.resumed,
+        input_audio,
+        output_audio,
+        mode: options.mode,
+        turn_mode: options.turn_mode,
+        recording: session.recording.is_some(),
+    };
+    if browser_ws.send(welcome.into_ws()).await.is_err() {
+        info!("Browser WebSocket ended before welcome");
+        return;
+    }
+
+    let oa = match connect_upstream(&app, session.options).await {
         Ok(s) => {
-            eprintln!("Successfully connected to OpenAI");
-            if let Err(e) = browser_ws.send(Message::Text("Connected to OpenAI".into())).await {
-                eprintln!("Failed to send connection status: {}", e);
+            info!("Successfully connected to OpenAI");
+            if let Err(e) = browser_ws.send(ServerMessage::status(P::READY_STATUS).into_ws()).await {
+                warn!("Failed to send connection status: {}", e);
                 return;
             }
             s
-        },
+        }
         Err(e) => {
-            eprintln!("Failed to connect to OpenAI: {}", e);
+            warn!("Failed to connect to OpenAI: {}", e);
             let error_msg = format!("OpenAI connection failed: {}", e);
-            let _ = browser_ws.send(Message::Text(error_msg.into())).await;
-            
+            let _ = browser_ws.send(ServerMessage::error(ErrorCode::UpstreamConnectFailed, error_msg).into_ws()).await;
+            let _ = browser_ws.send(ServerMessage::status(SessionStatus::UpstreamUnavailable).into_ws()).await;
+
             // Keep the WebSocket open and wait for browser commands instead of closing
-            eprintln!("Keeping browser WebSocket open despite OpenAI failure");
+            info!("Keeping browser WebSocket open despite OpenAI failure");
             loop {
-                match browser_ws.recv().await {
+                let msg = tokio::select! {
+                    msg = browser_ws.recv() => msg,
+                    _ = session.taken_over() => {
+                        hand_over(&mut browser_ws).await;
+                        break;
+                    }
+                };
+                match msg {
                     Some(Ok(Message::Close(_))) => {
-                        eprintln!("Browser WebSocket closed after OpenAI failure");
+                        info!("Browser WebSocket closed after OpenAI failure");
                         break;
                     }
                     Some(Ok(Message::Text(text))) => {
-                        if text == "retry_openai" {
-                            eprintln!("Retrying OpenAI connection...");
-                            match OASocket::connect(&key, FEYNMAN_PROMPT).await {
+                        if ClientMessage::parse(&text).ok() == Some(ClientMessage::RetryUpstream) {
+                            info!("Retrying OpenAI connection...");
+                            match connect_upstream(&app, session.options).await {
                                 Ok(new_oa) => {
-                                    eprintln!("OpenAI reconnection successful");
-                                    let _ = browser_ws.send(Message::Text("OpenAI reconnected".into())).await;
+                                    info!("OpenAI reconnection successful");
+                                    let _ = browser_ws.send(ServerMessage::status(P::READY_STATUS).into_ws()).await;
                                     // Continue with the new OpenAI connection
-                                    socket_task_with_openai(browser_ws, new_oa).await;
+                                    socket_task_with_provider(browser_ws, &app, new_oa, session).await;
                                     return;
                                 }
                                 Err(e) => {
-                                    eprintln!("OpenAI reconnection failed: {}", e);
-                                    let _ = browser_ws.send(Message::Text(format!("Reconnection failed: {}", e).into())).await;
+                                    warn!("OpenAI reconnection failed: {}", e);
+                                    let error_msg = format!("Reconnection failed: {}", e);
+                                    let _ = browser_ws
+                                        .send(
+                                            ServerMessage::error(ErrorCode::UpstreamConnectFailed, error_msg).into_ws(),
+                                        )
+                                        .await;
                                 }
                             }
                         } else {
-                            let _ = browser_ws.send(Message::Text("OpenAI unavailable - send 'retry_openai' to retry".into())).await;
+                            let _ = browser_ws
+                                .send(
+                                    ServerMessage::Status {
+                                        status: SessionStatus::UpstreamUnavailable,
+                                        detail: Some("send retry_upstream to retry".to_string()),
+                                        position: None,
+                                    }
+                                    .into_ws(),
+                                )
+                                .await;
                         }
                     }
                     Some(Ok(_)) => {
                         // Ignore other message types
                     }
                     Some(Err(e)) => {
-                        eprintln!("WebSocket error while OpenAI disconnected: {}", e);
+                        warn!("WebSocket error while OpenAI disconnected: {}", e);
                         break;
                     }
                     None => {
-                        eprintln!("Browser WebSocket connection ended");
+                        info!("Browser WebSocket connection ended");
                         break;
                     }
                 }
             }
+            session.finish().await;
             return;
         }
     };
-    
-    socket_task_with_openai(browser_ws, oa).await;
+
+    socket_task_with_provider(browser_ws, &app, oa, session).await;
 }
 
-async fn socket_task_test_mode(mut browser_ws: WebSocket) {
-    eprintln!("Test mode: simulating OpenAI responses");
-    
+// Fill in the session's span once it is known.
+async fn record_session(session: &Session) {
+    let span = Span::current();
+    span.record("session_id", session.id.as_str());
+    span.record("user", session.user_id.as_deref().unwrap_or(ANONYMOUS_USER));
+    let ctx = session.context.lock().await;
+    span.record("state", field::debug(&ctx.state));
+}
+
+// Open an upstream session, timing it for the metrics.
+async fn connect_upstream<P: RealtimeProvider>(app: &AppState<P>, options: SessionOptions) -> anyhow::Result<P> {
+    let started = Instant::now();
+    let connected = P::connect(&app.provider, &app.prompt, options).await;
+    match &connected {
+        Ok(_) => metrics::upstream_connected(started.elapsed()),
+        Err(_) => metrics::upstream_connect_failed(),
+    }
+    connected
+}
+
+// Wait for a turn to connect upstream, keeping the browser posted on its place in
+// the queue. None if the browser leaves first.
+async fn admit(admission: &Arc<Admission>, browser_ws: &mut WebSocket) -> Option<Ticket> {
+    let mut ticket = admission.join();
     loop {
-        match browser_ws.recv().await {
-            Some(Ok(Message::Binary(audio_data))) => {
-                eprintln!("Test mode: received {} bytes of audio data", audio_data.len());
-                
-                // Simulate OpenAI text response
-                let mock_response = r#"{"type":"response.audio.delta","delta":"dGVzdCBhdWRpbyBkYXRh"}"#;
-                if let Err(e) = browser_ws.send(Message::Text(mock_response.into())).await {
-                    eprintln!("Failed to send mock response: {}", e);
-                    break;
-                }
-                
-                // Simulate some audio response (just echo back some test data)
-                let mock_audio: Vec<u8> = (0..1024).map(|i| (i % 256) as u8).collect();
-                if let Err(e) = browser_ws.send(Message::Binary(mock_audio.into())).await {
-                    eprintln!("Failed to send mock audio: {}", e);
-                    break;
-                }
-            }
-            Some(Ok(Message::Text(text))) => {
-                eprintln!("Test mode: received text message: {}", text);
-                
-                if text == "commit_audio" {
-                    // Simulate OpenAI text response
-                    let mock_response = r#"{"type":"response.audio.delta","delta":"dGVzdCBhdWRpbyBkYXRh"}"#;
-                    if let Err(e) = browser_ws.send(Message::Text(mock_response.into())).await {
-                        eprintln!("Failed to send mock response: {}", e);
-                        break;
-                    }
-                    
-                    // Simulate audio response with actual PCM16 data (1 second of 440Hz tone)
-                    let sample_rate = 16000;
-                    let duration = 1.0; // 1 second
-                    let frequency = 440.0; // A4 note
-                    let samples = (sample_rate as f32 * duration) as usize;
-                    let mut audio_data = Vec::with_capacity(samples * 2); // 2 bytes per sample for PCM16
-                    
-                    for i in 0..samples {
-                        let t = i as f32 / sample_rate as f32;
-                        let amplitude = 0.3; // Reduce volume
-                        let sample = (amplitude * (2.0 * std::f32::consts::PI * frequency * t).sin() * 32767.0) as i16;
-                        audio_data.extend_from_slice(&sample.to_le_bytes());
-                    }
-                    
-                    eprintln!("Test mode: sending {} bytes of mock audio", audio_data.len());
-                    if let Err(e) = browser_ws.send(Message::Binary(audio_data.into())).await {
-                        eprintln!("Failed to send mock audio: {}", e);
-                        break;
-                    }
-                } else {
-                    let response = format!("Test mode echo: {}", text);
-                    if let Err(e) = browser_ws.send(Message::Text(response.into())).await {
-                        eprintln!("Failed to send text echo: {}", e);
-                        break;
+        let position = ticket.position();
+        if position == 0 {
+            return Some(ticket);
+        }
+        info!("Session limit reached, waiting at position {}", position);
+        let queued = ServerMessage::Status { status: SessionStatus::Queued, detail: None, position: Some(position) };
+        browser_ws.send(queued.into_ws()).await.ok()?;
+        loop {
+            tokio::select! {
+                _ = ticket.changed() => break,
+                msg = browser_ws.recv() => match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        info!("Browser left the queue");
+                        return None;
                     }
-                }
-            }
-            Some(Ok(Message::Close(_))) => {
-                eprintln!("Browser WebSocket closed in test mode");
-                break;
-            }
-            Some(Ok(_)) => {
-                // Ignore other message types
-            }
-            Some(Err(e)) => {
-                eprintln!("WebSocket error in test mode: {}", e);
-                break;
+                    // Nothing can be done with anything it sends until it is admitted
+                    Some(Ok(_)) => {}
+                },
             }
-            None => {
-                eprintln!("Browser WebSocket connection ended in test mode");
-                break;
+        }
+    }
+}
+
+// Where messages for the browser go: down its WebSocket in a relayed session, or
+// into the reply to its latest post in a direct one.
+trait BrowserSink: Send {
+    fn send_message(&mut self, message: ServerMessage) -> impl Future<Output = Result<(), axum::Error>> + Send;
+}
+
+impl BrowserSink for WebSocket {
+    fn send_message(&mut self, message: ServerMessage) -> impl Future<Output = Result<(), axum::Error>> + Send {
+        self.send(message.into_ws())
+    }
+}
+
+impl BrowserSink for Vec<ServerMessage> {
+    async fn send_message(&mut self, message: ServerMessage) -> Result<(), axum::Error> {
+        self.push(message);
+        Ok(())
+    }
+}
+
+// Wait for the browser's hello and settle the protocol version. The welcome is
+// sent once the session is resolved.
+async fn handshake(browser_ws: &mut WebSocket) -> Option<Hello> {
+    let text = match tokio::time::timeout(HELLO_TIMEOUT, browser_ws.recv()).await {
+        Ok(Some(Ok(Message::Text(text)))) => text,
+        Ok(Some(Ok(_))) => {
+            let _ = browser_ws.send(ServerMessage::error(ErrorCode::InvalidMessage, "expected hello").into_ws()).await;
+            return None;
+        }
+        Ok(_) => {
+            info!("Browser WebSocket ended before hello");
+            return None;
+        }
+        Err(_) => {
+            info!("Browser did not send hello in time");
+            return None;
+        }
+    };
+
+    let reply = match ClientMessage::parse(&text) {
+        Ok(ClientMessage::Hello(hello)) => protocol::negotiate_version(hello.version).and_then(|version| {
+            let mut input_audio = hello.input_audio.unwrap_or(PcmFormat::UPSTREAM);
+            // Without Opus here the client is told to send PCM16 at the same rate
+            if input_audio.encoding == SampleEncoding::Opus && !opus::AVAILABLE {
+                input_audio.encoding = SampleEncoding::Pcm16;
             }
+            input_audio.validate().map_err(|e| ServerMessage::error(ErrorCode::UnsupportedAudioFormat, e))?;
+            Ok(Hello { version, input_audio: Some(input_audio), ..hello })
+        }),
+        Ok(_) => Err(ServerMessage::error(ErrorCode::InvalidMessage, "expected hello")),
+        Err(e) => Err(ServerMessage::error(ErrorCode::InvalidMessage, e.to_string())),
+    };
+    match reply {
+        Ok(hello) => {
+            debug!("Browser speaks protocol version {} with input audio {:?}", hello.version, hello.input_audio);
+            Some(hello)
+        }
+        Err(error) => {
+            let _ = browser_ws.send(error.into_ws()).await;
+            None
         }
     }
 }
 
-async fn socket_task_with_openai(mut browser_ws: WebSocket, mut oa: OASocket) {
-    let context = Arc::new(Mutex::new(ConversationContext {
-        state: ConversationState::Initial,
-        topic: None,
-        questions: Vec::new(),
-        current_question_index: 0,
-        audio_buffer_has_data: false,
-    }));
+async fn socket_task_with_provider<P: RealtimeProvider>(
+    mut browser_ws: WebSocket,
+    app: &AppState<P>,
+    mut oa: P,
+    mut session: Session,
+) {
+    let context = session.context.clone();
+    let mut captions = Captions::default();
+    let mut playback = Playback::default();
+    let push_to_talk = session.options.turn_mode == TurnMode::PushToTalk;
+    // With turn detection on, whether upstream ended the learner's latest turn
+    // itself, so the browser's commit would only add a second, empty one.
+    let mut ended_by_server = false;
+    // A learner turn is on its way to being committed upstream.
+    let mut turn_committing = false;
+    // When the learner's explanation ends if they stay quiet.
+    let mut teaching_deadline: Option<Instant> = None;
+    let mut meter = Meter::new(app.quotas.clone(), session.user_id.as_deref());
+    // Upstream is generating a response, so another cannot be requested yet.
+    let mut response_active = false;
+    // When the learner's latest turn ended, until the tutor starts answering it.
+    let mut turn_ended: Option<Instant> = None;
+    let _ = browser_ws.send(meter.message().into_ws()).await;
 
-    // Send initial greeting
-    let _ = oa.create_response().await;
+    // Send initial greeting, or pick up where a resumed session left off
+    if session.resumed {
+        let ctx = context.lock().await;
+        let _ = browser_ws
+            .send(ServerMessage::State { state: ctx.state.clone(), topic: ctx.topic.clone() }.into_ws())
+            .await;
+    }
+    if let Err(e) = restore_upstream(&mut oa, &app.prompt, &session, session.resumed).await {
+        warn!("Failed to start the conversation: {}", e);
+    }
 
     loop {
+        let mut upstream_lost = false;
         tokio::select! {
             msg = browser_ws.recv() => {
                 match msg {
+                    Some(Ok(Message::Binary(_))) if session.options.mode == SessionMode::Text => {
+                        debug!("Ignoring audio in a text session");
+                    }
+                    Some(Ok(Message::Binary(_))) if meter.exhausted() => {}
                     Some(Ok(Message::Binary(buf))) => {
-                        eprintln!("Received audio frame from browser: {} bytes", buf.len());
-                        
+                        trace!("Received audio frame from browser: {} bytes", buf.len());
+
                         // Track that we have audio data
-                        {
+                        let starts_turn = {
                             let mut ctx = context.lock().await;
-                            ctx.audio_buffer_has_data = true;
+                            !std::mem::replace(&mut ctx.audio_buffer_has_data, true)
+                        };
+                        // Nothing upstream notices speech in push-to-talk, so the
+                        // learner pressing talk is what interrupts the tutor
+                        if push_to_talk && starts_turn {
+                            teaching_deadline = None;
+                            if let Err(e) = barge_in(&mut playback, &mut session.output, &mut oa, &mut browser_ws).await {
+                                warn!("Failed to interrupt the tutor: {}", e);
+                                upstream_lost = true;
+                            }
                         }
-                        
-                        if let Err(e) = oa.send_audio(buf).await {
-                            eprintln!("Failed to send audio: {}", e);
-                            let _ = browser_ws.send(Message::Close(None)).await;
-                            oa.close().await.ok();
-                            break;
+
+                        // Convert to the 24 kHz mono PCM16 the session expects
+                        let pcm = session.pipeline.process(&buf);
+                        // A frame that yields nothing yet still reaches the checks after the select
+                        if !pcm.is_empty() {
+                            metrics::learner_audio(pcm.len());
+                            session.record_audio(Speaker::Learner, &pcm).await;
+                            let used = DailyUsage::learner_audio(pcm.len());
+                            if let Err(e) = oa.send_audio(pcm.into()).await {
+                                warn!("Failed to send audio: {}", e);
+                                upstream_lost = true;
+                            }
+                            meter.charge(used);
                         }
                     }
                     Some(Ok(Message::Text(text))) => {
-                        eprintln!("Received text from browser: {}", text);
-                        
-                        // Handle special browser commands
-                        if text == "commit_audio" {
-                            eprintln!("Processing commit_audio command");
-                            let should_commit = {
-                                let ctx = context.lock().await;
-                                let has_data = ctx.audio_buffer_has_data;
-                                eprintln!("Audio buffer has data: {}", has_data);
-                                has_data
-                            };
-                            
-                            if should_commit {
-                                eprintln!("Committing audio buffer and creating response");
-                                if let Err(e) = oa.commit_audio_buffer().await {
-                                    eprintln!("Failed to commit audio buffer: {}", e);
-                                } else {
-                                    eprintln!("Audio buffer committed successfully");
-                                }
-                                if let Err(e) = oa.create_response().await {
-                                    eprintln!("Failed to create response: {}", e);
-                                } else {
-                                    eprintln!("Response creation requested successfully");
-                                }
-                                
-                                // Reset audio buffer tracking
-                                {
-                                    let mut ctx = context.lock().await;
-                                    ctx.audio_buffer_has_data = false;
-                                    eprintln!("Reset audio buffer tracking");
-                                }
-                            } else {
-                                eprintln!("No audio data to commit, skipping");
+                        debug!("Received text from browser: {}", text);
+
+                        // Handle browser commands
+                        let command = match ClientMessage::parse(&text) {
+                            Ok(command) => command,
+                            Err(e) => {
+                                let _ = browser_ws.send(ServerMessage::error(ErrorCode::InvalidMessage, e.to_string()).into_ws()).await;
+                                continue;
+                            }
+                        };
+                        // The session is winding down; nothing the learner says goes upstream
+                        if meter.exhausted() && !matches!(command, ClientMessage::PlaybackProgress { .. }) {
+                            continue;
+                        }
+                        if let ClientMessage::Text { text } = &command {
+                            let text = text.trim();
+                            if text.is_empty() {
+                                let _ = browser_ws.send(ServerMessage::error(ErrorCode::InvalidMessage, "text is empty").into_ws()).await;
+                                continue;
+                            }
+                            if let Err(e) = send_learner_text(text, &app.prompt, &mut session, &mut oa, &mut browser_ws).await {
+                                warn!("Failed to send learner text: {}", e);
+                                upstream_lost = true;
+                            }
+                            teaching_deadline = explanation_deadline(app, &session).await;
+                        }
+                        if let ClientMessage::PlaybackProgress { item_id, played_ms } = &command {
+                            playback.on_progress(item_id, *played_ms);
+                        }
+                        if command == ClientMessage::CommitAudio && commit_learner_audio(&mut oa, &session, ended_by_server).await {
+                            turn_committing = true;
+                            turn_ended = Some(Instant::now());
+                        }
+                        if command == ClientMessage::DoneTeaching {
+                            // Whatever the learner is still saying is the end of the explanation
+                            if commit_learner_audio(&mut oa, &session, ended_by_server).await {
+                                turn_committing = true;
+                                turn_ended = Some(Instant::now());
+                            }
+                            teaching_deadline = None;
+                            if let Err(e) = done_teaching(turn_committing, &app.prompt, &session, &mut oa, &mut browser_ws).await {
+                                warn!("Failed to end the explanation: {}", e);
+                                upstream_lost = true;
                             }
                         }
                     }
                     Some(Ok(Message::Close(_))) => {
-                        eprintln!("Browser WebSocket closed");
+                        info!("Browser WebSocket closed");
                         oa.close().await.ok();
                         break;
                     }
@@ -284,88 +859,1568 @@ async fn socket_task_with_openai(mut browser_ws: WebSocket, mut oa: OASocket) {
                         // Ignore other message types
                     }
                     Some(Err(e)) => {
-                        eprintln!("WebSocket receive error: {}", e);
+                        warn!("WebSocket receive error: {}", e);
                         break;
                     }
                     None => {
-                        eprintln!("Browser WebSocket connection ended");
+                        info!("Browser WebSocket connection ended");
                         oa.close().await.ok();
                         break;
                     }
                 }
             },
-            oa_msg = oa.next() => {
-                match oa_msg {
-                    Ok(tungstenite::Message::Binary(audio)) => {
-                        if browser_ws.send(Message::Binary(audio)).await.is_err() {
-                            eprintln!("Failed to send audio to browser");
-                            let _ = browser_ws.send(Message::Close(None)).await;
-                            oa.close().await.ok();
-                            break;
-                        }
+            oa_event = oa.next_event() => {
+                let event = match oa_event {
+                    Ok(event) => Some(event),
+                    Err(e) => {
+                        warn!("OpenAI WebSocket errored: {:?}", e);
+                        upstream_lost = true;
+                        None
                     }
-                    Ok(tungstenite::Message::Text(text)) => {
-                        eprintln!("Received text from OpenAI: {}", text);
-                        
-                        // Parse the JSON response to extract audio data
-                        if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(&text) {
-                            if let Some(event_type) = json_value.get("type").and_then(|t| t.as_str()) {
-                                match event_type {
-                                    "response.audio.delta" => {
-                                        if let Some(delta) = json_value.get("delta").and_then(|d| d.as_str()) {
-                                            // Decode base64 audio data
-                                            if let Ok(audio_bytes) = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, delta) {
-                                                eprintln!("Sending {} bytes of audio to browser", audio_bytes.len());
-                                                if browser_ws.send(Message::Binary(audio_bytes.into())).await.is_err() {
-                                                    eprintln!("Failed to send audio to browser");
-                                                    let _ = browser_ws.send(Message::Close(None)).await;
-                                                    oa.close().await.ok();
-                                                    break;
-                                                }
-                                            } else {
-                                                eprintln!("Failed to decode base64 audio data");
-                                            }
+                };
+                if let Some(event) = event {
+                    match &event {
+                        ServerEvent::ResponseAudioDelta { response_id, item_id, delta, .. } => {
+                            // Decode base64 audio data
+                            if let Ok(audio_bytes) = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, delta) {
+                                // Billed whether or not the learner gets to hear it
+                                meter.charge(DailyUsage::tutor_audio(audio_bytes.len()));
+                                let mut sent = true;
+                                match playback.on_audio(response_id, item_id, audio_bytes.len()) {
+                                    AudioDelta::Drop => debug!("Dropping audio of interrupted item {}", item_id),
+                                    AudioDelta::Forward { starts_item } => {
+                                        if let Some(ended) = turn_ended.take() {
+                                            metrics::response_latency(ended.elapsed());
                                         }
-                                    }
-                                    _ => {
-                                        // For non-audio events, send the text to browser for debugging
-                                        if browser_ws.send(axum::extract::ws::Message::Text(text.to_string().into())).await.is_err() {
-                                            eprintln!("Failed to send text to browser");
-                                            let _ = browser_ws.send(Message::Close(None)).await;
-                                            oa.close().await.ok();
-                                            break;
+                                        metrics::tutor_audio(audio_bytes.len());
+                                        trace!("Sending {} bytes of audio to browser", audio_bytes.len());
+                                        if starts_item {
+                                            // The last of the previous item goes out before the next starts
+                                            let rest = session.output.end_turn();
+                                            let start = ServerMessage::TutorAudio { item_id: item_id.clone() };
+                                            sent = send_audio(&mut browser_ws, rest).await
+                                                && browser_ws.send(start.into_ws()).await.is_ok();
                                         }
+                                        session.record_audio(Speaker::Tutor, &audio_bytes).await;
+                                        let frames = session.output.frames(audio_bytes);
+                                        sent = sent && send_audio(&mut browser_ws, frames).await;
                                     }
                                 }
+                                if !sent {
+                                    warn!("Failed to send audio to browser");
+                                    let _ = browser_ws.send(Message::Close(None)).await;
+                                    oa.close().await.ok();
+                                    break;
+                                }
+                            } else {
+                                warn!("Failed to decode base64 audio data");
+                            }
+                        }
+                        _ => {
+                            debug!("Received {} from OpenAI", event.event_type());
+                            match &event {
+                                ServerEvent::ResponseCreated { .. } => response_active = true,
+                                ServerEvent::Error { error } => metrics::upstream_error(error.code.as_deref()),
+                                ServerEvent::ResponseDone { response } => {
+                                    playback.on_response_done(&response.id);
+                                    response_active = false;
+                                    let tokens = response.usage.as_ref().map_or(0, |usage| usage.total_tokens);
+                                    meter.charge(DailyUsage::tokens(tokens));
+                                    let _ = browser_ws.send(meter.message().into_ws()).await;
+                                }
+                                ServerEvent::ResponseAudioDone { .. } => {
+                                    let rest = session.output.end_turn();
+                                    let _ = send_audio(&mut browser_ws, rest).await;
+                                }
+                                ServerEvent::InputAudioBufferSpeechStopped { .. } => {
+                                    turn_ended = Some(Instant::now());
+                                    ended_by_server = true;
+                                    turn_committing = true;
+                                }
+                                ServerEvent::InputAudioBufferSpeechStarted { .. } => {
+                                    ended_by_server = false;
+                                    teaching_deadline = None;
+                                    if let Err(e) = barge_in(&mut playback, &mut session.output, &mut oa, &mut browser_ws).await {
+                                        warn!("Failed to interrupt the tutor: {}", e);
+                                        upstream_lost = true;
+                                    }
+                                }
+                                _ => {}
+                            }
+                            // Relay the events the browser cares about in its own protocol
+                            let message = browser_message(&event);
+                            if let Some(ServerMessage::Transcript { speaker, item_id, text }) = &message {
+                                session.record_transcript(*speaker, item_id, text).await;
+                            }
+                            let mut sent = true;
+                            for message in captions.update(&event).into_iter().chain(message) {
+                                sent = browser_ws.send(message.into_ws()).await.is_ok();
+                                if !sent {
+                                    break;
+                                }
+                            }
+                            if !sent {
+                                warn!("Failed to send text to browser");
+                                let _ = browser_ws.send(Message::Close(None)).await;
+                                oa.close().await.ok();
+                                break;
                             }
                         }
-                        
-                        // Handle conversation state based on OpenAI response
-                        let _should_update_state = {
-                            let _ctx = context.lock().await;
-                            // Parse the response and update conversation state if needed
-                            // This is where we would implement the Feynman tutor logic
-                            false
-                        };
                     }
-                     Ok(tungstenite::Message::Close(_)) => {
-                        eprintln!("OpenAI WebSocket closed");
-                        let _ = browser_ws.send(Message::Close(None)).await;
-                        oa.close().await.ok();
-                        break;
+
+                    // Once the quota is used up, the only response still coming is the notice
+                    if meter.exhausted() {
+                        if meter.notice == Some(QuotaNotice::Requested) && matches!(event, ServerEvent::ResponseDone { .. }) {
+                            end_out_of_quota(&mut browser_ws, &mut oa).await;
+                            break;
+                        }
+                    } else {
+                        // Handle conversation state based on OpenAI response
+                        if let Err(e) = drive_conversation(&event, &app.prompt, &session, &mut oa, &mut browser_ws).await {
+                            warn!("Failed to advance conversation: {}", e);
+                            upstream_lost = true;
+                        }
+                        match &event {
+                            ServerEvent::InputAudioBufferCommitted { .. } => {
+                                turn_committing = false;
+                                teaching_deadline = explanation_deadline(app, &session).await;
+                            }
+                            // The turn had nothing in it; an explanation that was waiting on it ends now
+                            ServerEvent::Error { error } if error.code.as_deref() == Some("input_audio_buffer_commit_empty") => {
+                                turn_committing = false;
+                                let waiting = session.context.lock().await.done_teaching;
+                                if waiting && let Err(e) = done_teaching(false, &app.prompt, &session, &mut oa, &mut browser_ws).await {
+                                    warn!("Failed to end the explanation: {}", e);
+                                    upstream_lost = true;
+                                }
+                            }
+                            _ => {}
+                        }
                     }
-                    Err(e) => {
-                        eprintln!("OpenAI WebSocket errored: {:?}", e);
-                        let _ = browser_ws.send(Message::Close(None)).await;
-                        oa.close().await.ok();
-                        break;
+                }
+            }
+            _ = tokio::time::sleep_until(teaching_deadline.unwrap_or_else(Instant::now)), if teaching_deadline.is_some() => {
+                teaching_deadline = None;
+                info!("Learner went quiet, ending the explanation");
+                if let Err(e) = done_teaching(turn_committing, &app.prompt, &session, &mut oa, &mut browser_ws).await {
+                    warn!("Failed to end the explanation: {}", e);
+                    upstream_lost = true;
+                }
+            }
+            _ = tokio::time::sleep_until(meter.deadline.unwrap_or_else(Instant::now)), if meter.deadline.is_some() => {
+                warn!("The quota notice did not finish in time");
+                end_out_of_quota(&mut browser_ws, &mut oa).await;
+                break;
+            }
+            _ = session.taken_over() => {
+                hand_over(&mut browser_ws).await;
+                oa.close().await.ok();
+                break;
+            }
+        }
+
+        // The tutor tells a learner who ran out of quota once it is free to speak
+        if meter.notice == Some(QuotaNotice::Owed) && !response_active && !upstream_lost {
+            teaching_deadline = None;
+            match oa.create_response(QUOTA_NOTICE).await {
+                Ok(()) => meter.notice = Some(QuotaNotice::Requested),
+                Err(e) => {
+                    warn!("Failed to ask for the quota notice: {}", e);
+                    upstream_lost = true;
+                }
+            }
+        }
+
+        if upstream_lost {
+            oa.close().await.ok();
+            match reconnect_upstream(app, &mut browser_ws, &mut session).await {
+                Some(new_oa) => {
+                    oa = new_oa;
+                    // Nothing of the old session's responses is playing any more
+                    playback = Playback::default();
+                    ended_by_server = false;
+                    turn_committing = false;
+                    response_active = false;
+                    // A notice the old session never finished is owed again
+                    if meter.notice.is_some() {
+                        meter.notice = Some(QuotaNotice::Owed);
                     }
-                    Ok(_) => {
-                        // Ignore other message types
+                }
+                None => {
+                    let _ = browser_ws.send(Message::Close(None)).await;
+                    break;
+                }
+            }
+        }
+    }
+
+    session.finish().await;
+}
+
+// This session's draw on its user's daily quota. Once the quota is used up the
+// learner is no longer heard; the tutor says the session is over as soon as it is
+// not in the middle of a response, and the session ends after that.
+struct Meter {
+    quotas: Arc<Quotas>,
+    user_id: String,
+    notice: Option<QuotaNotice>,
+    // When the session closes even if the notice never finishes.
+    deadline: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+enum QuotaNotice {
+    // Waiting for upstream to finish the response it is giving.
+    Owed,
+    // Asked for; the session ends when it is done.
+    Requested,
+}
+
+impl Meter {
+    fn new(quotas: Arc<Quotas>, user_id: Option<&str>) -> Self {
+        let user_id = user_id.unwrap_or(ANONYMOUS_USER).to_string();
+        Self { quotas, user_id, notice: None, deadline: None }
+    }
+
+    fn exhausted(&self) -> bool {
+        self.notice.is_some()
+    }
+
+    // Count `used` against the user's day.
+    fn charge(&mut self, used: DailyUsage) {
+        let usage = self.quotas.add(&self.user_id, used);
+        if self.notice.is_none()
+            && let Some(limit) = self.quotas.exceeded(&usage)
+        {
+            warn!("{} has used up today's {}, ending the session", self.user_id, limit);
+            self.notice = Some(QuotaNotice::Owed);
+            self.deadline = Some(Instant::now() + QUOTA_NOTICE_TIMEOUT);
+        }
+    }
+
+    // The user's usage so far, for the browser.
+    fn message(&self) -> ServerMessage {
+        ServerMessage::Usage { used: self.quotas.today(&self.user_id), limits: self.quotas.limits() }
+    }
+}
+
+async fn end_out_of_quota<P: RealtimeProvider>(browser_ws: &mut WebSocket, oa: &mut P) {
+    let error = ServerMessage::error(ErrorCode::QuotaExceeded, "today's allowance is used up");
+    let _ = browser_ws.send(error.into_ws()).await;
+    let _ = browser_ws.send(Message::Close(None)).await;
+    oa.close().await.ok();
+}
+
+// Commit the audio the learner sent since their last turn, unless turn detection
+// already ended that turn. Returns whether a commit went upstream.
+async fn commit_learner_audio<P: RealtimeProvider>(oa: &mut P, session: &Session, ended_by_server: bool) -> bool {
+    debug!("Processing commit_audio command");
+    let has_data = session.context.lock().await.audio_buffer_has_data;
+    debug!("Audio buffer has data: {}", has_data);
+    if !has_data {
+        debug!("No audio data to commit, skipping");
+        return false;
+    }
+    if ended_by_server {
+        debug!("Turn already ended by turn detection, skipping");
+        return false;
+    }
+
+    // The response is requested once OpenAI confirms the commit
+    debug!("Committing audio buffer");
+    let committed = match oa.commit().await {
+        Ok(()) => {
+            debug!("Audio buffer committed successfully");
+            true
+        }
+        Err(e) => {
+            warn!("Failed to commit audio buffer: {}", e);
+            false
+        }
+    };
+
+    // Reset audio buffer tracking
+    session.context.lock().await.audio_buffer_has_data = false;
+    debug!("Reset audio buffer tracking");
+    committed
+}
+
+// The learner has finished explaining. A turn of theirs still being committed is
+// the last part of the explanation, so the analysis waits for it; otherwise it
+// starts now.
+async fn done_teaching<P: RealtimeProvider>(
+    turn_committing: bool,
+    prompt: &str,
+    session: &Session,
+    oa: &mut P,
+    browser: &mut impl BrowserSink,
+) -> anyhow::Result<()> {
+    if turn_committing {
+        let mut ctx = session.context.lock().await;
+        ctx.done_teaching = ctx.state == ConversationState::Teaching;
+        return Ok(());
+    }
+    drive_turn(TurnEvent::DoneTeaching, prompt, session, oa, browser).await
+}
+
+// When the learner's explanation should end if they say nothing more, or None if
+// they are not explaining or only they decide.
+async fn explanation_deadline<P: RealtimeProvider>(app: &AppState<P>, session: &Session) -> Option<Instant> {
+    let timeout = app.teaching_timeout?;
+    let teaching = session.context.lock().await.state == ConversationState::Teaching;
+    teaching.then(|| Instant::now() + timeout)
+}
+
+// The learner started talking over the tutor: stop the response, cut its audio
+// off where the browser got to, and have the browser drop the rest.
+async fn barge_in<P: RealtimeProvider>(
+    playback: &mut Playback,
+    output: &mut AudioOutput,
+    oa: &mut P,
+    browser_ws: &mut WebSocket,
+) -> anyhow::Result<()> {
+    let Some(cut) = playback.interrupt() else {
+        return Ok(());
+    };
+    info!("Learner interrupted {} at {} ms", cut.item_id, cut.audio_end_ms);
+    output.clear();
+    if let Some(response_id) = &cut.response_id {
+        oa.cancel_response(response_id).await?;
+    }
+    oa.truncate(&cut.item_id, cut.audio_end_ms).await?;
+    let _ = browser_ws.send(ServerMessage::FlushAudio { item_id: cut.item_id }.into_ws()).await;
+    Ok(())
+}
+
+// Tutor audio, one binary message per frame.
+async fn send_audio(browser_ws: &mut WebSocket, frames: Vec<Vec<u8>>) -> bool {
+    for frame in frames {
+        if browser_ws.send(Message::Binary(frame.into())).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+// Another connection resumed the session, so this one lets go of it.
+async fn hand_over(browser_ws: &mut WebSocket) {
+    info!("Session taken over by another connection");
+    let error = ServerMessage::error(ErrorCode::TakenOver, "the session was resumed on another connection");
+    let _ = browser_ws.send(error.into_ws()).await;
+    let _ = browser_ws.send(Message::Close(None)).await;
+}
+
+// Bring a fresh upstream session up to date: replay what was said so far and, if
+// the tutor owes a reply (or has not greeted yet), ask for one. `rejoining` adds
+// a note that the learner is coming back to an interrupted session.
+async fn restore_upstream<P: RealtimeProvider>(
+    oa: &mut P,
+    prompt: &str,
+    session: &Session,
+    rejoining: bool,
+) -> anyhow::Result<()> {
+    let history = session.history_items();
+    if !history.is_empty() {
+        debug!("Replaying {} conversation items to OpenAI", history.len());
+    }
+    for item in history {
+        oa.create_item(item).await?;
+    }
+
+    let teaching = session.context.lock().await.state == ConversationState::Teaching;
+    oa.set_teaching(teaching).await?;
+    // A learner who is explaining is not owed a reply until they are done
+    let tutor_owes_reply = !teaching && session.history().last().is_none_or(|entry| entry.speaker == Speaker::Learner);
+    if rejoining || tutor_owes_reply {
+        let instructions = {
+            let ctx = session.context.lock().await;
+            if rejoining {
+                format!("{}\n\n{}\n\n{RESUME_DIRECTIVE}", tutor_instructions(prompt, &ctx), ctx.summary())
+            } else {
+                tutor_instructions(prompt, &ctx)
+            }
+        };
+        oa.create_response(&instructions).await?;
+    }
+    Ok(())
+}
+
+// Reconnect to OpenAI with exponential backoff after the upstream connection was
+// lost, keeping the browser informed. Returns None if we gave up or the browser left.
+async fn reconnect_upstream<P: RealtimeProvider>(
+    app: &AppState<P>,
+    browser_ws: &mut WebSocket,
+    session: &mut Session,
+) -> Option<P> {
+    // Whatever was buffered or requested upstream is gone with the old connection
+    {
+        let mut ctx = session.context.lock().await;
+        ctx.audio_buffer_has_data = false;
+        ctx.pending_call = None;
+    }
+    session.output.clear();
+
+    for attempt in 1..=MAX_RECONNECT_ATTEMPTS {
+        let status = ServerMessage::Status {
+            status: SessionStatus::Reconnecting,
+            detail: Some(format!("attempt {attempt} of {MAX_RECONNECT_ATTEMPTS}")),
+            position: None,
+        };
+        browser_ws.send(status.into_ws()).await.ok()?;
+
+        match connect_upstream(app, session.options).await {
+            Ok(mut oa) => match restore_upstream(&mut oa, &app.prompt, session, false).await {
+                Ok(()) => {
+                    info!("Reconnected to OpenAI on attempt {}", attempt);
+                    browser_ws.send(ServerMessage::status(P::READY_STATUS).into_ws()).await.ok()?;
+                    return Some(oa);
+                }
+                Err(e) => warn!("Failed to replay conversation on attempt {}: {}", attempt, e),
+            },
+            Err(e) => warn!("Reconnect attempt {} failed: {}", attempt, e),
+        }
+
+        // Back off, but notice if the browser leaves meanwhile. Audio and commands
+        // sent while there is no upstream are dropped.
+        let backoff = tokio::time::sleep(reconnect_backoff(attempt));
+        tokio::pin!(backoff);
+        loop {
+            tokio::select! {
+                _ = &mut backoff => break,
+                msg = browser_ws.recv() => match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        info!("Browser left while reconnecting to OpenAI");
+                        return None;
                     }
+                    Some(Ok(_)) => {}
+                },
+                _ = session.taken_over() => {
+                    hand_over(browser_ws).await;
+                    return None;
                 }
             }
+        }
+    }
+
+    warn!("Giving up on OpenAI after {} attempts", MAX_RECONNECT_ATTEMPTS);
+    let _ = browser_ws
+        .send(ServerMessage::error(ErrorCode::UpstreamConnectFailed, "Lost the connection to OpenAI").into_ws())
+        .await;
+    None
+}
+
+fn reconnect_backoff(attempt: u32) -> Duration {
+    (RECONNECT_BASE_DELAY * 2u32.saturating_pow(attempt - 1)).min(RECONNECT_MAX_DELAY)
+}
+
+fn tutor_instructions(prompt: &str, ctx: &ConversationContext) -> String {
+    format!("{prompt}\n\nCurrent step: {}", ctx.directive())
+}
+
+// What the relay asks of the upstream session after a turn.
+enum Request {
+    Response(String),
+    FunctionCall(String, Tool),
+}
+
+// Feed turn-related OpenAI events into the state machine, tell the browser about
+// transitions and answer each learner turn with instructions for the current step.
+// The end of the explanation first gets a structured gap analysis, and each answer
+// to a probing question gets graded; the tutor speaks once the result is in. Calls
+// the tutor makes to the session tools are handled as they arrive.
+async fn drive_conversation<P: RealtimeProvider>(
+    event: &ServerEvent,
+    prompt: &str,
+    session: &Session,
+    oa: &mut P,
+    browser: &mut impl BrowserSink,
+) -> anyhow::Result<()> {
+    if let ServerEvent::ResponseFunctionCallArgumentsDone { call_id, name: Some(name), arguments, .. } = event
+        && tools::is_session_tool(name)
+    {
+        return handle_tool_call(call_id, name, arguments, session, oa, browser).await;
+    }
+    if let ServerEvent::ResponseDone { response } = event {
+        // Only the response tagged with the call settles it; others may finish first
+        let (pending, waiting) = {
+            let mut ctx = session.context.lock().await;
+            let pending = ctx.pending_call.take_if(|call| response.requested_call() == Some(call.function()));
+            (pending, ctx.pending_call.is_some())
+        };
+        match pending {
+            Some(PendingCall::GapAnalysis) => return finish_gap_analysis(response, prompt, session, oa).await,
+            Some(PendingCall::AnswerGrade) => return finish_grading(response, prompt, session, oa, browser).await,
+            None => {}
+        }
+        // An out-of-band response is never the tutor speaking, and nothing the tutor
+        // says moves the conversation on while a call is still out
+        if response.requested_call().is_some() || waiting {
+            return Ok(());
+        }
+        // A response that only called tools has not said anything yet; let it go on
+        if response.status == ResponseStatus::Completed
+            && response.transcript().is_none()
+            && calls_session_tool(response)
+        {
+            let instructions = tutor_instructions(prompt, &*session.context.lock().await);
+            return oa.create_response(&instructions).await;
+        }
+    }
+
+    let tutor_transcript;
+    let turn = match event {
+        ServerEvent::InputAudioBufferCommitted { item_id, .. } => TurnEvent::LearnerTurn { item_id },
+        ServerEvent::InputAudioTranscriptionCompleted { item_id, transcript, .. } => {
+            TurnEvent::LearnerTranscript { item_id, transcript }
+        }
+        // Treated as an answer that did not come through, so the question is asked again
+        ServerEvent::InputAudioTranscriptionFailed { item_id, .. } => {
+            TurnEvent::LearnerTranscript { item_id, transcript: "" }
+        }
+        ServerEvent::ResponseDone { response } if response.status == ResponseStatus::Completed => {
+            tutor_transcript = response.transcript();
+            TurnEvent::TutorFinished { transcript: tutor_transcript.as_deref() }
+        }
+        _ => return Ok(()),
+    };
+    drive_turn(turn, prompt, session, oa, browser).await
+}
+
+// Add a typed learner turn to the conversation and answer it like a spoken one.
+async fn send_learner_text<P: RealtimeProvider>(
+    text: &str,
+    prompt: &str,
+    session: &mut Session,
+    oa: &mut P,
+    browser: &mut impl BrowserSink,
+) -> anyhow::Result<()> {
+    oa.create_item(Item::message(Role::User, text)).await?;
+    // Only used to attribute the turn here; upstream assigns its own item id
+    let item_id = format!("text_{}", Uuid::new_v4().simple());
+    session.record_transcript(Speaker::Learner, &item_id, text).await;
+    let echo =
+        ServerMessage::Transcript { speaker: Speaker::Learner, item_id: item_id.clone(), text: text.to_string() };
+    browser.send_message(echo).await?;
+    drive_turn(TurnEvent::LearnerText { item_id: &item_id, text }, prompt, session, oa, browser).await
+}
+
+// Advance the state machine by one turn and ask upstream for whatever comes next.
+async fn drive_turn<P: RealtimeProvider>(
+    turn: TurnEvent<'_>,
+    prompt: &str,
+    session: &Session,
+    oa: &mut P,
+    browser: &mut impl BrowserSink,
+) -> anyhow::Result<()> {
+    let (transition, topic, request) = {
+        let mut ctx = session.context.lock().await;
+        // Answers to probing questions wait for their transcript, which gets graded
+        let answer = match &turn {
+            TurnEvent::LearnerTranscript { item_id, transcript }
+                if ctx.turn_state(item_id) == Some(&ConversationState::Questioning) =>
+            {
+                Some(transcript.trim().to_string())
+            }
+            TurnEvent::LearnerText { text, .. } if ctx.state == ConversationState::Questioning => {
+                Some(text.trim().to_string())
+            }
+            _ => None,
+        };
+        // The tutor stays quiet while the learner explains, and while the analysis of
+        // their explanation is out it has nothing to say yet
+        let respond_to_turn = matches!(turn, TurnEvent::LearnerTurn { .. } | TurnEvent::LearnerText { .. })
+            && !matches!(ctx.state, ConversationState::Questioning | ConversationState::Teaching)
+            && ctx.pending_call != Some(PendingCall::GapAnalysis);
+
+        let transition = ctx.advance(turn);
+        let request = if transition == Some(ConversationState::Analyzing) {
+            ctx.pending_call = Some(PendingCall::GapAnalysis);
+            Some(Request::FunctionCall(report::gap_analysis_instructions(&ctx), report::gap_analysis_tool()))
+        } else if let Some(answer) = answer {
+            match ctx.current_gap() {
+                Some(gap) if !answer.is_empty() => {
+                    let instructions = probing::grading_instructions(gap, &answer);
+                    ctx.pending_call = Some(PendingCall::AnswerGrade);
+                    Some(Request::FunctionCall(instructions, probing::grade_answer_tool()))
+                }
+                _ => Some(Request::Response(tutor_instructions(prompt, &ctx))),
+            }
+        } else if respond_to_turn {
+            Some(Request::Response(tutor_instructions(prompt, &ctx)))
+        } else {
+            None
+        };
+        (transition, ctx.topic.clone(), request)
+    };
+    session.save().await;
+
+    if let Some(state) = transition {
+        announce_transition(state, topic, session, oa, browser).await?;
+    }
+    match request {
+        Some(Request::Response(instructions)) => oa.create_response(&instructions).await,
+        Some(Request::FunctionCall(instructions, function)) => oa.request_function_call(&instructions, function).await,
+        None => Ok(()),
+    }
+}
+
+// Run a session tool the tutor called and hand it the result.
+async fn handle_tool_call<P: RealtimeProvider>(
+    call_id: &str,
+    name: &str,
+    arguments: &str,
+    session: &Session,
+    oa: &mut P,
+    browser: &mut impl BrowserSink,
+) -> anyhow::Result<()> {
+    let (result, topic) = {
+        let mut ctx = session.context.lock().await;
+        (tools::dispatch(&mut ctx, name, arguments), ctx.topic.clone())
+    };
+    session.save().await;
+    oa.create_item(Item::function_call_output(call_id, result.output)).await?;
+    if let Some(state) = result.transition {
+        announce_transition(state, topic, session, oa, browser).await?;
+    }
+    Ok(())
+}
+
+fn calls_session_tool(response: &RealtimeResponse) -> bool {
+    response
+        .output
+        .iter()
+        .any(|item| item.kind == ItemKind::FunctionCall && item.name.as_deref().is_some_and(tools::is_session_tool))
+}
+
+// Count the new state and tell the browser; a finished conversation also gets its report.
+// Turn detection is patient only while the learner is explaining.
+async fn announce_transition<P: RealtimeProvider>(
+    state: ConversationState,
+    topic: Option<String>,
+    session: &Session,
+    oa: &mut P,
+    browser: &mut impl BrowserSink,
+) -> anyhow::Result<()> {
+    metrics::state_entered(&state);
+    Span::current().record("state", field::debug(&state));
+    oa.set_teaching(state == ConversationState::Teaching).await?;
+    let complete = state == ConversationState::Complete;
+    browser.send_message(ServerMessage::State { state, topic }).await?;
+    if complete {
+        match session.write_report().await {
+            Ok(_) => {
+                let url = format!("/sessions/{}/report", session.id);
+                browser.send_message(ServerMessage::Report { url }).await?;
+            }
+            Err(e) => error!("Failed to write report: {}", e),
+        }
+    }
+    Ok(())
+}
+
+// Store the gaps from the structured analysis, then have the tutor talk them through.
+// Without usable gaps the tutor analyzes aloud and its questions are used instead.
+async fn finish_gap_analysis<P: RealtimeProvider>(
+    response: &RealtimeResponse,
+    prompt: &str,
+    session: &Session,
+    oa: &mut P,
+) -> anyhow::Result<()> {
+    let arguments = response.function_call(report::RECORD_GAPS).and_then(|call| call.arguments.as_deref());
+    let instructions = {
+        let mut ctx = session.context.lock().await;
+        match arguments.map(report::parse_gaps) {
+            Some(Ok(gaps)) => {
+                info!("Gap analysis found {} gaps", gaps.len());
+                ctx.record_gaps(gaps);
+            }
+            Some(Err(e)) => warn!("Unusable gap analysis: {}", e),
+            None => warn!("Gap analysis ended without a result ({:?})", response.status),
+        }
+        tutor_instructions(prompt, &ctx)
+    };
+    session.save().await;
+    oa.create_response(&instructions).await
+}
+
+// Apply the grade of the learner's answer, then have the tutor follow up: another
+// try at the same question, or feedback and the next one. Without a usable grade
+// the question is simply asked again.
+async fn finish_grading<P: RealtimeProvider>(
+    response: &RealtimeResponse,
+    prompt: &str,
+    session: &Session,
+    oa: &mut P,
+    browser: &mut impl BrowserSink,
+) -> anyhow::Result<()> {
+    let arguments = response.function_call(probing::GRADE_ANSWER).and_then(|call| call.arguments.as_deref());
+    let (transition, topic, instructions) = {
+        let mut ctx = session.context.lock().await;
+        let transition = match arguments.map(probing::parse_grade) {
+            Some(Ok(grade)) => {
+                info!("Answer graded {:?}", grade.verdict);
+                ctx.record_verdict(grade.verdict, grade.feedback)
+            }
+            Some(Err(e)) => {
+                warn!("Unusable answer grade: {}", e);
+                None
+            }
+            None => {
+                warn!("Answer grading ended without a result ({:?})", response.status);
+                None
+            }
+        };
+        (transition, ctx.topic.clone(), tutor_instructions(prompt, &ctx))
+    };
+    session.save().await;
+
+    if let Some(state) = transition {
+        announce_transition(state, topic, session, oa, browser).await?;
+    }
+    oa.create_response(&instructions).await
+}
+
+// Translate an OpenAI event into the message the browser should see, if any.
+fn browser_message(event: &ServerEvent) -> Option<ServerMessage> {
+    match event {
+        // A barge-in may try to cancel a response that had just finished
+        ServerEvent::Error { error } if error.code.as_deref() == Some("response_cancel_not_active") => None,
+        ServerEvent::Error { error } => Some(ServerMessage::error(ErrorCode::Upstream, error.message.clone())),
+        ServerEvent::InputAudioTranscriptionCompleted { item_id, transcript, .. } => Some(ServerMessage::Transcript {
+            speaker: Speaker::Learner,
+            item_id: item_id.clone(),
+            text: transcript.clone(),
+        }),
+        ServerEvent::ResponseAudioTranscriptDone { item_id, transcript: text, .. }
+        | ServerEvent::ResponseTextDone { item_id, text, .. } => {
+            Some(ServerMessage::Transcript { speaker: Speaker::Tutor, item_id: item_id.clone(), text: text.clone() })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_PROMPT;
+    use crate::mock_realtime::MockRealtime;
+    use crate::openai::{DEFAULT_MODEL, OASocket, OpenAiConfig, test_openai_config};
+    use crate::quota::QuotaLimits;
+    use crate::realtime::ClientEvent;
+    use crate::scripted::{Script, ScriptedProvider};
+    use crate::session_store::MemorySessionStore;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{Value, json};
+    use std::collections::BTreeMap;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    type Client = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+    // Explanations end only when the learner says so, unless a test sets a timeout.
+    fn test_app<P: RealtimeProvider>(provider: P::Config, data_dir: PathBuf) -> AppState<P> {
+        AppState {
+            sessions: Sessions::new(Arc::new(MemorySessionStore::default())),
+            data_dir,
+            prompt: Arc::from(DEFAULT_PROMPT),
+            provider: Arc::new(provider),
+            turn_mode: TurnMode::ServerVad,
+            teaching_timeout: None,
+            record_audio: false,
+            auth: Arc::new(Authenticator::new(&BTreeMap::new())),
+            quotas: Arc::new(Quotas::new(QuotaLimits::default())),
+            admission: Admission::new(0),
+            direct: Arc::new(DirectSessions::default()),
+            allowed_origins: vec![HeaderValue::from_static("http://tutor.test")],
+        }
+    }
+
+    async fn start<P: RealtimeProvider>(provider: P::Config, data_dir: PathBuf) -> Client {
+        serve_app(test_app::<P>(provider, data_dir)).await
+    }
+
+    async fn serve_app<P: RealtimeProvider>(app: AppState<P>) -> Client {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(app)).await.unwrap() });
+        let (client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws")).await.unwrap();
+        client
+    }
+
+    // Another connection to the server `client` is connected to.
+    async fn connect_again(client: &Client) -> Client {
+        let tokio_tungstenite::MaybeTlsStream::Plain(stream) = client.get_ref() else {
+            unreachable!("tests connect without TLS");
+        };
+        let url = format!("ws://{}/ws", stream.peer_addr().unwrap());
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    async fn send(client: &mut Client, message: Value) {
+        client.send(WsMessage::Text(message.to_string().into())).await.unwrap();
+    }
+
+    // The next text message, skipping audio.
+    async fn recv(client: &mut Client) -> Value {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+                .await
+                .expect("backend went quiet")
+                .unwrap()
+                .unwrap();
+            if let WsMessage::Text(text) = msg {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    // The next message of type `kind`, skipping others.
+    async fn recv_type(client: &mut Client, kind: &str) -> Value {
+        loop {
+            let msg = recv(client).await;
+            if msg["type"] == kind {
+                return msg;
             }
         }
     }
 
+    async fn expect_state(client: &mut Client, state: &str) {
+        assert_eq!(recv_type(client, "state").await["state"], state);
+    }
+
+    // A plain HTTP GET against the server `client` is connected to.
+    async fn http_get(client: &Client, path: &str) -> String {
+        http_request(client, "GET", path, None, None).await
+    }
+
+    // The same, with `token` as a bearer token.
+    async fn http_get_as(client: &Client, path: &str, token: &str) -> String {
+        http_request(client, "GET", path, None, Some(token)).await
+    }
+
+    // The status code and JSON body of a request with a JSON body.
+    async fn http_json(client: &Client, method: &str, path: &str, body: Value) -> (u16, Value) {
+        let response = http_request(client, method, path, Some(body), None).await;
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    async fn http_request(
+        client: &Client,
+        method: &str,
+        path: &str,
+        body: Option<Value>,
+        token: Option<&str>,
+    ) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let tokio_tungstenite::MaybeTlsStream::Plain(stream) = client.get_ref() else {
+            unreachable!("tests connect without TLS");
+        };
+        let mut http = tokio::net::TcpStream::connect(stream.peer_addr().unwrap()).await.unwrap();
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let authorization = token.map(|token| format!("Authorization: Bearer {token}\r\n")).unwrap_or_default();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{authorization}\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        http.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn speak(client: &mut Client) {
+        client.send(WsMessage::Binary(vec![0u8; 960].into())).await.unwrap();
+        send(client, json!({ "type": "commit_audio" })).await;
+    }
+
+    #[tokio::test]
+    async fn scripted_session_runs_from_greeting_to_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = start::<ScriptedProvider>(Script::test_mode(), dir.path().to_path_buf()).await;
+
+        send(&mut client, json!({ "type": "hello", "version": 1 })).await;
+        let welcome = recv(&mut client).await;
+        assert_eq!(welcome["type"], "welcome");
+        assert_eq!(welcome["resumed"], false);
+        assert_eq!(recv(&mut client).await, json!({ "type": "status", "status": "test_mode" }));
+
+        // The greeting is captioned as it is spoken
+        let caption = recv_type(&mut client, "caption").await;
+        assert_eq!(
+            (&caption["speaker"], &caption["text"], &caption["final"]),
+            (&json!("tutor"), &json!("Hi, "), &json!(false))
+        );
+        expect_state(&mut client, "waiting_for_topic").await;
+        speak(&mut client).await;
+        expect_state(&mut client, "ready_to_teach").await;
+        // The tutor named the topic through record_topic
+        let teaching = recv_type(&mut client, "state").await;
+        assert_eq!(teaching, json!({ "type": "state", "state": "teaching", "topic": "Photosynthesis in plants" }));
+        speak(&mut client).await;
+        send(&mut client, json!({ "type": "done_teaching" })).await;
+        expect_state(&mut client, "analyzing").await;
+        expect_state(&mut client, "questioning").await;
+        // Answer each question once it has been asked; the second needs another go
+        for _ in 0..2 {
+            speak(&mut client).await;
+            while recv_type(&mut client, "transcript").await["speaker"] != "tutor" {}
+        }
+        speak(&mut client).await;
+        expect_state(&mut client, "complete").await;
+
+        let session_id = welcome["session_id"].as_str().unwrap();
+        let jsonl = dir.path().join(format!("transcripts/{session_id}.jsonl"));
+        let transcript = std::fs::read_to_string(jsonl).unwrap();
+        assert!(transcript.contains("Photosynthesis"));
+
+        let url = recv_type(&mut client, "report").await["url"].as_str().unwrap().to_string();
+        assert_eq!(url, format!("/sessions/{session_id}/report"));
+        let report = http_get(&client, &url).await;
+        assert!(report.starts_with("HTTP/1.1 200 OK"));
+        assert!(report.contains(
+            "1. **Missing part** (high): Where does the oxygen come from? *(answered in depth)*\n   > they give off oxygen"
+        ));
+        assert!(report.contains("2. **Vague** (medium): What does chlorophyll do? *(answered in depth)*"));
+        assert!(http_get(&client, "/sessions/not-a-session/report").await.starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn resuming_takes_the_session_over_from_a_connection_left_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut first = start::<ScriptedProvider>(Script::test_mode(), dir.path().to_path_buf()).await;
+        send(&mut first, json!({ "type": "hello", "version": 1 })).await;
+        let session_id = recv_type(&mut first, "welcome").await["session_id"].clone();
+        expect_state(&mut first, "waiting_for_topic").await;
+        speak(&mut first).await;
+        expect_state(&mut first, "ready_to_teach").await;
+
+        // The browser lost track of its first connection and comes back on another
+        let mut second = connect_again(&first).await;
+        send(&mut second, json!({ "type": "hello", "version": 1, "session_id": session_id })).await;
+        let error = recv_type(&mut first, "error").await;
+        assert_eq!(error["code"], "taken_over");
+        let welcome = recv_type(&mut second, "welcome").await;
+        assert_eq!((&welcome["session_id"], &welcome["resumed"]), (&session_id, &json!(true)));
+        let state = recv_type(&mut second, "state").await;
+        assert!(state["state"] == "ready_to_teach" || state["state"] == "teaching", "{state}");
+    }
+
+    #[tokio::test]
+    async fn text_session_runs_on_typed_turns() {
+        let script = Script::parse(
+            r#"[
+                { "after": "response.create", "events": [{ "say": "What will you teach me?" }] },
+                { "after": "response.create", "events": [{ "say": "Go ahead." }] },
+                { "after": "response.create", "events": [{ "call": { "name": "record_gaps", "arguments": { "gaps": [
+                    { "category": "missing_part", "excerpt": "", "severity": "high", "question": "Where does the oxygen come from?" }
+                ] } } }] },
+                { "after": "response.create", "events": [{ "say": "Where does the oxygen come from?" }] },
+                { "after": "response.create", "events": [{ "call": { "name": "grade_answer", "arguments": { "verdict": "deep", "feedback": "Right." } } }] },
+                { "after": "response.create", "events": [{ "say": "Well done!" }] }
+            ]"#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = start::<ScriptedProvider>(script, dir.path().to_path_buf()).await;
+
+        send(&mut client, json!({ "type": "hello", "version": 1, "mode": "text" })).await;
+        assert_eq!(recv(&mut client).await["mode"], "text");
+        expect_state(&mut client, "waiting_for_topic").await;
+
+        send(&mut client, json!({ "type": "text", "text": "Photosynthesis" })).await;
+        assert_eq!(
+            recv_type(&mut client, "state").await,
+            json!({ "type": "state", "state": "ready_to_teach", "topic": "Photosynthesis" })
+        );
+        expect_state(&mut client, "teaching").await;
+        send(&mut client, json!({ "type": "text", "text": "Plants turn light into sugar." })).await;
+        send(&mut client, json!({ "type": "done_teaching" })).await;
+        expect_state(&mut client, "analyzing").await;
+        expect_state(&mut client, "questioning").await;
+        send(&mut client, json!({ "type": "text", "text": "From splitting water." })).await;
+        expect_state(&mut client, "complete").await;
+
+        // Replies arrive as text captions, never as audio
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), client.next()).await.unwrap().unwrap().unwrap();
+            let WsMessage::Text(text) = msg else {
+                panic!("a text session got {:?}", msg);
+            };
+            let caption: Value = serde_json::from_str(&text).unwrap();
+            if caption["type"] == "caption" && caption["final"] == true {
+                assert_eq!(caption["text"], "Well done!");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn out_of_band_calls_wait_for_their_own_response() {
+        // A tutor response finishes before each call does
+        let script = Script::parse(
+            r#"[
+                { "after": "response.create", "events": [{ "say": "What will you teach me?" }] },
+                { "after": "response.create", "events": [{ "say": "Go ahead." }] },
+                { "after": "response.create", "events": [
+                    { "say": "Thanks, one moment." },
+                    { "call": { "name": "record_gaps", "arguments": { "gaps": [
+                        { "category": "missing_part", "excerpt": "", "severity": "high", "question": "Where does the oxygen come from?" }
+                    ] } } }
+                ] },
+                { "after": "response.create", "events": [{ "say": "Where does the oxygen come from?" }] },
+                { "after": "response.create", "events": [
+                    { "say": "Let me check that." },
+                    { "call": { "name": "grade_answer", "arguments": { "verdict": "deep", "feedback": "Right." } } }
+                ] },
+                { "after": "response.create", "events": [{ "say": "Well done!" }] }
+            ]"#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = start::<ScriptedProvider>(script, dir.path().to_path_buf()).await;
+
+        send(&mut client, json!({ "type": "hello", "version": 1, "mode": "text" })).await;
+        expect_state(&mut client, "waiting_for_topic").await;
+        send(&mut client, json!({ "type": "text", "text": "Photosynthesis" })).await;
+        expect_state(&mut client, "ready_to_teach").await;
+        expect_state(&mut client, "teaching").await;
+        send(&mut client, json!({ "type": "text", "text": "Plants turn light into sugar." })).await;
+        send(&mut client, json!({ "type": "done_teaching" })).await;
+        expect_state(&mut client, "analyzing").await;
+        // The gap recorded, not the tutor's aside, decides what is asked
+        expect_state(&mut client, "questioning").await;
+        send(&mut client, json!({ "type": "text", "text": "From splitting water." })).await;
+        expect_state(&mut client, "complete").await;
+
+        let url = recv_type(&mut client, "report").await["url"].as_str().unwrap().to_string();
+        let report = http_get(&client, &url).await;
+        assert!(report.contains("Where does the oxygen come from?"), "{report}");
+        assert!(report.contains("answered in depth"), "{report}");
+    }
+
+    #[tokio::test]
+    async fn explanation_spans_turns_until_the_learner_goes_quiet() {
+        let script = Script::parse(
+            r#"[
+                { "after": "response.create", "events": [{ "say": "What will you teach me?" }] },
+                { "after": "response.create", "events": [{ "say": "Go ahead." }] },
+                { "after": "response.create", "events": [{ "call": { "name": "record_gaps", "arguments": { "gaps": [] } } }] },
+                { "after": "response.create", "events": [{ "say": "That was complete, well done!" }] }
+            ]"#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let app = AppState::<ScriptedProvider> {
+            teaching_timeout: Some(Duration::from_millis(200)),
+            ..test_app(script, dir.path().to_path_buf())
+        };
+        let mut client = serve_app(app).await;
+
+        send(&mut client, json!({ "type": "hello", "version": 1, "mode": "text" })).await;
+        expect_state(&mut client, "waiting_for_topic").await;
+        send(&mut client, json!({ "type": "text", "text": "Photosynthesis" })).await;
+        expect_state(&mut client, "ready_to_teach").await;
+        expect_state(&mut client, "teaching").await;
+
+        // The tutor lets the learner pause between parts without answering
+        for part in ["Plants turn light into sugar.", "They give off oxygen."] {
+            send(&mut client, json!({ "type": "text", "text": part })).await;
+            assert_eq!(recv_type(&mut client, "transcript").await["text"], part);
+        }
+        expect_state(&mut client, "analyzing").await;
+        expect_state(&mut client, "complete").await;
+
+        let url = recv_type(&mut client, "report").await["url"].as_str().unwrap().to_string();
+        let report = http_get(&client, &url).await;
+        assert!(report.contains("Plants turn light into sugar. They give off oxygen."));
+    }
+
+    #[tokio::test]
+    async fn voice_is_recorded_only_with_consent() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = AppState::<ScriptedProvider> {
+            record_audio: true,
+            ..test_app(Script::test_mode(), dir.path().to_path_buf())
+        };
+        let mut client = serve_app(app.clone()).await;
+        send(&mut client, json!({ "type": "hello", "version": 1 })).await;
+        assert_eq!(recv_type(&mut client, "welcome").await["recording"], false);
+
+        let mut client = serve_app(app).await;
+        send(&mut client, json!({ "type": "hello", "version": 1, "record": true })).await;
+        let welcome = recv_type(&mut client, "welcome").await;
+        assert_eq!(welcome["recording"], true);
+        expect_state(&mut client, "waiting_for_topic").await;
+        speak(&mut client).await;
+        expect_state(&mut client, "ready_to_teach").await;
+
+        // One track per speaker, for the session that agreed only
+        let recordings = dir.path().join("recordings").join(welcome["session_id"].as_str().unwrap());
+        let mut tracks: Vec<PathBuf> =
+            std::fs::read_dir(&recordings).unwrap().map(|entry| entry.unwrap().path()).collect();
+        tracks.sort();
+        assert_eq!(tracks.len(), 2);
+        assert!(tracks[0].to_string_lossy().ends_with("-learner.wav"));
+        assert!(tracks[1].to_string_lossy().ends_with("-tutor.wav"));
+        assert_eq!(std::fs::read_dir(dir.path().join("recordings")).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn opus_is_used_when_asked_for_and_built_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = start::<ScriptedProvider>(Script::test_mode(), dir.path().to_path_buf()).await;
+        let input_audio = json!({ "sample_rate": 48_000, "channels": 1, "encoding": "opus" });
+        send(
+            &mut client,
+            json!({ "type": "hello", "version": 1, "input_audio": input_audio, "output_encoding": "opus" }),
+        )
+        .await;
+
+        // Without Opus the browser gets PCM16 at the rates it would have had
+        let encoding = if opus::AVAILABLE { "opus" } else { "pcm16" };
+        let welcome = recv_type(&mut client, "welcome").await;
+        assert_eq!(welcome["input_audio"], json!({ "sample_rate": 48_000, "channels": 1, "encoding": encoding }));
+        assert_eq!(welcome["output_audio"], json!({ "sample_rate": 24_000, "channels": 1, "encoding": encoding }));
+
+        // The greeting's audio arrives either way, in 20 ms packets with Opus
+        let audio = loop {
+            match client.next().await.unwrap().unwrap() {
+                WsMessage::Binary(audio) => break audio,
+                _ => continue,
+            }
+        };
+        if opus::AVAILABLE {
+            assert!(audio.len() < 480 * 2, "{} bytes is not a 20 ms packet", audio.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_need_a_valid_token_when_auth_is_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = Authenticator::new(&BTreeMap::from([("k1".to_string(), "s".repeat(32))]));
+        let alice = auth.issue("k1", "alice", chrono::Duration::hours(1)).unwrap();
+        let bob = auth.issue("k1", "bob", chrono::Duration::hours(1)).unwrap();
+        let app = AppState::<ScriptedProvider> {
+            auth: Arc::new(auth),
+            ..test_app(Script::test_mode(), dir.path().to_path_buf())
+        };
+
+        for hello in
+            [json!({ "type": "hello", "version": 1 }), json!({ "type": "hello", "version": 1, "token": "nope" })]
+        {
+            let mut client = serve_app(app.clone()).await;
+            send(&mut client, hello).await;
+            assert_eq!(recv_type(&mut client, "error").await["code"], "unauthorized");
+        }
+
+        let mut client = serve_app(app.clone()).await;
+        send(&mut client, json!({ "type": "hello", "version": 1, "token": alice })).await;
+        let session_id = recv_type(&mut client, "welcome").await["session_id"].clone();
+        expect_state(&mut client, "waiting_for_topic").await;
+        client.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            app.sessions.load(session_id.as_str().unwrap()).await.unwrap().unwrap().user_id.as_deref(),
+            Some("alice")
+        );
+
+        // Knowing alice's session id is not enough for bob to pick it up
+        let mut client = serve_app(app).await;
+        send(&mut client, json!({ "type": "hello", "version": 1, "token": bob, "session_id": session_id })).await;
+        let welcome = recv_type(&mut client, "welcome").await;
+        assert_eq!(welcome["resumed"], false);
+        assert_ne!(welcome["session_id"], session_id);
+    }
+
+    #[tokio::test]
+    async fn reports_need_their_owners_token_in_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = Authenticator::new(&BTreeMap::from([("k1".to_string(), "s".repeat(32))]));
+        let alice = auth.issue("k1", "alice", chrono::Duration::hours(1)).unwrap();
+        let bob = auth.issue("k1", "bob", chrono::Duration::hours(1)).unwrap();
+        let app = AppState::<ScriptedProvider> {
+            auth: Arc::new(auth),
+            ..test_app(Script::test_mode(), dir.path().to_path_buf())
+        };
+        let mut client = serve_app(app).await;
+        send(&mut client, json!({ "type": "hello", "version": 1, "token": alice })).await;
+        let session_id = recv_type(&mut client, "welcome").await["session_id"].as_str().unwrap().to_string();
+        expect_state(&mut client, "waiting_for_topic").await;
+        let markdown = report::markdown_path(dir.path(), &session_id);
+        std::fs::create_dir_all(markdown.parent().unwrap()).unwrap();
+        std::fs::write(&markdown, "# Alice's report").unwrap();
+
+        let path = format!("/sessions/{session_id}/report");
+        assert!(http_get(&client, &path).await.starts_with("HTTP/1.1 401"));
+        // A token in the URL would end up in history and logs, so it does not count
+        assert!(http_get(&client, &format!("{path}?token={alice}")).await.starts_with("HTTP/1.1 401"));
+        assert!(http_get_as(&client, &path, &bob).await.starts_with("HTTP/1.1 404"));
+        let report = http_get_as(&client, &path, &alice).await;
+        assert!(report.starts_with("HTTP/1.1 200") && report.ends_with("# Alice's report"), "{report}");
+    }
+
+    #[tokio::test]
+    async fn sessions_end_with_a_notice_once_the_quota_is_used_up() {
+        let script = Script::parse(
+            r#"[
+                { "after": "response.create", "events": [{ "say": "What will you teach me?" }] },
+                { "after": "response.create", "events": [{ "say": "That is all for today." }] }
+            ]"#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let quotas = Quotas::new(QuotaLimits { learner_audio_seconds: 1, ..QuotaLimits::default() });
+        let app =
+            AppState::<ScriptedProvider> { quotas: Arc::new(quotas), ..test_app(script, dir.path().to_path_buf()) };
+        let mut client = serve_app(app).await;
+
+        send(&mut client, json!({ "type": "hello", "version": 1 })).await;
+        let usage = recv_type(&mut client, "usage").await;
+        assert_eq!(usage["used"]["learner_audio_seconds"], 0.0);
+        assert_eq!(usage["limits"]["learner_audio_seconds"], 1);
+        assert_eq!(recv_type(&mut client, "transcript").await["text"], "What will you teach me?");
+
+        // A second of audio uses it all up
+        client.send(WsMessage::Binary(vec![0u8; 48_000].into())).await.unwrap();
+        assert_eq!(recv_type(&mut client, "transcript").await["text"], "That is all for today.");
+        assert_eq!(recv_type(&mut client, "usage").await["used"]["learner_audio_seconds"], 1.0);
+        assert_eq!(recv_type(&mut client, "error").await["code"], "quota_exceeded");
+
+        // Until tomorrow, the next connection is turned away
+        let tokio_tungstenite::MaybeTlsStream::Plain(stream) = client.get_ref() else {
+            unreachable!("tests connect without TLS");
+        };
+        let url = format!("ws://{}/ws", stream.peer_addr().unwrap());
+        match tokio_tungstenite::connect_async(url).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS)
+            }
+            other => panic!("expected the connection to be refused, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_past_the_limit_wait_their_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = AppState::<ScriptedProvider> {
+            admission: Admission::new(1),
+            ..test_app(Script::test_mode(), dir.path().to_path_buf())
+        };
+        let mut first = serve_app(app.clone()).await;
+        send(&mut first, json!({ "type": "hello", "version": 1 })).await;
+        recv_type(&mut first, "welcome").await;
+
+        let mut second = serve_app(app.clone()).await;
+        send(&mut second, json!({ "type": "hello", "version": 1 })).await;
+        assert_eq!(recv(&mut second).await, json!({ "type": "status", "status": "queued", "position": 1 }));
+        let mut third = serve_app(app).await;
+        send(&mut third, json!({ "type": "hello", "version": 1 })).await;
+        assert_eq!(recv(&mut third).await["position"], 2);
+
+        // The first session ending lets the second in, and the third moves up
+        first.close(None).await.unwrap();
+        assert_eq!(recv(&mut second).await["type"], "welcome");
+        assert_eq!(recv(&mut third).await["position"], 1);
+    }
+
+    #[tokio::test]
+    async fn learner_barging_in_cuts_the_tutor_off() {
+        // A greeting that is still streaming when the learner starts talking
+        let chunk = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, [0u8; 4800]);
+        let script = Script::parse(&format!(
+            r#"[
+                {{ "after": "response.create", "events": [
+                    {{ "type": "response.audio.delta", "response_id": "resp_1", "item_id": "item_1",
+                       "output_index": 0, "content_index": 0, "delta": "{chunk}" }},
+                    {{ "type": "response.audio.delta", "response_id": "resp_1", "item_id": "item_1",
+                       "output_index": 0, "content_index": 0, "delta": "{chunk}" }}
+                ] }},
+                {{ "after": "input_audio_buffer.append", "events": [
+                    {{ "type": "input_audio_buffer.speech_started", "audio_start_ms": 0, "item_id": "item_2" }}
+                ] }}
+            ]"#
+        ))
+        .unwrap();
+        let mock = MockRealtime::start(vec![script]).await.unwrap();
+        let config = OpenAiConfig { base_url: mock.url.clone(), ..test_openai_config() };
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = start::<OASocket>(config, dir.path().to_path_buf()).await;
+
+        send(&mut client, json!({ "type": "hello", "version": 1 })).await;
+        assert_eq!(recv_type(&mut client, "tutor_audio").await["item_id"], "item_1");
+        send(&mut client, json!({ "type": "playback_progress", "item_id": "item_1", "played_ms": 40 })).await;
+        client.send(WsMessage::Binary(vec![0u8; 960].into())).await.unwrap();
+        assert_eq!(recv_type(&mut client, "flush_audio").await, json!({ "type": "flush_audio", "item_id": "item_1" }));
+
+        // Upstream was told to stop and where the learner stopped hearing the tutor
+        let mut received = Vec::new();
+        for _ in 0..50 {
+            received = mock.received().remove(0);
+            if received.iter().any(|event| matches!(event, ClientEvent::ConversationItemTruncate { .. })) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(received.iter().any(
+            |event| matches!(event, ClientEvent::ResponseCancel { response_id } if response_id.as_deref() == Some("resp_1"))
+        ));
+        assert!(received.iter().any(|event| matches!(
+            event,
+            ClientEvent::ConversationItemTruncate { item_id, audio_end_ms: 40, .. } if item_id == "item_1"
+        )));
+    }
+
+    #[tokio::test]
+    async fn push_to_talk_turns_off_turn_detection() {
+        let script = Script::parse(
+            r#"[
+                { "after": "response.create", "events": [{ "say": "What will you teach me?" }] },
+                { "after": "input_audio_buffer.commit", "events": [{ "hear": "Photosynthesis" }] }
+            ]"#,
+        )
+        .unwrap();
+        let mock = MockRealtime::start(vec![script]).await.unwrap();
+        let config = OpenAiConfig { base_url: mock.url.clone(), ..test_openai_config() };
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = start::<OASocket>(config, dir.path().to_path_buf()).await;
+
+        send(&mut client, json!({ "type": "hello", "version": 1, "turn_mode": "push_to_talk" })).await;
+        assert_eq!(recv_type(&mut client, "welcome").await["turn_mode"], "push_to_talk");
+        expect_state(&mut client, "waiting_for_topic").await;
+        // The learner's commit is what ends their turn
+        speak(&mut client).await;
+        expect_state(&mut client, "ready_to_teach").await;
+
+        match &mock.received()[0][0] {
+            ClientEvent::SessionUpdate { session } => assert_eq!(session.turn_detection, Some(None)),
+            other => panic!("expected session.update, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn openai_session_survives_upstream_errors_and_disconnects() {
+        let first = Script::parse(
+            r#"[
+                { "after": "response.create", "events": [{ "say": "What will you teach me?" }] },
+                { "after": "input_audio_buffer.commit", "events": [{ "hear": "Photosynthesis" }] },
+                { "after": "response.create", "events": [
+                    { "type": "error", "error": { "type": "server_error", "message": "overloaded" } },
+                    { "disconnect": true }
+                ] }
+            ]"#,
+        )
+        .unwrap();
+        let second = Script::parse(
+            r#"[{ "after": "response.create", "events": [{ "say": "Sorry about that. Go ahead and teach me!" }] }]"#,
+        )
+        .unwrap();
+        let mock = MockRealtime::start(vec![first, second]).await.unwrap();
+        let config = OpenAiConfig { base_url: mock.url.clone(), ..test_openai_config() };
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = start::<OASocket>(config, dir.path().to_path_buf()).await;
+
+        send(&mut client, json!({ "type": "hello", "version": 1 })).await;
+        assert_eq!(recv(&mut client).await["type"], "welcome");
+        assert_eq!(recv(&mut client).await, json!({ "type": "status", "status": "ready" }));
+        expect_state(&mut client, "waiting_for_topic").await;
+        speak(&mut client).await;
+        expect_state(&mut client, "ready_to_teach").await;
+
+        assert_eq!(
+            recv_type(&mut client, "error").await,
+            json!({ "type": "error", "code": "upstream", "message": "overloaded" })
+        );
+        assert_eq!(recv_type(&mut client, "status").await["status"], "reconnecting");
+        assert_eq!(recv_type(&mut client, "status").await["status"], "ready");
+        expect_state(&mut client, "teaching").await;
+
+        // The new upstream session was configured and caught up on the conversation
+        let received = mock.received();
+        assert_eq!(received.len(), 2);
+        match &received[1][0] {
+            ClientEvent::SessionUpdate { session } => assert_eq!(session.tools.as_ref().map(Vec::len), Some(4)),
+            other => panic!("expected session.update, got {:?}", other),
+        }
+        let replayed =
+            received[1].iter().filter(|event| matches!(event, ClientEvent::ConversationItemCreate { .. })).count();
+        assert_eq!(replayed, 2);
+    }
+
+    // Stands in for OpenAI's session endpoint: answers one request with a client
+    // secret and hands over the body it was sent.
+    async fn mint_stub() -> (String, tokio::sync::oneshot::Receiver<Value>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/v1/realtime", listener.local_addr().unwrap());
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let body = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|n| n.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        assert!(head.starts_with("POST /v1/realtime/sessions "), "{head}");
+                        break body.to_string();
+                    }
+                }
+            };
+            tx.send(serde_json::from_str(&body).unwrap()).unwrap();
+            let reply = json!({ "id": "sess_1", "client_secret": { "value": "ek_test", "expires_at": 1_900_000_000 } })
+                .to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reply}",
+                reply.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn direct_sessions_run_the_state_machine_on_posted_events() {
+        let (url, minted) = mint_stub().await;
+        let config = OpenAiConfig { base_url: url.clone(), ..test_openai_config() };
+        let dir = tempfile::tempdir().unwrap();
+        let app = test_app::<OASocket>(config, dir.path().to_path_buf());
+        let client = serve_app(app.clone()).await;
+
+        let (status, started) = http_json(&client, "POST", "/realtime/sessions", json!({})).await;
+        assert_eq!(status, 200);
+        assert_eq!(started["client_secret"]["value"], "ek_test");
+        assert_eq!(started["url"], format!("{}?model={DEFAULT_MODEL}", url.replacen("ws://", "http://", 1)));
+        // The tutor greets the learner first
+        assert_eq!(started["events"][0]["type"], "response.create");
+        // The session is set up before the browser ever sees it
+        let minted = minted.await.unwrap();
+        assert_eq!(minted["model"], DEFAULT_MODEL);
+        assert_eq!(minted["instructions"], DEFAULT_PROMPT);
+        assert_eq!(minted["voice"], "alloy");
+        assert!(!minted["tools"].as_array().unwrap().is_empty());
+
+        let id = started["session_id"].as_str().unwrap().to_string();
+        let greeting = json!({
+            "type": "response.done",
+            "response": {
+                "id": "resp_1",
+                "status": "completed",
+                "output": [{ "id": "item_0", "type": "message", "role": "assistant", "content": [{ "type": "audio", "transcript": "What will you teach me?" }] }],
+                "usage": { "total_tokens": 40 }
+            }
+        });
+        let learner = [
+            json!({ "type": "input_audio_buffer.committed", "item_id": "item_1" }),
+            json!({ "type": "conversation.item.input_audio_transcription.completed", "item_id": "item_1", "transcript": "Photosynthesis" }),
+        ];
+        let path = format!("/realtime/sessions/{id}/events");
+        let (status, reply) = http_json(&client, "POST", &path, json!({ "events": [greeting] })).await;
+        assert_eq!(status, 200);
+        let messages = reply["messages"].as_array().unwrap();
+        assert!(messages.iter().any(|m| m["type"] == "state" && m["state"] == "waiting_for_topic"));
+        assert_eq!(messages.last().unwrap()["used"]["tokens"], 40);
+
+        // The learner's answer is recorded, and the tutor is asked to go on
+        let (_, reply) = http_json(&client, "POST", &path, json!({ "events": learner })).await;
+        let messages = reply["messages"].as_array().unwrap();
+        assert!(messages.iter().any(|m| m["type"] == "state" && m["state"] == "ready_to_teach"));
+        assert!(messages.iter().any(|m| m["type"] == "transcript" && m["text"] == "Photosynthesis"));
+        assert!(reply["events"].as_array().unwrap().iter().any(|e| e["type"] == "response.create"));
+        assert_eq!(reply["ended"], false);
+
+        let (status, _) = http_json(&client, "DELETE", &format!("/realtime/sessions/{id}"), Value::Null).await;
+        assert_eq!(status, 204);
+        let (status, _) = http_json(&client, "POST", &path, json!({ "events": [] })).await;
+        assert_eq!(status, 404);
+        let stored = app.sessions.load(&id).await.unwrap().unwrap();
+        assert_eq!(stored.topic.as_deref(), Some("Photosynthesis"));
+    }
+
+    #[tokio::test]
+    async fn direct_sessions_are_refused_while_audio_is_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = QuotaLimits { tutor_audio_seconds: 600, ..QuotaLimits::default() };
+        let app = AppState::<OASocket> {
+            quotas: Arc::new(Quotas::new(limits)),
+            ..test_app(test_openai_config(), dir.path().to_path_buf())
+        };
+        let client = serve_app(app).await;
+        let (status, _) = http_json(&client, "POST", "/realtime/sessions", json!({})).await;
+        assert_eq!(status, 403);
+    }
+
+    #[tokio::test]
+    async fn a_refused_direct_session_leaves_the_live_one_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = AppState::<ScriptedProvider> {
+            admission: Admission::new(1),
+            ..test_app(Script::test_mode(), dir.path().to_path_buf())
+        };
+        let mut relay = serve_app(app).await;
+        send(&mut relay, json!({ "type": "hello", "version": 1 })).await;
+        let session_id = recv_type(&mut relay, "welcome").await["session_id"].clone();
+        expect_state(&mut relay, "waiting_for_topic").await;
+
+        // The relay connection holds the only place, so resuming directly is refused
+        let (status, _) = http_json(&relay, "POST", "/realtime/sessions", json!({ "session_id": session_id })).await;
+        assert_eq!(status, 503);
+        speak(&mut relay).await;
+        expect_state(&mut relay, "ready_to_teach").await;
+    }
+
+    #[tokio::test]
+    async fn only_the_frontend_origin_may_call_the_api() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let dir = tempfile::tempdir().unwrap();
+        let client = start::<ScriptedProvider>(Script::test_mode(), dir.path().to_path_buf()).await;
+        let tokio_tungstenite::MaybeTlsStream::Plain(stream) = client.get_ref() else {
+            unreachable!("tests connect without TLS");
+        };
+        let addr = stream.peer_addr().unwrap();
+
+        for (origin, allowed) in [("http://tutor.test", true), ("http://elsewhere.test", false)] {
+            let mut http = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "OPTIONS /realtime/sessions HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                 Origin: {origin}\r\nAccess-Control-Request-Method: POST\r\n\r\n"
+            );
+            http.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            http.read_to_string(&mut response).await.unwrap();
+            let granted = format!("access-control-allow-origin: {origin}");
+            assert_eq!(response.to_lowercase().contains(&granted), allowed, "{response}");
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_are_exported_for_prometheus() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = start::<ScriptedProvider>(Script::test_mode(), dir.path().to_path_buf()).await;
+        send(&mut client, json!({ "type": "hello", "version": 1 })).await;
+        expect_state(&mut client, "waiting_for_topic").await;
+
+        let metrics = http_get(&client, "/metrics").await;
+        assert!(metrics.starts_with("HTTP/1.1 200"), "{metrics}");
+        assert!(metrics.contains("application/openmetrics-text"));
+        for line in [
+            "feynman_state_transitions_total{state=\"waiting_for_topic\"}",
+            "feynman_audio_bytes_total{direction=\"out\"}",
+            "feynman_upstream_connect_seconds_count",
+            "# TYPE feynman_active_sessions gauge",
+        ] {
+            assert!(metrics.contains(line), "missing {line} in {metrics}");
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use serde::Deserialize;
+
+use crate::audio::{self, UPSTREAM_SAMPLE_RATE};
+use crate::protocol::{SessionMode, SessionStatus};
+use crate::provider::{RealtimeProvider, SessionOptions};
+use crate::realtime::{CALL_TAG, ContentPart, Item, ItemKind, Response, ResponseStatus, Role, ServerEvent, Tool};
+
+const TEST_MODE_SCRIPT: &str = include_str!("../scripts/test_mode.json");
+// Length of the tone that stands in for the tutor's voice on every `say`.
+const SAY_TONE_SECONDS: f32 = 0.25;
+
+// A canned upstream conversation: a JSON array of steps, each released by the relay
+// sending the client event named in `after` (e.g. "response.create",
+// "input_audio_buffer.commit"), or straight away when `after` is absent.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Script {
+    steps: Vec<ScriptStep>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ScriptStep {
+    #[serde(default)]
+    after: Option<String>,
+    events: Vec<ScriptEvent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ScriptEvent {
+    // A completed tutor response: a tone for audio, its transcript word by word and
+    // response.done. In a text session, the text word by word instead.
+    Say { say: String },
+    // A committed learner turn and its input transcription, in one delta.
+    Hear { hear: String },
+    // A completed response that calls a function with these arguments.
+    Call { call: ScriptCall },
+    // `{"disconnect": true}`: the connection drops here.
+    Disconnect { disconnect: bool },
+    // Any Realtime server event, verbatim.
+    Event(ServerEvent),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ScriptCall {
+    name: String,
+    arguments: serde_json::Value,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    // The built-in conversation TEST_MODE walks through, from greeting to wrap-up.
+    pub fn test_mode() -> Self {
+        Self::parse(TEST_MODE_SCRIPT).expect("the built-in script is valid")
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let script: Script = serde_json::from_str(text)?;
+        for (index, step) in script.steps.iter().enumerate() {
+            for event in &step.events {
+                if let ScriptEvent::Event(ServerEvent::Unknown(raw)) = event {
+                    return Err(anyhow::anyhow!("step {}: unrecognised event {}", index + 1, raw));
+                }
+            }
+        }
+        Ok(script)
+    }
+}
+
+// Replays a `Script` deterministically in place of a real upstream session.
+pub struct ScriptedProvider {
+    steps: VecDeque<ScriptStep>,
+    // Events ready for `next_event`; None is a dropped connection.
+    pending: VecDeque<Option<ServerEvent>>,
+    next_id: u32,
+    disconnected: bool,
+    mode: SessionMode,
+    // The function last requested out of band, until a scripted call answers it.
+    requested_call: Option<String>,
+}
+
+impl ScriptedProvider {
+    // Release the next step if it is waiting on `client_event` (or on nothing), then
+    // any unconditional steps after it.
+    fn observe(&mut self, client_event: Option<&str>) {
+        let mut trigger = client_event;
+        while let Some(step) = self.steps.front() {
+            match step.after.as_deref() {
+                None => {}
+                Some(after) if Some(after) == trigger => trigger = None,
+                Some(_) => break,
+            }
+            let step = self.steps.pop_front().expect("front exists");
+            for event in step.events {
+                self.expand(event);
+            }
+        }
+    }
+
+    fn expand(&mut self, event: ScriptEvent) {
+        match event {
+            ScriptEvent::Say { say } if self.mode == SessionMode::Text => {
+                let response_id = self.id("resp");
+                let item_id = self.id("item");
+                for word in say.split_inclusive(' ') {
+                    self.pending.push_back(Some(ServerEvent::ResponseTextDelta {
+                        response_id: response_id.clone(),
+                        item_id: item_id.clone(),
+                        output_index: 0,
+                        content_index: 0,
+                        delta: word.to_string(),
+                    }));
+                }
+                self.pending.push_back(Some(ServerEvent::ResponseTextDone {
+                    response_id: response_id.clone(),
+                    item_id: item_id.clone(),
+                    output_index: 0,
+                    content_index: 0,
+                    text: say.clone(),
+                }));
+                let mut item = Item::message(Role::Assistant, &say);
+                item.id = Some(item_id);
+                self.pending.push_back(Some(ServerEvent::ResponseDone {
+                    response: Response {
+                        id: response_id,
+                        status: ResponseStatus::Completed,
+                        output: vec![item],
+                        usage: None,
+                        metadata: None,
+                    },
+                }));
+            }
+            ScriptEvent::Say { say } => {
+                let response_id = self.id("resp");
+                let item_id = self.id("item");
+                let tone = audio::sine_pcm16(UPSTREAM_SAMPLE_RATE, 440.0, SAY_TONE_SECONDS);
+                self.pending.push_back(Some(ServerEvent::ResponseAudioDelta {
+                    response_id: response_id.clone(),
+                    item_id: item_id.clone(),
+                    output_index: 0,
+                    content_index: 0,
+                    delta: base64::Engine::encode(&base64::engine::general_purpose::STANDARD, tone),
+                }));
+                for word in say.split_inclusive(' ') {
+                    self.pending.push_back(Some(ServerEvent::ResponseAudioTranscriptDelta {
+                        response_id: response_id.clone(),
+                        item_id: item_id.clone(),
+                        output_index: 0,
+                        content_index: 0,
+                        delta: word.to_string(),
+                    }));
+                }
+                self.pending.push_back(Some(ServerEvent::ResponseAudioTranscriptDone {
+                    response_id: response_id.clone(),
+                    item_id: item_id.clone(),
+                    output_index: 0,
+                    content_index: 0,
+                    transcript: say.clone(),
+                }));
+                let mut item = Item::message(Role::Assistant, "");
+                item.id = Some(item_id);
+                item.content = vec![ContentPart::Audio { audio: None, transcript: Some(say) }];
+                self.pending.push_back(Some(ServerEvent::ResponseDone {
+                    response: Response {
+                        id: response_id,
+                        status: ResponseStatus::Completed,
+                        output: vec![item],
+                        usage: None,
+                        metadata: None,
+                    },
+                }));
+            }
+            ScriptEvent::Hear { hear } => {
+                let item_id = self.id("item");
+                self.pending.push_back(Some(ServerEvent::InputAudioBufferCommitted {
+                    previous_item_id: None,
+                    item_id: item_id.clone(),
+                }));
+                self.pending.push_back(Some(ServerEvent::InputAudioTranscriptionDelta {
+                    item_id: item_id.clone(),
+                    content_index: 0,
+                    delta: hear.clone(),
+                }));
+                self.pending.push_back(Some(ServerEvent::InputAudioTranscriptionCompleted {
+                    item_id,
+                    content_index: 0,
+                    transcript: hear,
+                }));
+            }
+            ScriptEvent::Call { call } => {
+                // A call to what the relay asked for answers that request, tagged as upstream would
+                let metadata = self
+                    .requested_call
+                    .take_if(|name| *name == call.name)
+                    .map(|name| HashMap::from([(CALL_TAG.to_string(), name)]));
+                let response_id = self.id("resp");
+                let item_id = self.id("item");
+                let call_id = self.id("call");
+                let arguments = call.arguments.to_string();
+                self.pending.push_back(Some(ServerEvent::ResponseFunctionCallArgumentsDone {
+                    response_id: response_id.clone(),
+                    item_id: item_id.clone(),
+                    output_index: 0,
+                    call_id: call_id.clone(),
+                    name: Some(call.name.clone()),
+                    arguments: arguments.clone(),
+                }));
+                self.pending.push_back(Some(ServerEvent::ResponseDone {
+                    response: Response {
+                        id: response_id,
+                        status: ResponseStatus::Completed,
+                        output: vec![Item {
+                            id: Some(item_id),
+                            kind: ItemKind::FunctionCall,
+                            role: None,
+                            content: Vec::new(),
+                            call_id: Some(call_id),
+                            name: Some(call.name),
+                            arguments: Some(arguments),
+                            output: None,
+                        }],
+                        usage: None,
+                        metadata,
+                    },
+                }));
+            }
+            ScriptEvent::Disconnect { disconnect } => {
+                if disconnect {
+                    self.pending.push_back(None);
+                }
+            }
+            ScriptEvent::Event(event) => self.pending.push_back(Some(event)),
+        }
+    }
+
+    fn id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{prefix}_scripted_{}", self.next_id)
+    }
+}
+
+impl RealtimeProvider for ScriptedProvider {
+    type Config = Script;
+
+    const READY_STATUS: SessionStatus = SessionStatus::TestMode;
+
+    async fn connect(script: &Script, _instructions: &str, options: SessionOptions) -> Result<Self> {
+        let mut provider = Self {
+            steps: script.steps.clone().into(),
+            pending: VecDeque::new(),
+            next_id: 0,
+            disconnected: false,
+            mode: options.mode,
+            requested_call: None,
+        };
+        provider.observe(None);
+        Ok(provider)
+    }
+
+    // Scripted turns end where the script says, so there is nothing to tune.
+    async fn set_teaching(&mut self, _teaching: bool) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send_audio(&mut self, _pcm: Bytes) -> Result<()> {
+        self.observe(Some("input_audio_buffer.append"));
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.observe(Some("input_audio_buffer.commit"));
+        Ok(())
+    }
+
+    async fn create_response(&mut self, _instructions: &str) -> Result<()> {
+        self.observe(Some("response.create"));
+        Ok(())
+    }
+
+    async fn request_function_call(&mut self, _instructions: &str, function: Tool) -> Result<()> {
+        self.requested_call = Some(function.name);
+        self.observe(Some("response.create"));
+        Ok(())
+    }
+
+    // Like the real API: the rest of the response's audio never comes and it ends
+    // as cancelled.
+    async fn cancel_response(&mut self, response_id: &str) -> Result<()> {
+        self.pending.retain(|event| {
+            !matches!(event, Some(ServerEvent::ResponseAudioDelta { response_id: id, .. }) if id == response_id)
+        });
+        for event in self.pending.iter_mut().flatten() {
+            if let ServerEvent::ResponseDone { response } = event
+                && response.id == response_id
+            {
+                response.status = ResponseStatus::Cancelled;
+            }
+        }
+        self.observe(Some("response.cancel"));
+        Ok(())
+    }
+
+    async fn truncate(&mut self, _item_id: &str, _audio_end_ms: u64) -> Result<()> {
+        self.observe(Some("conversation.item.truncate"));
+        Ok(())
+    }
+
+    async fn create_item(&mut self, _item: Item) -> Result<()> {
+        self.observe(Some("conversation.item.create"));
+        Ok(())
+    }
+
+    async fn next_event(&mut self) -> Result<ServerEvent> {
+        if self.disconnected {
+            return Err(anyhow::anyhow!("scripted connection is closed"));
+        }
+        match self.pending.pop_front() {
+            Some(Some(event)) => Ok(event),
+            Some(None) => {
+                self.disconnected = true;
+                Err(anyhow::anyhow!("scripted disconnect"))
+            }
+            // Nothing more until the relay sends something; it never does while this
+            // future is pending, so just wait to be dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.steps.clear();
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event_types(provider: &ScriptedProvider) -> Vec<String> {
+        provider.pending.iter().flatten().map(ServerEvent::event_type).collect()
+    }
+
+    #[tokio::test]
+    async fn steps_wait_for_their_client_event() {
+        let script = Script::parse(
+            r#"[
+                { "events": [{ "type": "input_audio_buffer.speech_started", "audio_start_ms": 0, "item_id": "item_0" }] },
+                { "after": "input_audio_buffer.commit", "events": [{ "hear": "Photosynthesis" }] },
+                { "after": "response.create", "events": [{ "say": "Teach me!" }] }
+            ]"#,
+        )
+        .unwrap();
+        let options = SessionOptions { mode: SessionMode::Voice, turn_mode: Default::default() };
+        let mut provider = ScriptedProvider::connect(&script, "", options).await.unwrap();
+        assert_eq!(event_types(&provider), ["input_audio_buffer.speech_started"]);
+        provider.pending.clear();
+
+        // Out of order: the response step is not next yet
+        provider.create_response("").await.unwrap();
+        assert!(provider.pending.is_empty());
+
+        provider.commit().await.unwrap();
+        assert_eq!(
+            event_types(&provider),
+            [
+                "input_audio_buffer.committed",
+                "conversation.item.input_audio_transcription.delta",
+                "conversation.item.input_audio_transcription.completed"
+            ]
+        );
+        provider.pending.clear();
+
+        provider.create_response("").await.unwrap();
+        assert_eq!(
+            event_types(&provider),
+            [
+                "response.audio.delta",
+                "response.audio_transcript.delta",
+                "response.audio_transcript.delta",
+                "response.audio_transcript.done",
+                "response.done"
+            ]
+        );
+        match provider.pending.back() {
+            Some(Some(ServerEvent::ResponseDone { response })) => {
+                assert_eq!(response.transcript().as_deref(), Some("Teach me!"))
+            }
+            other => panic!("expected response.done, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_events() {
+        assert!(Script::parse(r#"[{ "events": [{ "sya": "typo" }] }]"#).is_err());
+        Script::test_mode();
+    }
+}
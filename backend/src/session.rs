@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::Utc;
+use tokio::sync::{Mutex, OwnedMutexGuard, watch};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::audio::{AudioOutput, AudioPipeline};
+use crate::conversation::{ConversationContext, ConversationState};
+use crate::protocol::{SessionMode, Speaker};
+use crate::provider::SessionOptions;
+use crate::realtime::{Item, Role};
+use crate::recording::SessionRecording;
+use crate::report::GapReport;
+use crate::session_store::{SessionStore, StoredSession};
+use crate::transcript::{TranscriptEntry, TranscriptWriter};
+
+// Everything a browser connection carries for its tutoring session, independent of
+// the upstream connection it is currently relayed to.
+pub struct Session {
+    pub id: String,
+    // The authenticated user the session belongs to, if there is one.
+    pub user_id: Option<String>,
+    // True when the client presented the id of a session we had stored.
+    pub resumed: bool,
+    pub context: Arc<Mutex<ConversationContext>>,
+    pub pipeline: AudioPipeline,
+    pub output: AudioOutput,
+    // Chosen by the browser's hello; every upstream connection is opened with them.
+    pub options: SessionOptions,
+    pub transcript: Option<TranscriptWriter>,
+    // Only with the learner's consent, and only in voice sessions.
+    pub recording: Option<SessionRecording>,
+    claim: Claim,
+    data_dir: PathBuf,
+}
+
+impl Session {
+    // Start the session `claim` holds: where the stored one left off, or fresh.
+    pub async fn open(
+        claim: Claim,
+        user_id: Option<&str>,
+        data_dir: &Path,
+        pipeline: AudioPipeline,
+        output: AudioOutput,
+        options: SessionOptions,
+        record: bool,
+    ) -> Self {
+        let id = claim.id.clone();
+        let (resumed, context) = match &claim.stored {
+            Some(stored) => (true, stored.restore()),
+            None => (false, ConversationContext::new()),
+        };
+
+        let transcript = match TranscriptWriter::create(data_dir, &id, user_id).await {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                error!("Failed to create transcript for session {}: {}", id, e);
+                None
+            }
+        };
+
+        let recording = if record && options.mode == SessionMode::Voice {
+            match SessionRecording::create(data_dir, &id).await {
+                Ok(recording) => Some(recording),
+                Err(e) => {
+                    error!("Failed to start recording session {}: {}", id, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Self {
+            id,
+            user_id: user_id.map(str::to_string),
+            resumed,
+            context: Arc::new(Mutex::new(context)),
+            pipeline,
+            output,
+            options,
+            transcript,
+            recording,
+            claim,
+            data_dir: data_dir.to_path_buf(),
+        }
+    }
+
+    // Every finished turn so far, including those of earlier connections.
+    pub fn history(&self) -> &[TranscriptEntry] {
+        self.transcript.as_ref().map(TranscriptWriter::entries).unwrap_or_default()
+    }
+
+    // The history as conversation items, to replay into a new upstream session.
+    pub fn history_items(&self) -> Vec<Item> {
+        self.history()
+            .iter()
+            .map(|entry| {
+                let role = match entry.speaker {
+                    Speaker::Learner => Role::User,
+                    Speaker::Tutor => Role::Assistant,
+                };
+                Item::message(role, &entry.text)
+            })
+            .collect()
+    }
+
+    // Persist the current protocol position.
+    pub async fn save(&self) {
+        let stored = {
+            let ctx = self.context.lock().await;
+            StoredSession::capture(&self.id, self.user_id.as_deref(), &ctx)
+        };
+        // Written with the context unlocked, so the conversation does not wait on it
+        if let Err(e) = self.claim.sessions.save(stored).await {
+            error!("Failed to save session {}: {}", self.id, e);
+        }
+    }
+
+    // Wait until another connection takes the session over. This one should then
+    // finish and let go of it.
+    pub async fn taken_over(&mut self) {
+        self.claim.taken_over().await
+    }
+
+    // The same, for a session nothing is waiting on in a loop: true once it is
+    // taken over, false if it ends first.
+    pub fn watch_taken_over(&self) -> impl Future<Output = bool> + Send + 'static {
+        let mut kicked = self.claim.kicked.clone();
+        async move { kicked.changed().await.is_ok() }
+    }
+
+    // Append a finished turn to the session transcript, tagged with the step it was
+    // spoken in. A learner turn belongs to the step that was current when it was committed.
+    pub async fn record_transcript(&mut self, speaker: Speaker, item_id: &str, text: &str) {
+        let Some(transcript) = self.transcript.as_mut() else {
+            return;
+        };
+        let entry = {
+            let ctx = self.context.lock().await;
+            let state = match speaker {
+                Speaker::Learner => ctx.turn_state(item_id).unwrap_or(&ctx.state),
+                Speaker::Tutor => &ctx.state,
+            };
+            TranscriptEntry {
+                at: Utc::now(),
+                speaker,
+                item_id: item_id.to_string(),
+                text: text.to_string(),
+                state: state.clone(),
+                topic: ctx.topic.clone(),
+            }
+        };
+        if let Err(e) = transcript.append(entry).await {
+            error!("Failed to record transcript: {}", e);
+        }
+    }
+
+    // Add audio to the recording, if there is one. A recording that fails to
+    // write is given up on rather than failing the session.
+    pub async fn record_audio(&mut self, speaker: Speaker, pcm: &[u8]) {
+        let Some(recording) = self.recording.as_mut() else {
+            return;
+        };
+        if let Err(e) = recording.append(speaker, pcm).await {
+            error!("Failed to record audio, stopping the recording: {}", e);
+            self.recording = None;
+        }
+    }
+
+    // Write the gap report for where the session stands now.
+    pub async fn write_report(&self) -> std::io::Result<PathBuf> {
+        let report = GapReport::capture(&self.id, self.user_id.as_deref(), &*self.context.lock().await);
+        report.write(&self.data_dir).await
+    }
+
+    // Called once the browser has gone: save and render the transcript, and bring a
+    // finished session's report up to date.
+    pub async fn finish(&mut self) {
+        self.save().await;
+        if self.context.lock().await.state == ConversationState::Complete
+            && let Err(e) = self.write_report().await
+        {
+            error!("Failed to write report: {}", e);
+        }
+        if let Some(transcript) = self.transcript.as_mut() {
+            match transcript.finish().await {
+                Ok(path) => info!("Transcript written to {}", path.display()),
+                Err(e) => error!("Failed to write transcript: {}", e),
+            }
+        }
+        if let Some(recording) = self.recording.as_mut() {
+            match recording.finish().await {
+                Ok([learner, tutor]) => info!("Audio recorded to {} and {}", learner.display(), tutor.display()),
+                Err(e) => error!("Failed to finish recording: {}", e),
+            }
+        }
+    }
+}
+
+// The stored sessions, and which of them a connection is holding right now. The
+// store is used from the blocking pool, since SQLite blocks.
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    live: std::sync::Mutex<HashMap<String, Arc<Live>>>,
+}
+
+// A session id some connection holds or is waiting for.
+struct Live {
+    lease: Arc<Mutex<()>>,
+    // Bumped by each connection that wants the session, telling the one holding it
+    // to let go.
+    kick: watch::Sender<u64>,
+}
+
+// A connection's hold on a session id, so that only one connection at a time
+// writes its transcript and stored row. Dropping it lets go.
+pub struct Claim {
+    pub id: String,
+    // Where the session left off, if it is being resumed.
+    pub stored: Option<StoredSession>,
+    sessions: Arc<Sessions>,
+    live: Arc<Live>,
+    kicked: watch::Receiver<u64>,
+    _lease: OwnedMutexGuard<()>,
+}
+
+impl Sessions {
+    pub fn new(store: Arc<dyn SessionStore>) -> Arc<Self> {
+        Arc::new(Self { store, live: Default::default() })
+    }
+
+    pub async fn load(&self, id: &str) -> Result<Option<StoredSession>> {
+        let (store, id) = (self.store.clone(), id.to_string());
+        tokio::task::spawn_blocking(move || store.load(&id)).await?
+    }
+
+    pub async fn save(&self, session: StoredSession) -> Result<()> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.save(&session)).await?
+    }
+
+    // Hold the stored session `requested_id` if there is one and it belongs to
+    // `user_id`, otherwise a fresh session under a new id. A connection still
+    // holding the session, say one whose network dropped without it noticing, is
+    // told to let go, and this waits until it has saved and finished.
+    pub async fn claim(self: &Arc<Self>, requested_id: Option<&str>, user_id: Option<&str>) -> Claim {
+        // Only ever look up well-formed ids; they also name files on disk.
+        let stored = match requested_id.filter(|id| Uuid::parse_str(id).is_ok()) {
+            Some(id) => self.load(id).await.unwrap_or_else(|e| {
+                error!("Failed to load session {}: {}", id, e);
+                None
+            }),
+            None => None,
+        };
+        // Nobody gets to pick up someone else's session, or cut off its
+        // connection, by knowing its id
+        let owned = stored.filter(|stored| {
+            let owned = stored.user_id.as_deref() == user_id;
+            if !owned {
+                warn!("Session {} belongs to another user, starting a new one", stored.id);
+            }
+            owned
+        });
+        let Some(owned) = owned else {
+            return self.hold(Uuid::new_v4().to_string()).await;
+        };
+        let mut claim = self.hold(owned.id.clone()).await;
+        // Read it again: whoever held it last saved it on the way out
+        claim.stored = match self.load(&claim.id).await {
+            Ok(stored) => stored.or(Some(owned)),
+            Err(e) => {
+                error!("Failed to load session {}: {}", claim.id, e);
+                Some(owned)
+            }
+        };
+        claim
+    }
+
+    async fn hold(self: &Arc<Self>, id: String) -> Claim {
+        let (live, kicked) = {
+            let mut sessions = self.live.lock().unwrap();
+            let live = sessions
+                .entry(id.clone())
+                .or_insert_with(|| Arc::new(Live { lease: Arc::new(Mutex::new(())), kick: watch::channel(0).0 }))
+                .clone();
+            // Only kicks from after this one are meant for us
+            live.kick.send_modify(|kicks| *kicks += 1);
+            let kicked = live.kick.subscribe();
+            (live, kicked)
+        };
+        if live.lease.try_lock().is_err() {
+            info!("Session {} is still held by another connection, taking it over", id);
+        }
+        let lease = live.lease.clone().lock_owned().await;
+        Claim { id, stored: None, sessions: self.clone(), live, kicked, _lease: lease }
+    }
+}
+
+impl Claim {
+    async fn taken_over(&mut self) {
+        if self.kicked.changed().await.is_err() {
+            // Nobody else can want the session any more
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.live.lock().unwrap();
+        // The last one out, with nobody waiting, takes the id off the list
+        if Arc::strong_count(&self.live) == 2 {
+            sessions.remove(&self.id);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+
+use crate::conversation::{ConversationContext, ConversationState, Gap};
+
+// Where a session was in the Feynman protocol, saved so a client that reconnects
+// with the same session id picks up where it left off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub id: String,
+    // Who the session belongs to; None when it was started without a token.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    pub state: ConversationState,
+    pub topic: Option<String>,
+    pub explanation: String,
+    pub questions: Vec<Gap>,
+    pub current_question_index: usize,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl StoredSession {
+    pub fn capture(id: &str, user_id: Option<&str>, ctx: &ConversationContext) -> Self {
+        Self {
+            id: id.to_string(),
+            user_id: user_id.map(str::to_string),
+            state: ctx.state.clone(),
+            topic: ctx.topic.clone(),
+            explanation: ctx.explanation.clone(),
+            questions: ctx.questions.clone(),
+            current_question_index: ctx.current_question_index,
+            updated_at: Utc::now(),
+        }
+    }
+
+    pub fn restore(&self) -> ConversationContext {
+        let mut ctx = ConversationContext::new();
+        ctx.state = self.state.clone();
+        ctx.topic = self.topic.clone();
+        ctx.explanation = self.explanation.clone();
+        ctx.questions = self.questions.clone();
+        ctx.current_question_index = self.current_question_index;
+        ctx
+    }
+}
+
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> Result<Option<StoredSession>>;
+    fn save(&self, session: &StoredSession) -> Result<()>;
+}
+
+// Sessions kept for the lifetime of the process, for tests and throwaway servers.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, StoredSession>>,
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> Result<Option<StoredSession>> {
+        Ok(self.sessions.lock().unwrap().get(id).cloned())
+    }
+
+    fn save(&self, session: &StoredSession) -> Result<()> {
+        self.sessions.lock().unwrap().insert(session.id.clone(), session.clone());
+        Ok(())
+    }
+}
+
+// Sessions in an embedded SQLite database, one row per session.
+pub struct SqliteSessionStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteSessionStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let conn = Connection::open(path).with_context(|| format!("opening {}", path.display()))?;
+        Self::with_connection(conn)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                topic TEXT,
+                explanation TEXT NOT NULL,
+                questions TEXT NOT NULL,
+                current_question_index INTEGER NOT NULL,
+                updated_at TEXT NOT NULL
+            );",
+        )?;
+        // Databases from before sessions had owners
+        let has_user_id =
+            conn.prepare("SELECT 1 FROM pragma_table_info('sessions') WHERE name = 'user_id'")?.exists([])?;
+        if !has_user_id {
+            conn.execute_batch("ALTER TABLE sessions ADD COLUMN user_id TEXT;")?;
+        }
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+}
+
+impl SessionStore for SqliteSessionStore {
+    fn load(&self, id: &str) -> Result<Option<StoredSession>> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn
+            .query_row(
+                "SELECT state, topic, explanation, questions, current_question_index, updated_at, user_id
+                 FROM sessions WHERE id = ?1",
+                params![id],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, i64>(4)?,
+                        row.get::<_, String>(5)?,
+                        row.get::<_, Option<String>>(6)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        let Some((state, topic, explanation, questions, current_question_index, updated_at, user_id)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(StoredSession {
+            id: id.to_string(),
+            user_id,
+            state: serde_json::from_value(serde_json::Value::String(state))?,
+            topic,
+            explanation,
+            questions: parse_questions(&questions)?,
+            current_question_index: current_question_index as usize,
+            updated_at: updated_at.parse()?,
+        }))
+    }
+
+    fn save(&self, session: &StoredSession) -> Result<()> {
+        let state = serde_json::to_value(&session.state)?;
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO sessions (id, state, topic, explanation, questions, current_question_index, updated_at, user_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT(id) DO UPDATE SET
+                state = excluded.state,
+                topic = excluded.topic,
+                explanation = excluded.explanation,
+                questions = excluded.questions,
+                current_question_index = excluded.current_question_index,
+                updated_at = excluded.updated_at,
+                user_id = excluded.user_id",
+            params![
+                session.id,
+                state.as_str(),
+                session.topic,
+                session.explanation,
+                serde_json::to_string(&session.questions)?,
+                session.current_question_index as i64,
+                session.updated_at.to_rfc3339(),
+                session.user_id,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+// Rows saved before the gap analysis hold plain question strings.
+fn parse_questions(json: &str) -> Result<Vec<Gap>> {
+    match serde_json::from_str(json) {
+        Ok(gaps) => Ok(gaps),
+        Err(_) => Ok(serde_json::from_str::<Vec<String>>(json)?.into_iter().map(Gap::from_question).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn questioning_session() -> StoredSession {
+        let mut ctx = ConversationContext::new();
+        ctx.state = ConversationState::Questioning;
+        ctx.topic = Some("Photosynthesis".to_string());
+        ctx.explanation = "Plants turn light into sugar.".to_string();
+        ctx.questions = vec![
+            Gap::from_question("Where does the oxygen come from?".to_string()),
+            Gap::from_question("What is chlorophyll for?".to_string()),
+        ];
+        ctx.current_question_index = 1;
+        StoredSession::capture("session-1", Some("alice"), &ctx)
+    }
+
+    fn round_trips(store: &dyn SessionStore) {
+        assert_eq!(store.load("session-1").unwrap(), None);
+
+        let mut session = questioning_session();
+        store.save(&session).unwrap();
+        assert_eq!(store.load("session-1").unwrap(), Some(session.clone()));
+
+        session.state = ConversationState::Complete;
+        session.current_question_index = 2;
+        store.save(&session).unwrap();
+        let restored = store.load("session-1").unwrap().unwrap().restore();
+        assert_eq!(restored.state, ConversationState::Complete);
+        assert_eq!(restored.topic.as_deref(), Some("Photosynthesis"));
+        assert_eq!(restored.questions.len(), 2);
+        assert_eq!(restored.current_question_index, 2);
+        assert_eq!(store.load("session-1").unwrap().unwrap().user_id.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn memory_store_round_trips() {
+        round_trips(&MemorySessionStore::default());
+    }
+
+    #[test]
+    fn sqlite_store_round_trips() {
+        round_trips(&SqliteSessionStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn sqlite_store_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.db");
+        SqliteSessionStore::open(&path).unwrap().save(&questioning_session()).unwrap();
+        let reopened = SqliteSessionStore::open(&path).unwrap();
+        assert_eq!(reopened.load("session-1").unwrap().unwrap().topic.as_deref(), Some("Photosynthesis"));
+    }
+
+    #[test]
+    fn sqlite_store_reads_plain_question_lists() {
+        let store = SqliteSessionStore::in_memory().unwrap();
+        store.save(&questioning_session()).unwrap();
+        store.conn.lock().unwrap().execute("UPDATE sessions SET questions = '[\"Why?\"]'", []).unwrap();
+        assert_eq!(store.load("session-1").unwrap().unwrap().questions, vec![Gap::from_question("Why?".to_string())]);
+    }
+}
@@ -0,0 +1,231 @@
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::conversation::{ConversationContext, ConversationState, Gap, Probe, Verdict, gap_label, verdict_label};
+use crate::realtime::Tool;
+
+// Functions the tutor model may call during the conversation to tell the backend
+// what it decided. They are declared in session.update; each call is handled here
+// against the conversation state and answered with a function_call_output.
+struct SessionTool {
+    name: &'static str,
+    description: &'static str,
+    parameters: fn() -> Value,
+    handler: fn(&mut ConversationContext, &str) -> Result<Handled>,
+}
+
+// What a handler did: the result the model reads, and any state transition.
+struct Handled {
+    output: Value,
+    transition: Option<ConversationState>,
+}
+
+const TOOLS: &[SessionTool] = &[
+    SessionTool {
+        name: "record_topic",
+        description: "Record the topic the learner is going to teach, as a short name, once they have said it.",
+        parameters: || {
+            json!({
+                "type": "object",
+                "properties": { "topic": { "type": "string" } },
+                "required": ["topic"],
+                "additionalProperties": false
+            })
+        },
+        handler: record_topic,
+    },
+    SessionTool {
+        name: "record_gap",
+        description: "Record one gap in the learner's explanation that you noticed, with the question that probes it.",
+        parameters: || {
+            json!({
+                "type": "object",
+                "properties": {
+                    "category": { "type": "string", "enum": ["missing_part", "vague", "misconception"] },
+                    "excerpt": { "type": "string" },
+                    "severity": { "type": "string", "enum": ["low", "medium", "high"] },
+                    "question": { "type": "string" }
+                },
+                "required": ["category", "excerpt", "severity", "question"],
+                "additionalProperties": false
+            })
+        },
+        handler: record_gap,
+    },
+    SessionTool {
+        name: "mark_question_result",
+        description: "Record how well the learner answered the current probing question.",
+        parameters: || {
+            json!({
+                "type": "object",
+                "properties": {
+                    "verdict": { "type": "string", "enum": ["deep", "needs_reexplain", "review_material"] },
+                    "feedback": { "type": "string" }
+                },
+                "required": ["verdict", "feedback"],
+                "additionalProperties": false
+            })
+        },
+        handler: mark_question_result,
+    },
+    SessionTool {
+        name: "end_session",
+        description: "End the session, e.g. when the learner asks to stop.",
+        parameters: || {
+            json!({
+                "type": "object",
+                "properties": { "reason": { "type": "string" } },
+                "required": ["reason"],
+                "additionalProperties": false
+            })
+        },
+        handler: end_session,
+    },
+];
+
+// The declarations for session.update.
+pub fn session_tools() -> Vec<Tool> {
+    TOOLS.iter().map(|tool| Tool::function(tool.name, tool.description, (tool.parameters)())).collect()
+}
+
+pub fn is_session_tool(name: &str) -> bool {
+    TOOLS.iter().any(|tool| tool.name == name)
+}
+
+pub struct ToolResult {
+    // JSON for the function_call_output item.
+    pub output: String,
+    pub transition: Option<ConversationState>,
+}
+
+// Run the handler for a call to `name`. Bad arguments or a call that does not fit
+// the current step are reported back to the model rather than failing the session.
+pub fn dispatch(ctx: &mut ConversationContext, name: &str, arguments: &str) -> ToolResult {
+    let result = match TOOLS.iter().find(|tool| tool.name == name) {
+        Some(tool) => (tool.handler)(ctx, arguments),
+        None => Err(anyhow::anyhow!("unknown function {name}")),
+    };
+    match result {
+        Ok(handled) => {
+            info!("Tool call {} handled", name);
+            ToolResult { output: handled.output.to_string(), transition: handled.transition }
+        }
+        Err(e) => {
+            warn!("Tool call {} refused: {}", name, e);
+            ToolResult { output: json!({ "ok": false, "error": e.to_string() }).to_string(), transition: None }
+        }
+    }
+}
+
+fn ok() -> Value {
+    json!({ "ok": true })
+}
+
+#[derive(Deserialize)]
+struct RecordTopic {
+    topic: String,
+}
+
+fn record_topic(ctx: &mut ConversationContext, arguments: &str) -> Result<Handled> {
+    let RecordTopic { topic } = serde_json::from_str(arguments)?;
+    let topic = topic.trim();
+    if topic.is_empty() {
+        bail!("the topic is empty");
+    }
+    match ctx.state {
+        ConversationState::Initial | ConversationState::WaitingForTopic | ConversationState::ReadyToTeach => {}
+        _ => bail!("the topic is fixed once the learner starts teaching"),
+    }
+    ctx.topic = Some(topic.to_string());
+    Ok(Handled { output: ok(), transition: None })
+}
+
+fn record_gap(ctx: &mut ConversationContext, arguments: &str) -> Result<Handled> {
+    let gap: Gap = serde_json::from_str(arguments)?;
+    match ctx.state {
+        ConversationState::Analyzing | ConversationState::Questioning => {}
+        _ => bail!("gaps can only be recorded once the learner has finished explaining"),
+    }
+    let label = gap_label(gap.category);
+    if !ctx.add_gap(Gap { probe: Probe::default(), ..gap }) {
+        bail!("this gap is already recorded");
+    }
+    Ok(Handled { output: json!({ "ok": true, "recorded": label, "gaps": ctx.questions.len() }), transition: None })
+}
+
+#[derive(Deserialize)]
+struct MarkQuestionResult {
+    verdict: Verdict,
+    feedback: String,
+}
+
+fn mark_question_result(ctx: &mut ConversationContext, arguments: &str) -> Result<Handled> {
+    let MarkQuestionResult { verdict, feedback } = serde_json::from_str(arguments)?;
+    if ctx.state != ConversationState::Questioning {
+        bail!("no question is being asked");
+    }
+    let Some(gap) = ctx.current_gap() else {
+        bail!("no question is being asked");
+    };
+    // The backend grades every answer itself; this only fills in when that failed
+    if gap.probe.answers <= gap.probe.attempts {
+        match gap.probe.verdict {
+            Some(verdict) => bail!("the latest answer was already graded: {}", verdict_label(verdict)),
+            None => bail!("the learner has not answered this question yet"),
+        }
+    }
+    let transition = ctx.record_verdict(verdict, feedback);
+    Ok(Handled { output: ok(), transition })
+}
+
+#[derive(Deserialize)]
+struct EndSession {
+    reason: String,
+}
+
+fn end_session(ctx: &mut ConversationContext, arguments: &str) -> Result<Handled> {
+    let EndSession { reason } = serde_json::from_str(arguments)?;
+    info!("Tutor ended the session: {}", reason);
+    Ok(Handled { output: ok(), transition: ctx.end() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declares_every_tool_with_an_object_schema() {
+        let tools = session_tools();
+        let names: Vec<&str> = tools.iter().map(|tool| tool.name.as_str()).collect();
+        assert_eq!(names, ["record_topic", "record_gap", "mark_question_result", "end_session"]);
+        assert!(tools.iter().all(|tool| tool.parameters["type"] == "object"));
+        assert!(!is_session_tool("record_gaps"));
+    }
+
+    #[test]
+    fn calls_are_checked_against_the_current_step() {
+        let mut ctx = ConversationContext::new();
+        ctx.state = ConversationState::WaitingForTopic;
+        let result = dispatch(&mut ctx, "record_topic", r#"{"topic":"Photosynthesis"}"#);
+        assert_eq!(result.output, r#"{"ok":true}"#);
+        assert_eq!(ctx.topic.as_deref(), Some("Photosynthesis"));
+
+        // Too early for gaps, and bad arguments are reported rather than fatal
+        let result =
+            dispatch(&mut ctx, "record_gap", r#"{"category":"vague","excerpt":"","severity":"low","question":"Why?"}"#);
+        assert!(result.output.contains("once the learner has finished explaining"));
+        assert!(dispatch(&mut ctx, "record_topic", "{}").output.contains(r#""ok":false"#));
+
+        ctx.state = ConversationState::Questioning;
+        ctx.questions = vec![Gap::from_question("Where does the oxygen come from?".to_string())];
+        let verdict = r#"{"verdict":"deep","feedback":"Right."}"#;
+        assert!(dispatch(&mut ctx, "mark_question_result", verdict).output.contains("has not answered"));
+        ctx.questions[0].probe.answers = 1;
+        let result = dispatch(&mut ctx, "mark_question_result", verdict);
+        assert_eq!(result.transition, Some(ConversationState::Complete));
+
+        assert_eq!(dispatch(&mut ctx, "end_session", r#"{"reason":"done"}"#).transition, None);
+    }
+}
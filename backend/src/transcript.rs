@@ -0,0 +1,177 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::conversation::ConversationState;
+use crate::protocol::Speaker;
+
+// One finished turn of the conversation, with the protocol step it belonged to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TranscriptEntry {
+    pub at: DateTime<Utc>,
+    pub speaker: Speaker,
+    pub item_id: String,
+    pub text: String,
+    pub state: ConversationState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
+
+// Writes a session's transcript under `<data_dir>/transcripts/`: every entry is
+// appended to `<session_id>.jsonl` as it happens, and `finish` renders the whole
+// session to `<session_id>.md`.
+pub struct TranscriptWriter {
+    session_id: String,
+    user_id: Option<String>,
+    started_at: DateTime<Utc>,
+    dir: PathBuf,
+    jsonl: File,
+    entries: Vec<TranscriptEntry>,
+}
+
+impl TranscriptWriter {
+    // Open the transcript for a session, keeping whatever a previous connection of
+    // the same (resumed) session already wrote.
+    pub async fn create(data_dir: &Path, session_id: &str, user_id: Option<&str>) -> std::io::Result<Self> {
+        let dir = data_dir.join("transcripts");
+        fs::create_dir_all(&dir).await?;
+        let path = dir.join(format!("{session_id}.jsonl"));
+
+        let entries: Vec<TranscriptEntry> = match fs::read_to_string(&path).await {
+            Ok(existing) => existing.lines().filter_map(|line| serde_json::from_str(line).ok()).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let jsonl = OpenOptions::new().create(true).append(true).open(&path).await?;
+        Ok(Self {
+            session_id: session_id.to_string(),
+            user_id: user_id.map(str::to_string),
+            started_at: entries.first().map(|e| e.at).unwrap_or_else(Utc::now),
+            dir,
+            jsonl,
+            entries,
+        })
+    }
+
+    pub async fn append(&mut self, entry: TranscriptEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.jsonl.write_all(line.as_bytes()).await?;
+        self.jsonl.flush().await?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[TranscriptEntry] {
+        &self.entries
+    }
+
+    // Write the Markdown rendering and return its path.
+    pub async fn finish(&mut self) -> std::io::Result<PathBuf> {
+        self.jsonl.flush().await?;
+        let path = self.dir.join(format!("{}.md", self.session_id));
+        fs::write(&path, render_markdown(&self.session_id, self.user_id.as_deref(), self.started_at, &self.entries))
+            .await?;
+        Ok(path)
+    }
+}
+
+pub fn render_markdown(
+    session_id: &str,
+    user_id: Option<&str>,
+    started_at: DateTime<Utc>,
+    entries: &[TranscriptEntry],
+) -> String {
+    let mut out = format!("# Feynman session {session_id}\n\n");
+    if let Some(user_id) = user_id {
+        out.push_str(&format!("- **User:** {user_id}\n"));
+    }
+    let topic = entries.iter().rev().find_map(|e| e.topic.as_deref());
+    out.push_str(&format!("- **Topic:** {}\n", topic.unwrap_or("(not set)")));
+    out.push_str(&format!("- **Started:** {}\n\n", started_at.format("%Y-%m-%d %H:%M:%S UTC")));
+
+    let mut state = None;
+    for entry in entries {
+        if state != Some(&entry.state) {
+            out.push_str(&format!("## {}\n\n", heading(&entry.state)));
+            state = Some(&entry.state);
+        }
+        let speaker = match entry.speaker {
+            Speaker::Learner => "Learner",
+            Speaker::Tutor => "Tutor",
+        };
+        out.push_str(&format!("**{speaker}** ({}): {}\n\n", entry.at.format("%H:%M:%S"), entry.text.trim()));
+    }
+    out
+}
+
+fn heading(state: &ConversationState) -> &'static str {
+    match state {
+        ConversationState::Initial => "Greeting",
+        ConversationState::WaitingForTopic => "Choosing a topic",
+        ConversationState::ReadyToTeach => "Getting ready",
+        ConversationState::Teaching => "Explanation",
+        ConversationState::Analyzing => "Analysis",
+        ConversationState::Questioning => "Questions",
+        ConversationState::Complete => "Wrap-up",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(speaker: Speaker, text: &str, state: ConversationState, topic: Option<&str>) -> TranscriptEntry {
+        TranscriptEntry {
+            at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            speaker,
+            item_id: format!("item_{text}"),
+            text: text.to_string(),
+            state,
+            topic: topic.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_jsonl_and_markdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = TranscriptWriter::create(dir.path(), "abc", Some("alice")).await.unwrap();
+        let entries = [
+            entry(Speaker::Tutor, "What will you teach me?", ConversationState::Initial, None),
+            entry(Speaker::Learner, "Photosynthesis", ConversationState::WaitingForTopic, None),
+            entry(
+                Speaker::Learner,
+                "Plants turn light into sugar.",
+                ConversationState::Teaching,
+                Some("Photosynthesis"),
+            ),
+        ];
+        for e in entries.clone() {
+            writer.append(e).await.unwrap();
+        }
+        let md_path = writer.finish().await.unwrap();
+
+        let jsonl = std::fs::read_to_string(dir.path().join("transcripts/abc.jsonl")).unwrap();
+        let parsed: Vec<TranscriptEntry> = jsonl.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(parsed, entries);
+
+        let md = std::fs::read_to_string(md_path).unwrap();
+        assert!(md.starts_with("# Feynman session abc\n"));
+        assert!(md.contains("**User:** alice"));
+        assert!(md.contains("**Topic:** Photosynthesis"));
+        assert!(md.contains("## Explanation\n\n**Learner** (22:13:20): Plants turn light into sugar."));
+
+        // A resumed session keeps the earlier turns in both files.
+        let mut resumed = TranscriptWriter::create(dir.path(), "abc", Some("alice")).await.unwrap();
+        resumed
+            .append(entry(Speaker::Tutor, "Welcome back!", ConversationState::Teaching, Some("Photosynthesis")))
+            .await
+            .unwrap();
+        let md = std::fs::read_to_string(resumed.finish().await.unwrap()).unwrap();
+        assert!(md.contains("What will you teach me?"));
+        assert!(md.contains("Welcome back!"));
+    }
+}
@@ -1,17 +1,162 @@
-import { useState, useEffect } from "react";
-import { openRelay } from "./services/ws";
+import { useState, useEffect, useRef, useCallback, type FormEvent, type MouseEvent } from "react";
+import { backendUrl, downloadReport, openRelay, parseServerMessage, send, type DailyUsage, type PcmFormat, type ServerMessage, type SessionMode, type Speaker, type TurnMode } from "./services/ws";
+import { openDirect, type DirectSession } from "./services/webrtc";
 import { useMic } from "./hooks/useMic";
-import { playAudio, initializeAudioContext } from "./services/audio";
+import {
+  playAudio,
+  playOpus,
+  initializeAudioContext,
+  startTutorTurn,
+  playedMs,
+  isPlaying,
+  flushPlayback,
+} from "./services/audio";
+
+type Caption = { itemId: string; speaker: Speaker; text: string; final: boolean; startedAt: string };
+
+// How many caption lines stay on screen
+const CAPTION_LINES = 4;
+
+// How often to tell the backend how far tutor playback has got
+const PROGRESS_INTERVAL_MS = 250;
+
+// Open the app with ?mode=text to type instead of talking
+const MODE: SessionMode = new URLSearchParams(window.location.search).get("mode") === "text" ? "text" : "voice";
+
+// ?turn=push_to_talk or ?turn=semantic_vad; otherwise the backend's default
+const TURN_MODES: TurnMode[] = ["server_vad", "semantic_vad", "push_to_talk"];
+const TURN_PARAM = new URLSearchParams(window.location.search).get("turn");
+const TURN_MODE = TURN_MODES.find((mode) => mode === TURN_PARAM);
+
+// ?record=yes agrees to the session's audio being recorded for coaching review
+const RECORD = new URLSearchParams(window.location.search).get("record") === "yes";
+
+// ?transport=webrtc talks to OpenAI directly, falling back to the relay if the
+// backend cannot set that up
+const DIRECT = MODE === "voice" && new URLSearchParams(window.location.search).get("transport") === "webrtc";
+
+// e.g. "42s of 600s speaking, 1200 tokens"
+function describeUsage(used: DailyUsage, limits: DailyUsage): string {
+  const part = (value: number, limit: number, unit: string) =>
+    limit > 0 ? `${Math.round(value)}${unit} of ${limit}${unit}` : `${Math.round(value)}${unit}`;
+  return [
+    `${part(used.learner_audio_seconds, limits.learner_audio_seconds, "s")} speaking`,
+    `${part(used.tutor_audio_seconds, limits.tutor_audio_seconds, "s")} listening`,
+    `${part(used.tokens, limits.tokens, "")} tokens`,
+  ].join(", ");
+}
 
 export default function App() {
-  const [ws] = useState(() => openRelay());
+  const [ws, setWs] = useState<WebSocket | null>(() => (DIRECT ? null : openRelay(MODE, TURN_MODE, RECORD)));
+  const direct = useRef<DirectSession | null>(null);
   const [running, setRunning] = useState(false);
   const [connectionStatus, setConnectionStatus] = useState("Connecting...");
   const [lastMessage, setLastMessage] = useState("");
+  const [tutorState, setTutorState] = useState("");
+  const [teaching, setTeaching] = useState(false);
+  const [recording, setRecording] = useState(false);
+  const [usage, setUsage] = useState("");
+  const [inputAudio, setInputAudio] = useState<PcmFormat | null>(null);
+  const [outputAudio, setOutputAudio] = useState<PcmFormat | null>(null);
+  const [reportPath, setReportPath] = useState("");
+  const [captions, setCaptions] = useState<Caption[]>([]);
+  const [draft, setDraft] = useState("");
+  const [turnMode, setTurnMode] = useState<TurnMode>("server_vad");
+  const tutorItem = useRef<string | null>(null);
+
+  useMic(ws, running && MODE === "voice", inputAudio?.encoding);
+
+  const handleMessage = useCallback((message: ServerMessage) => {
+    switch (message.type) {
+      case "welcome":
+        setInputAudio(message.input_audio);
+        setOutputAudio(message.output_audio);
+        setTurnMode(message.turn_mode);
+        setRecording(message.recording);
+        break;
+      case "status":
+        if (message.status === "ready") {
+          setConnectionStatus("Connected to OpenAI - Ready to start");
+        } else if (message.status === "test_mode") {
+          setConnectionStatus("Test Mode - Ready to start");
+        } else if (message.status === "queued") {
+          setConnectionStatus(`Waiting for a free spot - you are number ${message.position ?? "?"} in line`);
+        } else if (message.status === "reconnecting") {
+          setConnectionStatus(`Reconnecting to OpenAI (${message.detail ?? "..."})`);
+        } else {
+          setConnectionStatus("OpenAI connection failed");
+        }
+        break;
+      case "error":
+        setLastMessage(`Error (${message.code}): ${message.message}`);
+        break;
+      case "transcript":
+        setLastMessage(`${message.speaker}: ${message.text}`);
+        break;
+      case "state":
+        setTutorState(message.topic ? `${message.state} (${message.topic})` : message.state);
+        setTeaching(message.state === "teaching");
+        break;
+      case "caption": {
+        const caption: Caption = {
+          itemId: message.item_id,
+          speaker: message.speaker,
+          text: message.text,
+          final: message.final,
+          startedAt: message.started_at,
+        };
+        setCaptions((lines) => {
+          const rest = lines.filter((line) => line.itemId !== caption.itemId);
+          const next = caption.text ? [...rest, caption] : rest;
+          next.sort((a, b) => a.startedAt.localeCompare(b.startedAt));
+          return next.slice(-CAPTION_LINES);
+        });
+        break;
+      }
+      case "tutor_audio":
+        tutorItem.current = message.item_id;
+        startTutorTurn();
+        break;
+      case "flush_audio":
+        flushPlayback();
+        break;
+      case "report":
+        setReportPath(message.url);
+        break;
+      case "usage":
+        setUsage(describeUsage(message.used, message.limits));
+        break;
+    }
+  }, []);
+
+  useEffect(() => {
+    if (!DIRECT) return;
+    let cancelled = false;
+    openDirect(TURN_MODE, handleMessage).then((session) => {
+      if (cancelled) {
+        session?.close();
+      } else if (session) {
+        direct.current = session;
+        setTurnMode(session.turnMode);
+        setConnectionStatus("Connected to OpenAI directly - Ready to start");
+      } else {
+        setWs(openRelay(MODE, TURN_MODE, RECORD));
+      }
+    });
+    return () => {
+      cancelled = true;
+      direct.current?.close();
+      direct.current = null;
+    };
+  }, [handleMessage]);
 
-  useMic(ws, running);
+  // In a direct session the mic goes to OpenAI, and is only heard while running
+  useEffect(() => {
+    direct.current?.setMicEnabled(running);
+  }, [running]);
 
   useEffect(() => {
+    if (!ws) return;
     ws.onopen = () => {
       setConnectionStatus("Connected to server");
     };
@@ -26,29 +171,52 @@ export default function App() {
 
     ws.onmessage = async (e) => {
       if (typeof e.data === "string") {
-        setLastMessage(e.data);
-        console.log("Received text message:", e.data);
-        
-        // Update connection status based on message content
-        if (e.data.includes("Connected to OpenAI")) {
-          setConnectionStatus("Connected to OpenAI - Ready to start");
-        } else if (e.data.includes("OpenAI connection failed")) {
-          setConnectionStatus("OpenAI connection failed");
-        } else if (e.data.includes("TEST_MODE")) {
-          setConnectionStatus("Test Mode - Ready to start");
-        }
+        const message = parseServerMessage(e.data);
+        console.log("Received message:", message);
+        if (!message) return;
+
+        handleMessage(message);
       } else {
         // Handle binary audio data
         console.log("Received audio data:", e.data.byteLength, "bytes");
         try {
-          await playAudio(e.data);
+          if (outputAudio?.encoding === "opus") {
+            await playOpus(e.data, outputAudio.sample_rate);
+          } else {
+            await playAudio(e.data, outputAudio?.sample_rate);
+          }
         } catch (error) {
           console.error("Failed to play audio:", error);
         }
       }
     };
+  }, [ws, outputAudio, handleMessage]);
+
+  // Report playback progress so an interruption cuts the tutor off where we are
+  useEffect(() => {
+    const timer = setInterval(() => {
+      const itemId = tutorItem.current;
+      if (itemId && isPlaying() && ws?.readyState === WebSocket.OPEN) {
+        send(ws, { type: "playback_progress", item_id: itemId, played_ms: playedMs() });
+      }
+    }, PROGRESS_INTERVAL_MS);
+    return () => clearInterval(timer);
   }, [ws]);
 
+  // The report needs the learner's token, which the link alone cannot send
+  const handleDownloadReport = (e: MouseEvent) => {
+    e.preventDefault();
+    downloadReport(reportPath).catch((error) => console.error("Failed to download the report:", error));
+  };
+
+  const handleSendText = (e: FormEvent) => {
+    e.preventDefault();
+    const text = draft.trim();
+    if (!text || !ws) return;
+    send(ws, { type: "text", text });
+    setDraft("");
+  };
+
   const handleStartStop = async () => {
     if (!running && connectionStatus.includes("Ready to start")) {
       try {
@@ -61,8 +229,12 @@ export default function App() {
         setConnectionStatus("Audio initialization failed - check browser permissions");
       }
     } else if (running) {
-      // Send final commit when stopping
-      ws.send("commit_audio");
+      // Send final commit when stopping; in push-to-talk this ends the learner's turn
+      if (direct.current) {
+        if (turnMode === "push_to_talk") direct.current.commit();
+      } else if (ws) {
+        send(ws, { type: "commit_audio" });
+      }
       setRunning(false);
     }
   };
@@ -72,12 +244,36 @@ export default function App() {
       <h1>Feynman Tutor</h1>
       <div style={{ marginBottom: 20 }}>
         <p>Status: {connectionStatus}</p>
+        {tutorState && <p>Tutor step: {tutorState}</p>}
+        {recording && <p>This session is being recorded.</p>}
+        {usage && <p>Used today: {usage}</p>}
+        {reportPath && (
+          <p>
+            <a href={backendUrl(reportPath)} onClick={handleDownloadReport}>Download your gap report</a>
+          </p>
+        )}
         {lastMessage && (
           <p style={{ fontSize: 12, color: "#666", marginTop: 10 }}>
             Last message: {lastMessage}
           </p>
         )}
       </div>
+      {MODE === "text" ? (
+        <form onSubmit={handleSendText}>
+          <textarea
+            value={draft}
+            onChange={(e) => setDraft(e.target.value)}
+            disabled={!connectionStatus.includes("Ready to start")}
+            rows={4}
+            style={{ width: "100%", maxWidth: 600 }}
+            placeholder="Type your explanation..."
+          />
+          <br />
+          <button type="submit" disabled={!connectionStatus.includes("Ready to start") || !draft.trim()}>
+            Send
+          </button>
+        </form>
+      ) : (
       <button 
         onClick={handleStartStop}
         disabled={!connectionStatus.includes("Ready to start") && !running}
@@ -91,13 +287,30 @@ export default function App() {
           cursor: connectionStatus.includes("Ready to start") || running ? "pointer" : "not-allowed"
         }}
       >
-        {running ? "Stop Teaching" : "Start Teaching"}
+        {turnMode === "push_to_talk"
+          ? (running ? "Done Talking" : "Talk")
+          : (running ? "Stop Teaching" : "Start Teaching")}
       </button>
+      )}
+      {teaching && (
+        <p>
+          <button onClick={() => (direct.current ? direct.current.doneTeaching() : ws && send(ws, { type: "done_teaching" }))}>I'm done explaining</button>
+        </p>
+      )}
       {running && (
         <p style={{ marginTop: 20, color: "#666" }}>
-          Speak now... AI is listening to your teaching.
+          {turnMode === "push_to_talk"
+            ? "Speak now... press Done Talking when you have finished."
+            : "Speak now... AI is listening to your teaching."}
         </p>
       )}
+      <section aria-live="polite" aria-label="Live captions" style={{ maxWidth: 600, margin: "20px auto", textAlign: "left" }}>
+        {captions.map((caption) => (
+          <p key={caption.itemId} style={{ margin: "4px 0", color: caption.final ? "#000" : "#666" }}>
+            <strong>{caption.speaker === "tutor" ? "Tutor" : "You"}:</strong> {caption.text}
+          </p>
+        ))}
+      </section>
     </main>
   );
 }
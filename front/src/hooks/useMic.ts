@@ -1,11 +1,14 @@
 import { useEffect, useRef} from "react";
+import { MIC_SAMPLE_RATE, type AudioEncoding } from "../services/ws";
 
-export function useMic(ws: WebSocket, running: boolean) {
+// `encoding` is what the backend's welcome asked the mic audio to be sent as
+export function useMic(ws: WebSocket | null, running: boolean, encoding: AudioEncoding = "pcm16") {
   const ctxRef = useRef<AudioContext | null>(null);
   const srcRef = useRef<MediaStreamAudioSourceNode | null>(null);
+  const encoderRef = useRef<AudioEncoder | null>(null);
 
   useEffect(() => {
-    if (!running) return;
+    if (!running || !ws) return;
 
     let cancelled = false;
 
@@ -16,14 +19,43 @@ export function useMic(ws: WebSocket, running: boolean) {
         if (cancelled) return;
 
         // Only create AudioContext after permission is granted
-        const ctx = new AudioContext({ sampleRate: 48000 });
+        const ctx = new AudioContext({ sampleRate: MIC_SAMPLE_RATE });
         ctxRef.current = ctx;
         srcRef.current = ctx.createMediaStreamSource(stream);
 
+        // Opus packets go out one per message as the encoder finishes them
+        let encoder: AudioEncoder | null = null;
+        let timestamp = 0;
+        if (encoding === "opus") {
+          encoder = new AudioEncoder({
+            output: (chunk) => {
+              if (ws.readyState !== WebSocket.OPEN) return;
+              const packet = new ArrayBuffer(chunk.byteLength);
+              chunk.copyTo(packet);
+              ws.send(packet);
+            },
+            error: (err) => console.error("Opus encoder failed:", err),
+          });
+          encoder.configure({ codec: "opus", sampleRate: MIC_SAMPLE_RATE, numberOfChannels: 1, bitrate: 24000 });
+          encoderRef.current = encoder;
+        }
+
         const processor = ctx.createScriptProcessor(4096, 1, 1);
         processor.onaudioprocess = e => {
           if (!running || ws.readyState !== WebSocket.OPEN) return;
           const pcm = e.inputBuffer.getChannelData(0);
+          if (encoder) {
+            encoder.encode(new AudioData({
+              format: "f32",
+              sampleRate: MIC_SAMPLE_RATE,
+              numberOfFrames: pcm.length,
+              numberOfChannels: 1,
+              timestamp,
+              data: pcm,
+            }));
+            timestamp += (pcm.length * 1_000_000) / MIC_SAMPLE_RATE;
+            return;
+          }
           const buf = new ArrayBuffer(pcm.length * 2);
           const view = new DataView(buf);
           for (let i = 0; i < pcm.length; i++) {
@@ -43,6 +75,8 @@ export function useMic(ws: WebSocket, running: boolean) {
     return () => {
       cancelled = true;
       ctxRef.current?.close();
+      if (encoderRef.current?.state === "configured") encoderRef.current.close();
+      encoderRef.current = null;
     };
-  }, [running, ws]);
+  }, [running, ws, encoding]);
 }
\ No newline at end of file
@@ -1,6 +1,16 @@
 // Global audio context instance to avoid recreating
 let audioContext: AudioContext | null = null;
 
+// Tutor audio is scheduled back to back; these track the queue so it can be
+// flushed when the learner interrupts, and how much of the current turn has played
+let nextStartTime = 0;
+let queued: AudioBufferSourceNode[] = [];
+let turnStartedAt: number | null = null;
+
+// Decodes Opus tutor audio; recreated after a flush so nothing stale comes out of it
+let opusDecoder: AudioDecoder | null = null;
+let opusTimestamp = 0;
+
 export async function initializeAudioContext(): Promise<AudioContext> {
   if (!audioContext) {
     audioContext = new (window.AudioContext || (window as any).webkitAudioContext)();
@@ -16,7 +26,7 @@ export async function initializeAudioContext(): Promise<AudioContext> {
   return audioContext;
 }
 
-export async function playAudio(data: ArrayBuffer) {
+export async function playAudio(data: ArrayBuffer, sampleRate = 24000) {
   try {
     console.log(`Attempting to play audio: ${data.byteLength} bytes`);
     
@@ -26,7 +36,7 @@ export async function playAudio(data: ArrayBuffer) {
     // The data is raw PCM16 from OpenAI, not encoded audio
     // We need to create an AudioBuffer from the raw PCM data
     const pcmData = new Int16Array(data);
-    const sampleRate = 16000; // OpenAI uses 16kHz sample rate for PCM16
+    // sampleRate comes from the backend's welcome; OpenAI's pcm16 is 24kHz
     const channels = 1; // Mono audio
     
     console.log(`Audio details: ${pcmData.length} samples, ${sampleRate}Hz, ${channels} channel(s)`);
@@ -36,31 +46,93 @@ export async function playAudio(data: ArrayBuffer) {
       return;
     }
     
-    // Create an AudioBuffer
-    const audioBuffer = ctx.createBuffer(channels, pcmData.length, sampleRate);
-    const channelData = audioBuffer.getChannelData(0);
-    
     // Convert PCM16 to float32 for Web Audio API
+    const samples = new Float32Array(pcmData.length);
     for (let i = 0; i < pcmData.length; i++) {
-      channelData[i] = pcmData[i] / 32768.0; // Convert from int16 to float32 range [-1, 1]
+      samples[i] = pcmData[i] / 32768.0; // Convert from int16 to float32 range [-1, 1]
     }
-    
-    // Create source and play the audio
-    const source = ctx.createBufferSource();
-    source.buffer = audioBuffer;
-    source.connect(ctx.destination);
-    
-    // Add event listeners for debugging
-    source.onended = () => {
-      console.log('Audio playback ended');
-    };
-    
-    console.log(`AudioContext state before playing: ${ctx.state}`);
-    source.start();
-    console.log(`Audio playback started: ${pcmData.length} samples at ${sampleRate}Hz, duration: ${audioBuffer.duration.toFixed(2)}s`);
+    schedule(ctx, samples, sampleRate);
     
   } catch (error) {
     console.error("Error playing audio:", error);
     console.error("AudioContext state:", audioContext?.state);
   }
 }
+
+// One Opus packet of tutor audio; it plays once decoded
+export async function playOpus(data: ArrayBuffer, sampleRate = 24000) {
+  try {
+    const ctx = await initializeAudioContext();
+    if (!opusDecoder) {
+      opusDecoder = new AudioDecoder({
+        output: (frame) => {
+          const samples = new Float32Array(frame.numberOfFrames);
+          frame.copyTo(samples, { planeIndex: 0, format: "f32-planar" });
+          frame.close();
+          schedule(ctx, samples, sampleRate);
+        },
+        error: (err) => console.error("Opus decoder failed:", err),
+      });
+      opusDecoder.configure({ codec: "opus", sampleRate, numberOfChannels: 1 });
+      opusTimestamp = 0;
+    }
+    opusDecoder.decode(new EncodedAudioChunk({ type: "key", timestamp: opusTimestamp, data }));
+    // The backend sends 20 ms packets
+    opusTimestamp += 20_000;
+  } catch (error) {
+    console.error("Error playing audio:", error);
+  }
+}
+
+// Queue mono samples after whatever is still playing
+function schedule(ctx: AudioContext, samples: Float32Array, sampleRate: number) {
+  const audioBuffer = ctx.createBuffer(1, samples.length, sampleRate);
+  audioBuffer.getChannelData(0).set(samples);
+
+  const source = ctx.createBufferSource();
+  source.buffer = audioBuffer;
+  source.connect(ctx.destination);
+  
+  source.onended = () => {
+    queued = queued.filter((s) => s !== source);
+  };
+  
+  console.log(`AudioContext state before playing: ${ctx.state}`);
+  const startAt = Math.max(ctx.currentTime, nextStartTime);
+  if (turnStartedAt === null) turnStartedAt = startAt;
+  nextStartTime = startAt + audioBuffer.duration;
+  queued.push(source);
+  source.start(startAt);
+  console.log(`Audio playback started: ${samples.length} samples at ${sampleRate}Hz, duration: ${audioBuffer.duration.toFixed(2)}s`);
+}
+
+// The frames that follow belong to a new tutor turn
+export function startTutorTurn() {
+  turnStartedAt = null;
+}
+
+// Milliseconds of the current tutor turn played so far
+export function playedMs(): number {
+  if (!audioContext || turnStartedAt === null) return 0;
+  const now = Math.min(audioContext.currentTime, nextStartTime);
+  return Math.max(0, Math.round((now - turnStartedAt) * 1000));
+}
+
+export function isPlaying(): boolean {
+  return !!audioContext && audioContext.currentTime < nextStartTime;
+}
+
+// Stop the tutor mid-sentence and drop everything still queued
+export function flushPlayback() {
+  for (const source of queued) {
+    try {
+      source.stop();
+    } catch {
+      // Already stopped
+    }
+  }
+  queued = [];
+  nextStartTime = 0;
+  if (opusDecoder?.state === "configured") opusDecoder.close();
+  opusDecoder = null;
+}
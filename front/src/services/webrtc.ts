@@ -0,0 +1,152 @@
+// Direct sessions: the browser talks to OpenAI over WebRTC instead of through the
+// relay. The backend mints the session and runs the tutor's state machine on the
+// events we post back, answering with events to send and messages to show.
+import { BACKEND_HOST, SESSION_KEY, authToken, type ServerMessage, type TurnMode } from "./ws";
+
+// The events the backend's state machine needs; the rest stay here
+const FORWARDED_EVENTS = new Set([
+  "input_audio_buffer.committed",
+  "conversation.item.input_audio_transcription.completed",
+  "conversation.item.input_audio_transcription.failed",
+  "response.function_call_arguments.done",
+  "response.done",
+  "error",
+]);
+
+type Started = {
+  session_id: string;
+  resumed: boolean;
+  turn_mode: TurnMode;
+  client_secret: { value: string; expires_at: number };
+  url: string;
+  events: unknown[];
+  messages: ServerMessage[];
+};
+
+type Reply = { events: unknown[]; messages: ServerMessage[]; ended: boolean };
+
+export type DirectSession = {
+  turnMode: TurnMode;
+  // The mic is connected from the start but only heard while enabled
+  setMicEnabled(enabled: boolean): void;
+  // Ends the learner's turn in push-to-talk
+  commit(): void;
+  doneTeaching(): void;
+  close(): void;
+};
+
+function headers(): Record<string, string> {
+  const token = authToken();
+  return { "Content-Type": "application/json", ...(token ? { Authorization: `Bearer ${token}` } : {}) };
+}
+
+// Null if the backend cannot start one (it is not in front of OpenAI, or it is
+// full) or the connection fails, so the caller can fall back to the relay
+export async function openDirect(
+  turnMode: TurnMode | undefined,
+  onMessage: (message: ServerMessage) => void,
+): Promise<DirectSession | null> {
+  const sessionsUrl = `http://${BACKEND_HOST}/realtime/sessions`;
+  let started: Started;
+  try {
+    const response = await fetch(sessionsUrl, {
+      method: "POST",
+      headers: headers(),
+      body: JSON.stringify({ session_id: localStorage.getItem(SESSION_KEY) ?? undefined, turn_mode: turnMode }),
+    });
+    if (!response.ok) {
+      console.warn("Backend declined a direct session:", response.status);
+      return null;
+    }
+    started = await response.json();
+  } catch (error) {
+    console.warn("Direct session unavailable:", error);
+    return null;
+  }
+  localStorage.setItem(SESSION_KEY, started.session_id);
+  const sessionUrl = `${sessionsUrl}/${started.session_id}`;
+
+  const pc = new RTCPeerConnection();
+  const channel = pc.createDataChannel("oai-events");
+  let mic: MediaStream | null = null;
+  let closed = false;
+  // Posts go one at a time so the backend sees events in the order they came
+  let posting = Promise.resolve();
+
+  function close() {
+    if (closed) return;
+    closed = true;
+    channel.close();
+    pc.close();
+    mic?.getTracks().forEach((track) => track.stop());
+    fetch(sessionUrl, { method: "DELETE", headers: headers() }).catch(() => {});
+  }
+
+  function sendEvents(events: unknown[]) {
+    for (const event of events) {
+      if (channel.readyState === "open") channel.send(JSON.stringify(event));
+    }
+  }
+
+  function post(body: { events?: unknown[]; done_teaching?: boolean }) {
+    posting = posting.then(async () => {
+      if (closed) return;
+      try {
+        const response = await fetch(`${sessionUrl}/events`, { method: "POST", headers: headers(), body: JSON.stringify(body) });
+        if (!response.ok) {
+          console.error("Backend refused posted events:", response.status);
+          return;
+        }
+        const reply: Reply = await response.json();
+        sendEvents(reply.events);
+        reply.messages.forEach(onMessage);
+        if (reply.ended) close();
+      } catch (error) {
+        console.error("Failed to post events to the backend:", error);
+      }
+    });
+  }
+
+  try {
+    const speaker = new Audio();
+    speaker.autoplay = true;
+    pc.ontrack = (e) => {
+      speaker.srcObject = e.streams[0];
+    };
+    mic = await navigator.mediaDevices.getUserMedia({ audio: true });
+    const [track] = mic.getAudioTracks();
+    track.enabled = false;
+    pc.addTrack(track, mic);
+
+    channel.onopen = () => sendEvents(started.events);
+    channel.onmessage = (e) => {
+      const event = JSON.parse(e.data);
+      if (FORWARDED_EVENTS.has(event.type)) post({ events: [event] });
+    };
+
+    const offer = await pc.createOffer();
+    await pc.setLocalDescription(offer);
+    const answer = await fetch(started.url, {
+      method: "POST",
+      headers: { Authorization: `Bearer ${started.client_secret.value}`, "Content-Type": "application/sdp" },
+      body: offer.sdp,
+    });
+    if (!answer.ok) throw new Error(`OpenAI answered ${answer.status}`);
+    await pc.setRemoteDescription({ type: "answer", sdp: await answer.text() });
+    started.messages.forEach(onMessage);
+
+    return {
+      turnMode: started.turn_mode,
+      setMicEnabled: (enabled) => {
+        track.enabled = enabled;
+      },
+      commit: () => sendEvents([{ type: "input_audio_buffer.commit" }]),
+      doneTeaching: () => post({ done_teaching: true }),
+      close,
+    };
+  } catch (error) {
+    console.warn("Failed to connect to OpenAI directly:", error);
+    close();
+    return null;
+  }
+}
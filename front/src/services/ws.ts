@@ -1,8 +1,146 @@
+// Browser <-> backend protocol, mirrors backend/src/protocol.rs
+export const PROTOCOL_VERSION = 1;
 
-export function openRelay(): WebSocket{
-    const ws = new WebSocket("ws://localhost:3000/ws");
+// "opus" is one Opus packet per binary frame
+export type AudioEncoding = "pcm16" | "f32" | "opus";
+export type PcmFormat = { sample_rate: number; channels: number; encoding: AudioEncoding };
+
+// useMic captures mono audio at this rate; the backend resamples it
+export const MIC_SAMPLE_RATE = 48000;
+
+// Opus needs WebCodecs; the backend may still answer with PCM16 if it has no Opus
+export const OPUS_SUPPORTED = typeof AudioEncoder !== "undefined" && typeof AudioDecoder !== "undefined";
+
+// Text sessions exchange typed turns instead of audio
+export type SessionMode = "voice" | "text";
+
+// How a voice turn ends: the server hears a pause, or the learner says when
+export type TurnMode = "server_vad" | "semantic_vad" | "push_to_talk";
+
+export type ClientMessage =
+  | { type: "hello"; version: number; input_audio?: PcmFormat; output_encoding?: AudioEncoding; session_id?: string; mode?: SessionMode; turn_mode?: TurnMode; record?: boolean; token?: string }
+  | { type: "commit_audio" }
+  | { type: "text"; text: string }
+  // The learner has finished explaining; until then pauses do not end it
+  | { type: "done_teaching" }
+  | { type: "playback_progress"; item_id: string; played_ms: number }
+  | { type: "retry_upstream" };
+
+// "queued" arrives before the welcome when the server is full, with our place in line
+export type SessionStatus = "ready" | "upstream_unavailable" | "reconnecting" | "test_mode" | "queued";
+
+export type ConversationState =
+  | "initial"
+  | "waiting_for_topic"
+  | "ready_to_teach"
+  | "teaching"
+  | "analyzing"
+  | "questioning"
+  | "complete";
+
+export type Speaker = "learner" | "tutor";
+
+// Today's usage, and the daily limits it counts against (0 is unlimited)
+export type DailyUsage = { learner_audio_seconds: number; tutor_audio_seconds: number; tokens: number };
+
+export type ServerMessage =
+  | {
+      type: "welcome";
+      version: number;
+      session_id: string;
+      resumed: boolean;
+      input_audio: PcmFormat;
+      output_audio: PcmFormat;
+      mode: SessionMode;
+      turn_mode: TurnMode;
+      recording: boolean;
+    }
+  | { type: "status"; status: SessionStatus; detail?: string; position?: number }
+  | { type: "error"; code: string; message: string }
+  | { type: "transcript"; speaker: Speaker; item_id: string; text: string }
+  | {
+      type: "caption";
+      speaker: Speaker;
+      item_id: string;
+      // Text of the turn so far; the final caption holds the finished transcript
+      text: string;
+      final: boolean;
+      started_at: string;
+      updated_at: string;
+    }
+  | { type: "state"; state: ConversationState; topic?: string }
+  | { type: "report"; url: string }
+  | { type: "tutor_audio"; item_id: string }
+  | { type: "flush_audio"; item_id: string }
+  | { type: "usage"; used: DailyUsage; limits: DailyUsage };
+
+export const SESSION_KEY = "feynman.session_id";
+const TOKEN_KEY = "feynman.token";
+export const BACKEND_HOST = "localhost:3000";
+
+// The token the backend issued for this learner: from ?token= the first time,
+// remembered after that
+export function authToken(): string | undefined {
+    const fromUrl = new URLSearchParams(window.location.search).get("token");
+    if (fromUrl) localStorage.setItem(TOKEN_KEY, fromUrl);
+    return fromUrl ?? localStorage.getItem(TOKEN_KEY) ?? undefined;
+}
+
+// Absolute URL for a path the backend hands out, e.g. a report download.
+export function backendUrl(path: string): string {
+    return `http://${BACKEND_HOST}${path}`;
+}
+
+// Save the gap report at `path`. The backend wants the token in the
+// Authorization header, which a plain download link cannot send, so the report
+// is fetched and handed to the browser as a file.
+export async function downloadReport(path: string): Promise<void> {
+    const token = authToken();
+    const response = await fetch(backendUrl(path), { headers: token ? { Authorization: `Bearer ${token}` } : {} });
+    if (!response.ok) throw new Error(`report download failed: ${response.status}`);
+    const url = URL.createObjectURL(await response.blob());
+    const link = document.createElement("a");
+    link.href = url;
+    link.download = "feynman-report.md";
+    link.click();
+    // Let the download start before the file goes
+    setTimeout(() => URL.revokeObjectURL(url), 0);
+}
+
+// `record` is the learner's consent to the session's audio being kept
+export function openRelay(mode: SessionMode = "voice", turnMode?: TurnMode, record = false): WebSocket{
+    const ws = new WebSocket(`ws://${BACKEND_HOST}/ws`);
     ws.binaryType = "arraybuffer";
+    ws.addEventListener("open", () => send(ws, {
+        type: "hello",
+        version: PROTOCOL_VERSION,
+        input_audio: { sample_rate: MIC_SAMPLE_RATE, channels: 1, encoding: OPUS_SUPPORTED ? "opus" : "pcm16" },
+        output_encoding: OPUS_SUPPORTED ? "opus" : undefined,
+        session_id: localStorage.getItem(SESSION_KEY) ?? undefined,
+        mode,
+        turn_mode: turnMode,
+        record,
+        token: authToken(),
+    }));
+    // Remember the session so a reconnect resumes it instead of starting over
+    ws.addEventListener("message", (e) => {
+        if (typeof e.data !== "string") return;
+        const message = parseServerMessage(e.data);
+        if (message?.type === "welcome") localStorage.setItem(SESSION_KEY, message.session_id);
+    });
     return ws;
 }
 //create a websocket connection to the backend
 
+export function send(ws: WebSocket, message: ClientMessage) {
+    ws.send(JSON.stringify(message));
+}
+
+export function parseServerMessage(data: string): ServerMessage | null {
+    try {
+        return JSON.parse(data) as ServerMessage;
+    } catch {
+        console.error("Unparseable message from backend:", data);
+        return null;
+    }
+}